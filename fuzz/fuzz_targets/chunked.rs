@@ -0,0 +1,17 @@
+//! Feeds arbitrary bytes through the chunked HTTP body decoder, which must
+//! reject malformed bodies without panicking, and must decode a complete body
+//! to the length it reported for it.
+
+#![no_main]
+
+#[path = "../../uefi-bootloader/src/chunked.rs"]
+mod chunked;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(Some(len)) = chunked::decoded_len(data) {
+        let mut bytes = data.to_vec();
+        assert_eq!(chunked::decode_in_place(&mut bytes).ok(), Some(len));
+    }
+});
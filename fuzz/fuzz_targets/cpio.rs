@@ -0,0 +1,15 @@
+//! Feeds arbitrary bytes through the module archive parser, which must reject
+//! malformed archives without panicking or slicing out of bounds.
+
+#![no_main]
+
+#[path = "../../uefi-bootloader/src/cpio.rs"]
+mod cpio;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for entry in cpio::entries(data).flatten() {
+        let _ = (entry.name, entry.data, entry.is_file());
+    }
+});
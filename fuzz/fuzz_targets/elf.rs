@@ -0,0 +1,51 @@
+//! Feeds arbitrary bytes through the same header validation as the loader, and
+//! then slices out everything the loader would read based on the validated
+//! headers. Any out-of-bounds slice is a validation bug.
+
+#![no_main]
+
+#[path = "../../uefi-bootloader/src/elf.rs"]
+mod elf;
+
+use goblin::elf64::{
+    program_header::{PT_LOAD, PT_NOTE, SIZEOF_PHDR},
+    section_header::{SHT_NOBITS, SIZEOF_SHDR},
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let file_size = data.len() as u64;
+    let Ok(header) = elf::parse_header(data, file_size) else {
+        return;
+    };
+
+    let phdrs_start = header.e_phoff as usize;
+    let phdrs = &data[phdrs_start..phdrs_start + usize::from(header.e_phnum) * SIZEOF_PHDR];
+    for bytes in phdrs.chunks_exact(SIZEOF_PHDR) {
+        let Ok(program_header) = elf::parse_program_header(bytes, file_size) else {
+            continue;
+        };
+        let start = program_header.p_offset as usize;
+        let contents = &data[start..start + program_header.p_filesz as usize];
+        match program_header.p_type {
+            PT_LOAD => {
+                assert!(program_header.p_filesz <= program_header.p_memsz);
+                assert!(program_header.p_align <= 1 || program_header.p_align.is_power_of_two());
+            }
+            PT_NOTE => elf::notes(contents).for_each(drop),
+            _ => {}
+        }
+    }
+
+    let shdrs_start = header.e_shoff as usize;
+    let shdrs = &data[shdrs_start..shdrs_start + usize::from(header.e_shnum) * SIZEOF_SHDR];
+    for bytes in shdrs.chunks_exact(SIZEOF_SHDR) {
+        let Ok(section_header) = elf::parse_section_header(bytes, file_size) else {
+            continue;
+        };
+        if section_header.sh_type != SHT_NOBITS {
+            let start = section_header.sh_offset as usize;
+            let _ = &data[start..start + section_header.sh_size as usize];
+        }
+    }
+});
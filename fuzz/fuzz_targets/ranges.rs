@@ -0,0 +1,95 @@
+//! Checks invariants of the page range algebra, which the page allocator,
+//! mappings, and memory map all rely on, for arbitrary ranges near an
+//! arbitrary base page.
+//!
+//! Ranges whose start is after their end are empty, like `PageRange::empty`.
+
+#![feature(step_trait)]
+#![no_main]
+
+#[path = "../../uefi-bootloader/src/memory/structs.rs"]
+#[allow(dead_code)]
+mod structs;
+
+/// The x86_64 address canonicalisation, which the bootloader gets from its
+/// `arch` module.
+mod imp {
+    pub(crate) fn is_canonical_virtual_address(virt_addr: usize) -> bool {
+        matches!(virt_addr >> 47, 0 | 0x1_ffff)
+    }
+
+    pub(crate) const fn canonicalize_virtual_address(virt_addr: usize) -> usize {
+        ((virt_addr << 16) as isize >> 16) as usize
+    }
+
+    pub(crate) fn is_canonical_physical_address(phys_addr: usize) -> bool {
+        phys_addr >> 52 == 0
+    }
+
+    pub(crate) const fn canonicalize_physical_address(phys_addr: usize) -> usize {
+        phys_addr & 0x000f_ffff_ffff_ffff
+    }
+}
+
+use libfuzzer_sys::fuzz_target;
+use structs::{Page, PageRange};
+use uefi_bootloader_api::PAGE_SIZE;
+
+/// The number of pages in the lower half of the address space, which keeps
+/// every page's address canonical.
+const LOWER_HALF_PAGES: usize = (1 << 47) / PAGE_SIZE;
+
+fuzz_target!(|input: (usize, [u8; 5], u16)| {
+    let (base, [a_start, a_end, b_start, b_end, page_index], offset) = input;
+    let base = base % (LOWER_HALF_PAGES - usize::from(u8::MAX));
+    let nth_page = |index: u8| Page {
+        number: base + usize::from(index),
+    };
+    let a = PageRange::new(nth_page(a_start), nth_page(a_end));
+    let b = PageRange::new(nth_page(b_start), nth_page(b_end));
+    let page = nth_page(page_index);
+
+    // Empty ranges yield nothing and overlap nothing.
+    for range in [&a, &b, &PageRange::empty()] {
+        assert_eq!(range.is_empty(), range.size_in_pages() == 0);
+        assert_eq!(range.clone().into_iter().count(), range.size_in_pages());
+    }
+    assert!(PageRange::empty().overlap(&a).is_none());
+    assert!(a.overlap(&PageRange::empty()).is_none());
+
+    // The overlap is commutative, and is exactly the pages in both ranges.
+    let overlap = a.overlap(&b);
+    assert_eq!(overlap, b.overlap(&a));
+    match &overlap {
+        Some(overlap) => {
+            assert!(!overlap.is_empty());
+            for page in overlap.clone() {
+                assert!(a.contains(&page) && b.contains(&page));
+            }
+        }
+        None => assert!(a.clone().into_iter().all(|page| !b.contains(&page))),
+    }
+    assert_eq!(a.overlap(&a).is_some(), !a.is_empty());
+
+    // Extending a range adds the page and keeps every page it had.
+    let extended = a.to_extended(page);
+    assert!(extended.contains(&page));
+    assert!(a.clone().into_iter().all(|page| extended.contains(&page)));
+    if a.is_empty() {
+        assert_eq!(extended, PageRange::new(page, page));
+    }
+
+    // Addresses are in a range if and only if their page is, and their offset
+    // is relative to the start of the range.
+    let address = page.start_address() + usize::from(offset) % PAGE_SIZE;
+    assert_eq!(a.contains_address(address), a.contains(&page));
+    match a.offset_of_address(address) {
+        Some(offset) => {
+            assert!(a.contains(&page));
+            assert_eq!(a.start_address() + offset, address);
+            assert_eq!(a.address_at_offset(offset), Some(address));
+        }
+        None => assert!(!a.contains(&page)),
+    }
+    assert!(PageRange::from_virt_addr(address, PAGE_SIZE).contains_address(address));
+});
@@ -0,0 +1,144 @@
+# Boots the bootloader in QEMU with a minimal kernel, checks its mappings using
+# the self-test, and checks that the kernel is reached.
+#
+# Requires qemu-system-x86_64, binutils, python3, and OVMF with HTTP support.
+# The OVMF firmware path can be overridden using the OVMF environment variable.
+set -e
+
+OVMF="${OVMF:-/usr/share/OVMF/OVMF_CODE.fd}"
+OUT=target/smoke-test
+ESP="$OUT/esp"
+MARKER="uefi-bootloader: reached kernel"
+
+cargo build --manifest-path uefi-bootloader/Cargo.toml --target x86_64-unknown-uefi
+
+rm -rf "$OUT"
+mkdir -p "$ESP/EFI/BOOT"
+
+as --64 -o "$OUT/kernel.o" smoke-test/kernel.S
+# Link the kernel into the higher half, like Theseus.
+ld -static -nostdlib -e _start -Ttext=0xffffffff80100000 -o "$ESP/kernel.elf" "$OUT/kernel.o"
+
+cp target/x86_64-unknown-uefi/debug/uefi-bootloader.efi "$ESP/EFI/BOOT/BOOTX64.EFI"
+
+# Boots QEMU with any further arguments, and waits for $1 to appear in the
+# serial output.
+run_qemu() {
+    marker="$1"
+    shift
+    rm -f "$OUT/serial.log"
+    timeout 60 qemu-system-x86_64 \
+        -drive if=pflash,format=raw,readonly=on,file="$OVMF" \
+        -drive format=raw,file=fat:rw:"$ESP" \
+        -serial file:"$OUT/serial.log" \
+        -display none \
+        -no-reboot \
+        "$@" &
+    QEMU=$!
+
+    for _ in $(seq 60); do
+        if grep -q "$marker" "$OUT/serial.log" 2>/dev/null; then
+            kill "$QEMU"
+            return 0
+        fi
+        sleep 1
+    done
+
+    kill "$QEMU" 2>/dev/null || true
+    return 1
+}
+
+# Check that the bootloader's mappings are as expected. No section contents are
+# requested, as `sections` is unset, and the boot info may extend an existing
+# used virtual range, as `boot_info_address` is unset.
+echo "selftest=true" > "$ESP/bootloader.cfg"
+if ! run_qemu "uefi-bootloader: self-test passed"; then
+    echo "smoke test failed: self-test did not pass"
+    exit 1
+fi
+
+# Check that the boot info is reported as a used virtual range when placed at a
+# fixed address, where it adds a range of its own.
+printf 'selftest=true\nboot_info_address=0xffff810000000000\n' > "$ESP/bootloader.cfg"
+if ! run_qemu "uefi-bootloader: self-test passed"; then
+    echo "smoke test failed: self-test did not pass with a fixed boot info address"
+    exit 1
+fi
+
+# Check that a poisoned guard page is mapped as expected.
+printf 'selftest=true\npoison_guard_page=true\n' > "$ESP/bootloader.cfg"
+if ! run_qemu "uefi-bootloader: self-test passed"; then
+    echo "smoke test failed: self-test did not pass with a poisoned guard page"
+    exit 1
+fi
+
+# Check that the kernel's identity mapping is as expected.
+printf 'selftest=true\nidentity_map_kernel=true\n' > "$ESP/bootloader.cfg"
+if ! run_qemu "uefi-bootloader: self-test passed"; then
+    echo "smoke test failed: self-test did not pass with the kernel identity-mapped"
+    exit 1
+fi
+
+# Check that the contents of the requested sections are passed to the kernel.
+printf 'selftest=true\nsections=.text,.rodata\n' > "$ESP/bootloader.cfg"
+if ! run_qemu "uefi-bootloader: self-test passed"; then
+    echo "smoke test failed: self-test did not pass with section contents"
+    exit 1
+fi
+
+# Check that the early heap is mapped as expected.
+printf 'selftest=true\nearly_heap_size=0x100000\n' > "$ESP/bootloader.cfg"
+if ! run_qemu "uefi-bootloader: self-test passed"; then
+    echo "smoke test failed: self-test did not pass with an early heap"
+    exit 1
+fi
+
+# Check that an empty modules directory loads no modules. The log is written to
+# serial, as the framebuffer is disabled.
+mkdir "$ESP/modules"
+printf 'selftest=true\nframebuffer=false\n' > "$ESP/bootloader.cfg"
+if ! run_qemu "uefi-bootloader: self-test passed"; then
+    echo "smoke test failed: self-test did not pass with an empty modules directory"
+    exit 1
+fi
+if ! grep -q "loaded 0 modules" "$OUT/serial.log"; then
+    echo "smoke test failed: empty modules directory did not load zero modules"
+    exit 1
+fi
+rmdir "$ESP/modules"
+
+# Check that the stack is placed at the configured address.
+echo "stack_address=0xffff808000000000" > "$ESP/bootloader.cfg"
+if ! run_qemu "$MARKER"; then
+    echo "smoke test failed: kernel marker not found in serial output"
+    exit 1
+fi
+
+# Check that the kernel can be downloaded over HTTP. QEMU's user networking
+# makes the host reachable at 10.0.2.2.
+mkdir -p "$OUT/http"
+cp "$ESP/kernel.elf" "$OUT/http/kernel.elf"
+python3 -m http.server --directory "$OUT/http" 8000 > /dev/null 2>&1 &
+HTTP_SERVER=$!
+printf 'stack_address=0xffff808000000000\nhttp_url=http://10.0.2.2:8000/\n' > "$ESP/bootloader.cfg"
+if ! run_qemu "$MARKER" -nic user,model=virtio-net-pci; then
+    kill "$HTTP_SERVER"
+    echo "smoke test failed: kernel was not downloaded over HTTP"
+    exit 1
+fi
+kill "$HTTP_SERVER"
+
+# Check that a corrupted kernel falls back to the fallback kernel, and that the
+# rejection is reported as a structured record in the serial log.
+cp "$ESP/kernel.elf" "$ESP/kernel-fallback.elf"
+echo "not an ELF file" > "$ESP/kernel.elf"
+printf 'kernel_fallback=kernel-fallback.elf\nframebuffer=false\nlog_format=json\n' > "$ESP/bootloader.cfg"
+if ! run_qemu "$MARKER"; then
+    echo "smoke test failed: fallback kernel was not booted"
+    exit 1
+fi
+if ! grep -q '^{"level":"WARN","target":"uefi_bootloader::kernel",' "$OUT/serial.log"; then
+    echo "smoke test failed: rejected kernel was not logged as a JSON record"
+    exit 1
+fi
+echo "smoke test passed"
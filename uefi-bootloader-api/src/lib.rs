@@ -1,27 +1,549 @@
 //! Types to interact with the `uefi-bootloader` crate.
+//!
+//! # Calling convention
+//!
+//! The bootloader jumps to the kernel's entry point with the following
+//! arguments in registers:
+//!
+//! | Argument                                 | x86_64 | aarch64 |
+//! |------------------------------------------|--------|---------|
+//! | A pointer to the [`BootInformation`]     | `rdi`  | `x0`    |
+//! | The top of the kernel's stack            | `rsi`  | `x1`    |
+//! | The [physical memory offset][offset]     | `rdx`  | `x2`    |
+//!
+//! The stack pointer is also set to the top of the stack. Minimal kernels can
+//! use the other arguments to bootstrap themselves before reading the boot
+//! information.
+//!
+//! [offset]: NO_PHYSICAL_MEMORY_OFFSET
 
 #![feature(pointer_byte_offsets)]
 #![no_std]
 
 use core::{ops, slice, str};
 
+/// The size of the pages the bootloader maps and of the memory regions it
+/// reports.
+pub const PAGE_SIZE: usize = 4096;
+
+/// The physical memory offset passed to the kernel if physical memory isn't
+/// mapped.
+///
+/// Otherwise, the offset is the virtual address at which physical address
+/// zero is mapped, which is zero if
+/// [`identity_mapped`](BootInformation::identity_mapped) is set.
+pub const NO_PHYSICAL_MEMORY_OFFSET: usize = usize::MAX;
+
+/// The maximum number of [`GopMode`]s reported in [`BootInformation`].
+pub const MAX_GOP_MODES: usize = 64;
+
+/// The maximum number of parameters passed to the kernel.
+pub const MAX_PARAMETERS: usize = 32;
+
+/// The maximum combined length in bytes of the keys and values of the
+/// parameters passed to the kernel.
+pub const MAX_PARAMETERS_SIZE: usize = 4096;
+
+/// The maximum number of physical memory regions mapped at the request of
+/// the bootloader configuration.
+pub const MAX_PRE_MAPPED_REGIONS: usize = 8;
+
+/// The maximum length in bytes of the kernel command line.
+pub const MAX_COMMAND_LINE_SIZE: usize = 4096;
+
+/// The UEFI memory type of the kernel image and the page tables created by
+/// the bootloader.
+pub const KERNEL_MEMORY_TYPE: u32 = 0xffff_ffff;
+
+/// The UEFI memory type of the module contents, unless the bootloader is
+/// configured to use a different type with the `module_memory_type` option.
+pub const DEFAULT_MODULE_MEMORY_TYPE: u32 = 0x8000_0000;
+
+/// The UEFI memory type of the temporary buffers allocated by the bootloader,
+/// which are reported as [`MemoryRegionKind::BootloaderReclaimable`].
+pub const RECLAIMABLE_MEMORY_TYPE: u32 = 0xffff_fffe;
+
+/// The name of the ELF note in which a kernel declares the [`Features`] it
+/// requires.
+pub const FEATURES_NOTE_NAME: &[u8] = b"uefi-bootloader\0";
+
+/// The type of the ELF note in which a kernel declares the [`Features`] it
+/// requires.
+///
+/// The note must be in a `PT_NOTE` segment, and its descriptor must be the
+/// [`bits`](Features::bits) of the required features as a `u64` in the
+/// kernel's byte order. The bootloader refuses to boot a kernel that requires
+/// features it doesn't provide, including features it doesn't know about.
+pub const NT_REQUIRED_FEATURES: u32 = 1;
+
+/// The pattern that fills a poisoned guard page, repeated as a `u64` in the
+/// kernel's byte order.
+///
+/// See [`Stack::lower_guard_page_poisoned`].
+pub const GUARD_PAGE_POISON: u64 = 0xdead_beef_dead_beef;
+
+/// A set of features that the bootloader provides to the kernel.
+///
+/// Some features are only provided if they are enabled in the bootloader
+/// configuration or supported by the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(transparent)]
+pub struct Features(u64);
+
+impl Features {
+    /// All RAM is identity-mapped, as described by
+    /// [`BootInformation::identity_mapped`].
+    pub const IDENTITY_MAP: Self = Self(1 << 0);
+    /// The kernel's symbol table is passed in [`BootInformation::symbols`], if
+    /// the kernel has one.
+    pub const SYMBOLS: Self = Self(1 << 1);
+    /// The UEFI memory map is passed in
+    /// [`BootInformation::uefi_memory_regions`].
+    pub const UEFI_MEMORY_MAP: Self = Self(1 << 2);
+    /// A framebuffer is passed in [`BootInformation::frame_buffer`].
+    pub const FRAME_BUFFER: Self = Self(1 << 3);
+    /// The guard page below the stack is mapped and poisoned, as described by
+    /// [`Stack::lower_guard_page_poisoned`].
+    pub const POISONED_GUARD_PAGE: Self = Self(1 << 4);
+    /// The kernel's segments are also identity-mapped, as described by
+    /// [`BootInformation::kernel_identity_mapped`].
+    pub const KERNEL_IDENTITY_MAP: Self = Self(1 << 5);
+    /// Memory is reserved for the kernel's early allocator, as described by
+    /// [`BootInformation::early_heap`].
+    pub const EARLY_HEAP: Self = Self(1 << 6);
+
+    /// Returns the empty set of features.
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Creates a set of features from its bits, including bits that don't
+    /// correspond to a known feature.
+    #[must_use]
+    pub const fn from_bits_retain(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the bits of the set of features.
+    #[must_use]
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns whether the set is empty.
+    #[must_use]
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns whether every feature in `other` is in `self`.
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the features that are in either `self` or `other`.
+    #[must_use]
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns the features in `self` that aren't in `other`.
+    #[must_use]
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+/// The permissions of a [`PreMappedRegion`], beyond being readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(transparent)]
+pub struct MappingFlags(u8);
+
+impl MappingFlags {
+    /// The region is writable.
+    pub const WRITABLE: Self = Self(1 << 0);
+    /// The region is executable.
+    pub const EXECUTABLE: Self = Self(1 << 1);
+
+    /// Returns the empty set of flags, for a read-only region.
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the bits of the set of flags.
+    #[must_use]
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Returns whether every flag in `other` is in `self`.
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the flags that are in either `self` or `other`.
+    #[must_use]
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// A macro for defining FFI-safe slice types and implementing their common
+/// traits, which are identical.
+macro_rules! implement_ffi_slice {
+    ($TypeName:ident, $Item:ident) => {
+        #[doc = concat!("FFI-safe slice of [`", stringify!($Item), "`]s, semantically equivalent to")]
+        #[doc = concat!("`&'static mut [", stringify!($Item), "]`.")]
+        #[derive(Debug)]
+        #[repr(C)]
+        pub struct $TypeName {
+            pub(crate) ptr: *mut $Item,
+            pub(crate) len: usize,
+        }
+
+        impl ops::Deref for $TypeName {
+            type Target = [$Item];
+
+            fn deref(&self) -> &Self::Target {
+                // SAFETY: Pointer and length were calculated from a valid slice.
+                unsafe { slice::from_raw_parts(self.ptr, self.len) }
+            }
+        }
+
+        impl ops::DerefMut for $TypeName {
+            fn deref_mut(&mut self) -> &mut Self::Target {
+                // SAFETY: Pointer and length were calculated from a valid slice.
+                unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+            }
+        }
+
+        impl From<&'static mut [$Item]> for $TypeName {
+            fn from(items: &'static mut [$Item]) -> Self {
+                Self {
+                    ptr: items.as_mut_ptr(),
+                    len: items.len(),
+                }
+            }
+        }
+
+        impl From<$TypeName> for &'static mut [$Item] {
+            fn from(items: $TypeName) -> Self {
+                // SAFETY: Pointer and length were calculated from a valid slice.
+                unsafe { slice::from_raw_parts_mut(items.ptr, items.len) }
+            }
+        }
+    };
+}
+
+implement_ffi_slice!(MemoryRegions, MemoryRegion);
+implement_ffi_slice!(Modules, Module);
+implement_ffi_slice!(ElfSections, ElfSection);
+implement_ffi_slice!(GopModes, GopMode);
+implement_ffi_slice!(Bytes, u8);
+implement_ffi_slice!(Parameters, Parameter);
+implement_ffi_slice!(UefiMemoryRegions, UefiMemoryRegion);
+implement_ffi_slice!(PreMappedRegions, PreMappedRegion);
+implement_ffi_slice!(SectionContentsSlice, SectionContents);
+implement_ffi_slice!(VirtualRanges, VirtualRange);
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct BootInformation {
+    /// The size in bytes of the boot information, including the arrays it
+    /// points to, which directly follow it.
     pub size: usize,
+    /// The physical address of the boot information.
+    ///
+    /// The boot information and the arrays it points to are physically
+    /// contiguous, so [`physical_address_of`](Self::physical_address_of) can
+    /// translate pointers into them.
+    pub physical_address: usize,
+    /// The features the bootloader provided for this boot.
+    pub features: Features,
     pub frame_buffer: Option<FrameBuffer>,
-    pub rsdp_address: Option<usize>,
+    /// The ACPI root system description pointer.
+    ///
+    /// This is `None` if the firmware didn't provide a valid RSDP.
+    pub rsdp: Option<Rsdp>,
+    /// The physical address of the EFI system table.
+    ///
+    /// The table remains valid after the bootloader exits boot services, but
+    /// isn't necessarily mapped in the kernel's page table. Its boot services
+    /// and console pointers are no longer valid, but its configuration table
+    /// and runtime services pointers are, so the kernel can find the ACPI and
+    /// SMBIOS tables itself.
+    pub system_table_address: usize,
+    /// The number of logical processors, as reported by the firmware's MP
+    /// services protocol.
+    ///
+    /// This is `None` if the firmware doesn't support the protocol. The kernel
+    /// should treat it as a hint, and still enumerate processors using ACPI.
+    pub cpu_count: Option<CpuCount>,
     pub memory_regions: MemoryRegions,
     pub modules: Modules,
+    /// The physical memory containing the module contents, which
+    /// [`Module::offset`] is relative to.
+    ///
+    /// This memory isn't mapped in the kernel's page table, unless all RAM is
+    /// [identity-mapped](Self::identity_mapped). It is empty if there are no
+    /// modules.
+    pub modules_physical_range: PhysicalRange,
     pub elf_sections: ElfSections,
+    /// The graphics modes supported by the framebuffer.
+    ///
+    /// At most [`MAX_GOP_MODES`] modes are reported. Modes that don't have an
+    /// RGB or BGR pixel format are omitted.
+    pub gop_modes: GopModes,
+    /// The physical memory range spanned by the kernel's loaded segments.
+    ///
+    /// This is the smallest range containing every segment, so it may contain
+    /// memory that doesn't belong to the kernel.
+    pub kernel_physical_range: PhysicalRange,
+    /// The offset the bootloader added to the kernel's link addresses when
+    /// relocating it.
+    ///
+    /// This is zero unless the kernel is relocatable, and is random if KASLR
+    /// is enabled in the bootloader configuration. The addition wraps around,
+    /// so a kernel loaded below its link addresses has a bias above
+    /// `usize::MAX / 2`.
+    pub kernel_load_bias: usize,
+    /// The kernel's initial stack.
+    pub stack: Stack,
+    /// Memory reserved for the kernel's allocator to use before it has parsed
+    /// the memory map.
+    ///
+    /// This is `None` unless the `early_heap_size` option is set in the
+    /// bootloader configuration.
+    pub early_heap: Option<EarlyHeap>,
+    /// Information about the UEFI firmware.
+    pub firmware: FirmwareInfo,
+    /// Whether all RAM is identity-mapped, except for the memory containing
+    /// the kernel.
+    ///
+    /// This is only the case if the `identity_map` option is enabled in the
+    /// bootloader configuration.
+    pub identity_mapped: bool,
+    /// Whether the kernel's segments are mapped at their physical addresses,
+    /// in addition to their link addresses.
+    ///
+    /// This is only the case if the `identity_map_kernel` option is enabled in
+    /// the bootloader configuration. The identity mappings have the same
+    /// flags as the segments, and the kernel should unmap them once it's
+    /// running at its link address. Segments that are linked at their
+    /// physical address are only mapped once.
+    pub kernel_identity_mapped: bool,
+    /// The address of the page containing the bootloader's context switch
+    /// code, which is identity-mapped in the kernel's page table.
+    ///
+    /// Only this page is mapped, as it must remain executable while the
+    /// bootloader switches to the kernel's page table. The kernel should unmap
+    /// it once it's running. This is `None` if
+    /// [`identity_mapped`](Self::identity_mapped) is set, in which case the
+    /// page is mapped as part of RAM.
+    pub trampoline: Option<usize>,
+    /// Whether the no-execute bit in page table entries is enforced when the
+    /// kernel is entered.
+    ///
+    /// On x86_64, this reflects `EFER.NXE`. If it's clear, the kernel must set
+    /// it before relying on no-execute mappings. It's always set on aarch64.
+    pub nx_enabled: bool,
+    /// Whether read-only pages are write protected in the kernel's privilege
+    /// level when the kernel is entered.
+    ///
+    /// On x86_64, this reflects `CR0.WP`. It's always set on aarch64.
+    pub write_protect_enabled: bool,
+    /// The entries in the bootloader configuration that the bootloader doesn't
+    /// recognise.
+    ///
+    /// At most [`MAX_PARAMETERS`] parameters are passed, with a combined size
+    /// of at most [`MAX_PARAMETERS_SIZE`].
+    pub parameters: Parameters,
+    /// The kernel command line.
+    ///
+    /// This is the `command_line` option in the bootloader configuration,
+    /// followed by the UEFI load options the bootloader was started with,
+    /// separated by a space. It is empty if neither is present, and at most
+    /// [`MAX_COMMAND_LINE_SIZE`] bytes long.
+    ///
+    /// Note that the UEFI shell includes the path of the bootloader in its load
+    /// options.
+    #[doc(hidden)]
+    pub command_line: Bytes,
+    /// The version of the bootloader, followed by the commit it was built
+    /// from in parentheses if it was built from a git checkout.
+    #[doc(hidden)]
+    pub bootloader_version: Bytes,
+    /// Whether the `debug` option is enabled in the bootloader configuration.
+    pub debug: bool,
+    /// The memory map reported by the UEFI firmware when the bootloader
+    /// exited boot services.
+    ///
+    /// This is only present if [`debug`](Self::debug) is set, and is empty
+    /// otherwise.
+    pub uefi_memory_regions: UefiMemoryRegions,
+    /// The physical memory regions mapped at the request of `map_phys` options
+    /// in the bootloader configuration, in the order they were requested.
+    ///
+    /// At most [`MAX_PRE_MAPPED_REGIONS`] regions are mapped.
+    pub pre_mapped_regions: PreMappedRegions,
+    /// The virtual memory reserved by the bootloader, in ascending order.
+    ///
+    /// The bootloader reserves whole top-level page table entries, so these
+    /// ranges cover everything it mapped in the kernel's page table, including
+    /// the kernel, stack, framebuffer, boot information, identity mappings,
+    /// and the recursive mapping on x86_64. The kernel can map memory outside
+    /// these ranges without colliding with the bootloader's mappings.
+    pub used_virtual_ranges: VirtualRanges,
+    /// The contents of the kernel's sections named by the `sections` option
+    /// in the bootloader configuration, in the order they appear in the
+    /// kernel.
+    pub section_contents: SectionContentsSlice,
+    /// The kernel's symbol table.
+    ///
+    /// This is only present if the `symbols` or `debug` option is enabled in
+    /// the bootloader configuration, and the kernel has a symbol table.
+    pub symbols: Option<SymbolTable>,
+    /// The kernel's GNU build ID, from the `NT_GNU_BUILD_ID` note in its
+    /// `PT_NOTE` segments.
+    ///
+    /// This can be used to match a kernel to its debug symbols. Only 20 byte
+    /// (SHA-1) build IDs are reported.
+    pub build_id: Option<[u8; 20]>,
+}
+
+impl BootInformation {
+    /// The kernel command line.
+    #[must_use]
+    pub fn command_line(&self) -> &str {
+        str::from_utf8(&self.command_line).expect("invalid bytes in command line")
+    }
+
+    /// The version of the bootloader, e.g. `0.1.0 (abc1234)`.
+    #[must_use]
+    pub fn bootloader_version(&self) -> &str {
+        str::from_utf8(&self.bootloader_version).expect("invalid bytes in bootloader version")
+    }
+
+    /// Returns the physical address of `pointer`, or `None` if it doesn't
+    /// point into the boot information or the arrays it points to.
+    ///
+    /// The boot information must be accessed at the virtual address the
+    /// bootloader passed to the kernel.
+    #[must_use]
+    pub fn physical_address_of<T>(&self, pointer: *const T) -> Option<usize> {
+        let offset = (pointer as usize).checked_sub(self as *const Self as usize)?;
+        (offset < self.size).then(|| self.physical_address + offset)
+    }
+}
+
+/// The contents of the kernel's `.symtab` section, and its associated string
+/// table.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SymbolTable {
+    /// The raw `.symtab` section.
+    pub symtab: Bytes,
+    /// The raw string table section linked to by `.symtab`, usually `.strtab`.
+    pub strtab: Bytes,
+}
+
+/// The location and revision of the ACPI root system description pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Rsdp {
+    /// The physical address of the RSDP.
+    pub address: usize,
+    /// The revision of the RSDP.
+    ///
+    /// This is 0 for ACPI 1.0, and 2 for ACPI 2.0 and later.
+    pub revision: u8,
+}
+
+/// The number of logical processors in the system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct CpuCount {
+    /// The total number of logical processors, including the bootstrap
+    /// processor.
+    pub total: usize,
+    /// The number of logical processors that are enabled, i.e. can be started.
+    pub enabled: usize,
+}
+
+/// The stack the kernel is entered with.
+///
+/// The stack is surrounded by an unmapped guard page on either side, so
+/// overflowing or underflowing it causes a page fault, unless the lower guard
+/// page is [poisoned](Self::lower_guard_page_poisoned).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Stack {
+    /// The virtual address of the lowest byte of the stack.
+    pub bottom: usize,
+    /// The virtual address one past the highest byte of the stack.
+    ///
+    /// This is the initial stack pointer.
+    pub top: usize,
+    /// The virtual address of the guard page below the stack.
+    pub lower_guard_page: usize,
+    /// The virtual address of the guard page above the stack.
+    pub upper_guard_page: usize,
+    /// Whether the guard page below the stack is mapped writable to a frame
+    /// filled with [`GUARD_PAGE_POISON`], rather than left unmapped.
+    ///
+    /// Overflowing the stack then doesn't fault, but the kernel can detect it
+    /// by checking that the page still only contains the pattern.
+    pub lower_guard_page_poisoned: bool,
+}
+
+/// Memory reserved for the kernel's early allocator.
+///
+/// The memory is physically contiguous, mapped writable and non-executable,
+/// and reported as [`Bootloader`](MemoryRegionKind::Bootloader) memory, so the
+/// kernel can use it without consulting the memory map. It isn't zeroed.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct EarlyHeap {
+    /// The physical address of the start of the heap.
+    pub physical: usize,
+    /// The virtual address at which the heap is mapped.
+    pub virt: usize,
+    /// The length of the heap in bytes, which is a multiple of the page size.
+    pub len: usize,
+}
+
+/// A range of physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct PhysicalRange {
+    /// The physical start address of the range.
+    pub start: usize,
+    /// The length of the range in bytes.
+    pub len: usize,
+}
+
+/// A range of virtual memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct VirtualRange {
+    /// The virtual start address of the range.
+    pub start: usize,
+    /// The length of the range in bytes.
+    pub len: usize,
 }
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct FrameBuffer {
-    /// The framebuffer's physical address.
+    /// The framebuffer's physical address, as reported by the firmware.
+    ///
+    /// This can be used to remap the framebuffer with different attributes.
     pub physical: usize,
-    /// The framebuffer's virtual address.
+    /// The virtual address at which the bootloader mapped the framebuffer.
     pub virt: usize,
     pub info: FrameBufferInfo,
 }
@@ -44,45 +566,19 @@ pub enum PixelFormat {
     Bgr,
 }
 
-/// FFI-safe slice of [`MemoryRegion`] structs, semantically equivalent to
-/// `&'static mut [MemoryRegion]`.
-#[derive(Debug)]
+/// A graphics mode supported by the framebuffer.
+///
+/// The firmware's graphics protocol is unavailable after exiting boot services,
+/// so switching to one of these modes requires a kernel display driver.
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
-pub struct MemoryRegions {
-    pub(crate) ptr: *mut MemoryRegion,
-    pub(crate) len: usize,
-}
-
-impl ops::Deref for MemoryRegions {
-    type Target = [MemoryRegion];
-
-    fn deref(&self) -> &Self::Target {
-        // SAFETY: Pointer and length were calculated from a valid slice.
-        unsafe { slice::from_raw_parts(self.ptr, self.len) }
-    }
-}
-
-impl ops::DerefMut for MemoryRegions {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        // SAFETY: Pointer and length were calculated from a valid slice.
-        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
-    }
-}
-
-impl From<&'static mut [MemoryRegion]> for MemoryRegions {
-    fn from(regions: &'static mut [MemoryRegion]) -> Self {
-        MemoryRegions {
-            ptr: regions.as_mut_ptr(),
-            len: regions.len(),
-        }
-    }
-}
-
-impl From<MemoryRegions> for &'static mut [MemoryRegion] {
-    fn from(regions: MemoryRegions) -> &'static mut [MemoryRegion] {
-        // SAFETY: Pointer and length were calculated from a valid slice.
-        unsafe { slice::from_raw_parts_mut(regions.ptr, regions.len) }
-    }
+pub struct GopMode {
+    /// The index of the mode as reported by the firmware.
+    pub index: u32,
+    pub width: usize,
+    pub height: usize,
+    pub pixel_format: PixelFormat,
+    pub stride: usize,
 }
 
 /// Represent a physical memory region.
@@ -111,6 +607,20 @@ impl MemoryRegion {
     }
 }
 
+/// A region of the memory map reported by the UEFI firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct UefiMemoryRegion {
+    /// The UEFI memory type of the region.
+    pub ty: u32,
+    /// The physical start address of the region.
+    pub start: usize,
+    /// The number of 4 KiB pages in the region.
+    pub page_count: usize,
+    /// The UEFI memory attributes of the region.
+    pub attributes: u64,
+}
+
 /// Represents the different types of memory.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[non_exhaustive]
@@ -123,51 +633,75 @@ pub enum MemoryRegionKind {
     ///
     /// This memory should _not_ be used by the kernel.
     Bootloader,
+    /// The framebuffer described by [`BootInformation::frame_buffer`].
+    ///
+    /// This memory should only be used by the kernel to draw to the screen.
+    FrameBuffer,
+    /// Memory containing ACPI tables.
+    ///
+    /// The kernel can use this memory once it no longer needs the tables.
+    AcpiReclaimable,
+    /// Memory reserved by the firmware for ACPI, which must be preserved
+    /// across sleep states.
+    ///
+    /// This memory should _not_ be used by the kernel.
+    AcpiNvs,
+    /// Temporary buffers used by the bootloader, such as the kernel file and
+    /// configuration it read.
+    ///
+    /// Everything the boot information refers to has been copied out of this
+    /// memory, so the kernel can use it freely.
+    BootloaderReclaimable,
     /// An unknown memory region reported by the UEFI firmware.
     ///
-    /// Contains the UEFI memory type tag.
+    /// Contains the UEFI memory type tag. This includes the memory allocated
+    /// by the bootloader for the kernel image, which has the type
+    /// [`KERNEL_MEMORY_TYPE`], and for the modules, which has the type
+    /// [`DEFAULT_MODULE_MEMORY_TYPE`] unless configured otherwise.
     UnknownUefi(u32),
 }
 
-/// FFI-safe slice of [`Module`] structs, semantically equivalent to `&'static
-/// mut [Module]`.
+/// The contents of a section of the kernel's ELF file.
 #[derive(Debug)]
 #[repr(C)]
-pub struct Modules {
-    pub(crate) ptr: *mut Module,
-    pub(crate) len: usize,
-}
-
-impl ops::Deref for Modules {
-    type Target = [Module];
-
-    fn deref(&self) -> &Self::Target {
-        // SAFETY: Pointer and length were calculated from a valid slice.
-        unsafe { slice::from_raw_parts(self.ptr, self.len) }
-    }
-}
-
-impl ops::DerefMut for Modules {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        // SAFETY: Pointer and length were calculated from a valid slice.
-        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
-    }
+pub struct SectionContents {
+    /// The name of the section encoded as a null-terminated UTF-8 string.
+    #[doc(hidden)]
+    pub name: [u8; 64],
+    /// The contents of the section.
+    ///
+    /// This is zeroed for `SHT_NOBITS` sections.
+    pub bytes: Bytes,
 }
 
-impl From<&'static mut [Module]> for Modules {
-    fn from(modules: &'static mut [Module]) -> Self {
-        Self {
-            ptr: modules.as_mut_ptr(),
-            len: modules.len(),
-        }
+impl SectionContents {
+    /// The name of the section.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        let end = self
+            .name
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(self.name.len());
+        str::from_utf8(&self.name[..end]).expect("invalid bytes in section name")
     }
 }
 
-impl From<Modules> for &'static mut [Module] {
-    fn from(modules: Modules) -> Self {
-        // SAFETY: Pointer and length were calculated from a valid slice.
-        unsafe { slice::from_raw_parts_mut(modules.ptr, modules.len) }
-    }
+/// A physical memory region, such as a device's MMIO registers, mapped at the
+/// request of a `map_phys` option in the bootloader configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct PreMappedRegion {
+    /// The physical address of the start of the region.
+    pub phys: usize,
+    /// The virtual address at which the region is mapped.
+    ///
+    /// This has the same offset into a page as [`phys`](Self::phys).
+    pub virt: usize,
+    /// The length of the region in bytes.
+    pub len: usize,
+    /// The permissions the region is mapped with.
+    pub flags: MappingFlags,
 }
 
 /// A file.
@@ -179,7 +713,9 @@ pub struct Module {
     pub name: [u8; 64],
     /// The offset in bytes from the start of the modules.
     ///
-    /// The offset is guaranteed to be page aligned.
+    /// The offset is page aligned, unless a smaller alignment is configured
+    /// with the `module_align` option in the bootloader configuration, in
+    /// which case modules may share pages.
     pub offset: usize,
     /// The length of the module in bytes.
     pub len: usize,
@@ -198,44 +734,57 @@ impl Module {
     }
 }
 
-/// FFI-safe slice of [`ElfSection`] structs, semantically equivalent to
-/// `&'static mut [ElfSection]`.
+/// A `key=value` entry from the bootloader configuration.
+///
+/// Whitespace surrounding the key and value is removed, and the value extends
+/// to the end of the line. There is no escaping, so keys can't contain `=` and
+/// neither keys nor values can contain newlines.
 #[derive(Debug)]
 #[repr(C)]
-pub struct ElfSections {
-    pub(crate) ptr: *mut ElfSection,
-    pub(crate) len: usize,
+pub struct Parameter {
+    #[doc(hidden)]
+    pub key: Bytes,
+    #[doc(hidden)]
+    pub value: Bytes,
 }
 
-impl ops::Deref for ElfSections {
-    type Target = [ElfSection];
-
-    fn deref(&self) -> &Self::Target {
-        // SAFETY: Pointer and length were calculated from a valid slice.
-        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+impl Parameter {
+    /// The key of the parameter.
+    #[must_use]
+    pub fn key(&self) -> &str {
+        str::from_utf8(&self.key).expect("invalid bytes in parameter key")
     }
-}
 
-impl ops::DerefMut for ElfSections {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        // SAFETY: Pointer and length were calculated from a valid slice.
-        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    /// The value of the parameter.
+    #[must_use]
+    pub fn value(&self) -> &str {
+        str::from_utf8(&self.value).expect("invalid bytes in parameter value")
     }
 }
 
-impl From<&'static mut [ElfSection]> for ElfSections {
-    fn from(elf_sections: &'static mut [ElfSection]) -> Self {
-        Self {
-            ptr: elf_sections.as_mut_ptr(),
-            len: elf_sections.len(),
-        }
-    }
+/// Information about the UEFI firmware that loaded the bootloader.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FirmwareInfo {
+    /// The firmware vendor encoded as a null-terminated UTF-8 string.
+    ///
+    /// Vendor names that don't fit are truncated.
+    #[doc(hidden)]
+    pub vendor: [u8; 64],
+    /// The vendor-specific firmware revision.
+    pub revision: u32,
 }
 
-impl From<ElfSections> for &'static mut [ElfSection] {
-    fn from(elf_sections: ElfSections) -> Self {
-        // SAFETY: Pointer and length were calculated from a valid slice.
-        unsafe { slice::from_raw_parts_mut(elf_sections.ptr, elf_sections.len) }
+impl FirmwareInfo {
+    /// The firmware vendor.
+    #[must_use]
+    pub fn vendor(&self) -> &str {
+        let end = self
+            .vendor
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(self.vendor.len());
+        str::from_utf8(&self.vendor[..end]).expect("invalid bytes in firmware vendor")
     }
 }
 
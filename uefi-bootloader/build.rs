@@ -0,0 +1,21 @@
+use std::{env, process::Command};
+
+fn main() {
+    let version = env::var("CARGO_PKG_VERSION").expect("CARGO_PKG_VERSION is not set");
+
+    // The commit is only available when building from a git checkout.
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    let version = match commit {
+        Some(commit) => format!("{version} ({})", commit.trim()),
+        None => version,
+    };
+
+    println!("cargo:rustc-env=UEFI_BOOTLOADER_VERSION={version}");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+}
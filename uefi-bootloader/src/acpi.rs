@@ -0,0 +1,64 @@
+use core::slice;
+use log::warn;
+use uefi::table::{
+    cfg::{ACPI2_GUID, ACPI_GUID},
+    Boot, SystemTable,
+};
+use uefi_bootloader_api::Rsdp;
+
+/// The signature at the start of the RSDP.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// The length of the ACPI 1.0 RSDP, which is covered by the first checksum.
+const RSDP_V1_LEN: usize = 20;
+
+/// The length of the ACPI 2.0+ RSDP, which is covered by the extended checksum.
+const RSDP_V2_LEN: usize = 36;
+
+/// Returns the RSDP listed in the firmware's configuration table.
+///
+/// ACPI 2.0+ RSDPs are preferred. RSDPs with an invalid signature or checksum
+/// are ignored, so that the kernel can fall back to its own discovery.
+pub(crate) fn get_rsdp(system_table: &SystemTable<Boot>) -> Option<Rsdp> {
+    [ACPI2_GUID, ACPI_GUID].iter().find_map(|guid| {
+        let entry = system_table
+            .config_table()
+            .iter()
+            .find(|entry| entry.guid == *guid)?;
+        let address = entry.address as usize;
+
+        match validate_rsdp(address) {
+            Some(revision) => Some(Rsdp { address, revision }),
+            None => {
+                warn!("ignoring invalid RSDP at {address:#x}");
+                None
+            }
+        }
+    })
+}
+
+/// Returns the revision of the RSDP at `address` if it's valid.
+fn validate_rsdp(address: usize) -> Option<u8> {
+    // SAFETY: The firmware listed the address in the configuration table, and
+    // memory is identity-mapped.
+    let bytes = unsafe { slice::from_raw_parts(address as *const u8, RSDP_V1_LEN) };
+    if &bytes[..RSDP_SIGNATURE.len()] != RSDP_SIGNATURE || !is_checksum_valid(bytes) {
+        return None;
+    }
+
+    let revision = bytes[15];
+    if revision >= 2 {
+        // SAFETY: ACPI 2.0+ RSDPs are at least this long.
+        let bytes = unsafe { slice::from_raw_parts(address as *const u8, RSDP_V2_LEN) };
+        if !is_checksum_valid(bytes) {
+            return None;
+        }
+    }
+
+    Some(revision)
+}
+
+/// Returns whether `bytes` sums to zero.
+fn is_checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
@@ -1,5 +1,8 @@
 use crate::{
-    memory::{Frame, FrameAllocator, Page, PhysicalAddress, VirtualAddress, PAGE_SIZE},
+    memory::{
+        Frame, FrameAllocator, MapError, Page, PageRange, PhysicalAddress, UsedRanges,
+        VirtualAddress, PAGE_SIZE,
+    },
     RuntimeContext,
 };
 use bit_field::BitField;
@@ -10,6 +13,9 @@ use core::{
 use cortex_a::{asm::barrier, registers::TTBR0_EL1};
 use goblin::elf64::program_header::ProgramHeader;
 
+/// The level 0 index of the recursive page table entry.
+pub(crate) const RECURSIVE_INDEX: usize = 510;
+
 /// On aarch64, VAs are composed of an ASID
 /// which is 8 or 16 bits long depending
 /// on MMU config. In Theseus, we use 8-bits
@@ -44,6 +50,57 @@ pub(crate) const fn canonicalize_physical_address(phys_addr: usize) -> usize {
     phys_addr & 0x0000_FFFF_FFFF_FFFF
 }
 
+// Compile-time checks of the address arithmetic, as the bootloader can't run
+// tests on the host.
+const _: () = {
+    // The upper 16 bits are cleared.
+    assert!(canonicalize_virtual_address(0xffff_8000_0000_1000) == 0x0000_8000_0000_1000);
+    assert!(canonicalize_virtual_address(0x0000_ffff_ffff_ffff) == 0x0000_ffff_ffff_ffff);
+    assert!(canonicalize_physical_address(0xffff_0000_0000_1000) == 0x1000);
+
+    // Addresses are rounded down to the containing page or frame.
+    assert!(Page::containing_address(VirtualAddress::new_canonical(0x1fff)).number() == 1);
+    assert!(Frame::containing_address(PhysicalAddress::new_canonical(0xfff)).number() == 0);
+    assert!(
+        Page::containing_address(VirtualAddress::new_canonical(0x0000_ff80_0000_0000)).p0_index()
+            == 511
+    );
+
+    // Level 0 addresses round-trip through their index.
+    assert!(
+        Page::containing_address(VirtualAddress::new_canonical(level_0_address(1))).p0_index() == 1
+    );
+    assert!(
+        Page::containing_address(VirtualAddress::new_canonical(level_0_address(511))).p0_index()
+            == 511
+    );
+};
+
+/// Returns the start address of the level 0 entry with the given index.
+const fn level_0_address(index: usize) -> usize {
+    // The level 0 index is stored in bits 39..48, covering all 512 entries.
+    (index & 0x1ff) << 39
+}
+
+/// The bits of a descriptor that hold its output address.
+const OUTPUT_ADDRESS_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// Returns the descriptor `entry` with its flags replaced by `flags`, keeping
+/// its output address.
+const fn replace_flags(entry: u64, flags: u64) -> u64 {
+    entry & OUTPUT_ADDRESS_MASK | flags & !OUTPUT_ADDRESS_MASK
+}
+
+// Replacing the flags of a descriptor keeps the frame it maps, and none of its
+// old flags.
+const _: () = {
+    let frame = 0x0000_1234_5678_9000;
+    let flags = 1 << 0 | 1 << 1 | 1 << 7;
+    let updated = replace_flags(frame | 1 << 0 | 1 << 1 | 1 << 10 | 1 << 54, flags);
+    assert!(updated & OUTPUT_ADDRESS_MASK == frame);
+    assert!(updated & !OUTPUT_ADDRESS_MASK == flags);
+};
+
 pub(crate) fn set_up_arch_specific_mappings(context: &mut RuntimeContext) {
     let flags = PteFlags::new()
         .present(true)
@@ -54,7 +111,7 @@ pub(crate) fn set_up_arch_specific_mappings(context: &mut RuntimeContext) {
 
     let top_level_frame = context.mapper.frame();
     let top_level = &mut context.mapper.level_zero_page_table;
-    top_level[510].set(top_level_frame, flags);
+    top_level[RECURSIVE_INDEX].set(top_level_frame, flags);
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -116,6 +173,18 @@ impl PteFlags {
     }
 }
 
+impl PteFlags {
+    /// Returns whether pages mapped with these flags are writable.
+    pub(crate) fn is_writable(self) -> bool {
+        !self.0.get_bit(7)
+    }
+
+    /// Returns whether pages mapped with these flags are executable at EL1.
+    pub(crate) fn is_executable(self) -> bool {
+        !self.0.get_bit(53)
+    }
+}
+
 impl Page {
     const fn p0_index(self) -> usize {
         (self.number >> 27) & 0x1ff
@@ -144,6 +213,7 @@ impl PageAllocator {
             level_0_entries: [false; 512],
         };
         page_allocator.level_0_entries[0] = true;
+        page_allocator.level_0_entries[RECURSIVE_INDEX] = true;
 
         page_allocator
     }
@@ -175,10 +245,18 @@ impl PageAllocator {
         let num_level_0_entries = (len + (LEVEL_0_SIZE - 1)) / LEVEL_0_SIZE;
 
         let level_0_index = self.get_free_entries(num_level_0_entries as u64);
-        let mut address = 0;
+        VirtualAddress::new(level_0_address(level_0_index))
+            .expect("allocated invalid virtual address")
+    }
 
-        address.set_bits(39..47, level_0_index);
-        VirtualAddress::new(address).expect("allocated invalid virtual address")
+    /// Returns the pages spanned by each run of allocated level 0 entries.
+    pub(crate) fn used_ranges(&self) -> UsedRanges<'_> {
+        // The kernel's address space is contiguous, as it only uses TTBR0.
+        UsedRanges::new(
+            &self.level_0_entries,
+            |index| VirtualAddress::new_canonical(level_0_address(index)),
+            self.level_0_entries.len(),
+        )
     }
 
     pub(crate) fn mark_segment_as_used(&mut self, segment: &ProgramHeader) {
@@ -188,7 +266,27 @@ impl PageAllocator {
         let start_page = Page::containing_address(start);
         let end_page_inclusive = Page::containing_address(end_inclusive);
 
-        for p0_index in start_page.p0_index()..=end_page_inclusive.p0_index() {
+        self.mark_pages_as_used(&PageRange::new(start_page, end_page_inclusive));
+    }
+
+    /// Returns whether none of the level 0 entries spanned by `pages` have been
+    /// allocated.
+    pub(crate) fn are_pages_free(&self, pages: &PageRange) -> bool {
+        (pages.start().p0_index()..=pages.end().p0_index())
+            .all(|index| !self.level_0_entries[index])
+    }
+
+    /// Prevents the level 0 entries spanned by `pages` from being allocated.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pages` overlaps the recursive page table mapping.
+    pub(crate) fn mark_pages_as_used(&mut self, pages: &PageRange) {
+        for p0_index in pages.start().p0_index()..=pages.end().p0_index() {
+            assert_ne!(
+                p0_index, RECURSIVE_INDEX,
+                "{pages:?} overlaps the recursive page table mapping"
+            );
             self.level_0_entries[p0_index] = true;
         }
     }
@@ -225,6 +323,22 @@ impl Mapper {
         }
     }
 
+    /// Creates a mapper for the existing page table in `frame`.
+    ///
+    /// # Safety
+    ///
+    /// `frame` must contain a level 0 page table, which mustn't be accessed
+    /// through another mapper while the returned mapper is in use.
+    pub(crate) unsafe fn from_frame(frame: Frame) -> Self {
+        let address = frame.start_address().value() as *mut PageTable;
+        Self {
+            // SAFETY: Physical memory is identity-mapped, and the caller
+            // guarantees the frame contains a page table that isn't otherwise
+            // accessed.
+            level_zero_page_table: unsafe { &mut *address },
+        }
+    }
+
     pub(crate) fn frame(&mut self) -> Frame {
         Frame::containing_address(PhysicalAddress::new_canonical(
             self.level_zero_page_table as *const _ as usize,
@@ -237,15 +351,166 @@ impl Mapper {
         frame: Frame,
         flags: PteFlags,
         frame_allocator: &mut T,
-    ) where
+    ) -> Result<(), MapError>
+    where
+        T: FrameAllocator,
+    {
+        let page_table_flags = Self::page_table_flags();
+        let level_2 = self.level_2_table(page, frame_allocator)?;
+        let level_3 = unsafe {
+            level_2.create_next_table(page.p2_index(), page_table_flags, frame_allocator)
+        }?;
+
+        let entry = &mut level_3[page.p3_index()];
+        if !entry.is_unused() {
+            return Err(MapError::PageAlreadyMapped(Frame::containing_address(
+                entry.output_address(),
+            )));
+        }
+        entry.set(frame, flags.accessed(true).page_descriptor(true));
+
+        // The entry was previously invalid, so it can't be cached in the TLB, but the
+        // write must be visible to the table walker before the page is accessed.
+        barrier::dsb(barrier::ISHST);
+        barrier::isb(barrier::SY);
+        Ok(())
+    }
+
+    /// Maps the huge page starting at `page` to the huge frame starting at
+    /// `frame`.
+    ///
+    /// Both `page` and `frame` must be aligned to [`HUGE_PAGE_SIZE`].
+    ///
+    /// [`HUGE_PAGE_SIZE`]: crate::memory::HUGE_PAGE_SIZE
+    pub(crate) fn map_huge<T>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: PteFlags,
+        frame_allocator: &mut T,
+    ) -> Result<(), MapError>
+    where
         T: FrameAllocator,
     {
-        let page_table_flags = PteFlags::new()
+        assert_eq!(page.p3_index(), 0, "huge page is not aligned");
+        assert_eq!(frame.number() % 512, 0, "huge frame is not aligned");
+
+        let level_2 = self.level_2_table(page, frame_allocator)?;
+        let entry = &mut level_2[page.p2_index()];
+        if !entry.is_unused() {
+            return Err(MapError::PageAlreadyMapped(Frame::containing_address(
+                entry.output_address(),
+            )));
+        }
+        // A level 2 entry without the page descriptor bit is a block descriptor.
+        entry.set(frame, flags.accessed(true));
+
+        // See `map`.
+        barrier::dsb(barrier::ISHST);
+        barrier::isb(barrier::SY);
+        Ok(())
+    }
+
+    /// Replaces the flags of the existing mapping of `page`, leaving the frame
+    /// it's mapped to unchanged.
+    ///
+    /// Only 4 KiB pages can be updated, so an error is returned if `page` isn't
+    /// mapped, or is part of a huge page.
+    pub(crate) fn update_flags(&mut self, page: Page, flags: PteFlags) -> Result<(), MapError> {
+        let level_3 = [page.p0_index(), page.p1_index(), page.p2_index()]
+            .into_iter()
+            .try_fold(&mut *self.level_zero_page_table, |table, index| {
+                let entry = &table[index];
+                if entry.is_unused() {
+                    return Err(MapError::PageNotMapped);
+                }
+                if !entry.is_table() {
+                    return Err(MapError::ParentEntryHugePage);
+                }
+                // SAFETY: The entry points to a page table.
+                Ok(unsafe { entry.as_page_table() })
+            })?;
+
+        let entry = &mut level_3[page.p3_index()];
+        if entry.is_unused() {
+            return Err(MapError::PageNotMapped);
+        }
+        entry.0 = replace_flags(entry.0, flags.accessed(true).page_descriptor(true).0);
+
+        barrier::dsb(barrier::ISHST);
+        // SAFETY: Invalidating a TLB entry doesn't change the contents of memory.
+        unsafe { core::arch::asm!("tlbi vaae1, {}", in(reg) page.number()) };
+        barrier::dsb(barrier::ISH);
+        barrier::isb(barrier::SY);
+        Ok(())
+    }
+
+    /// Returns the physical address that `address` is mapped to, and the
+    /// flags it's mapped with, if it's mapped by a page or block descriptor.
+    pub(crate) fn translate(&self, address: VirtualAddress) -> Option<(PhysicalAddress, PteFlags)> {
+        let page = Page::containing_address(address);
+        let mut table: &PageTable = self.level_zero_page_table;
+        // The sizes of the regions mapped by level 1 and 2 block descriptors.
+        for (index, block_size) in [
+            (page.p0_index(), None),
+            (page.p1_index(), Some(1 << 30)),
+            (page.p2_index(), Some(1 << 21)),
+        ] {
+            let entry = &table[index];
+            if entry.is_unused() {
+                return None;
+            }
+            if !entry.is_table() {
+                let block_size: usize = block_size?;
+                let offset = address.value() & (block_size - 1);
+                return Some((entry.output_address() + offset, PteFlags(entry.0)));
+            }
+            // SAFETY: The entry points to a page table.
+            table = unsafe { entry.as_page_table() };
+        }
+
+        let entry = &table[page.p3_index()];
+        let offset = address.value() & (PAGE_SIZE - 1);
+        (!entry.is_unused()).then(|| (entry.output_address() + offset, PteFlags(entry.0)))
+    }
+
+    /// Returns the frame that `page` is mapped to, if it's mapped by a page
+    /// descriptor.
+    pub(crate) fn translate_page(&self, page: Page) -> Option<Frame> {
+        let mut table: &PageTable = self.level_zero_page_table;
+        for index in [page.p0_index(), page.p1_index(), page.p2_index()] {
+            let entry = &table[index];
+            if entry.is_unused() || !entry.is_table() {
+                return None;
+            }
+            // SAFETY: The entry points to a page table.
+            table = unsafe { entry.as_page_table() };
+        }
+
+        let entry = &table[page.p3_index()];
+        (!entry.is_unused()).then(|| Frame::containing_address(entry.output_address()))
+    }
+
+    fn page_table_flags() -> PteFlags {
+        PteFlags::new()
             .present(true)
             .accessed(true)
             .page_descriptor(true)
             .writable(true)
-            .no_execute(true);
+            .no_execute(true)
+    }
+
+    /// Returns the level 2 table containing `page`, creating the intermediate
+    /// tables if necessary.
+    fn level_2_table<T>(
+        &mut self,
+        page: Page,
+        frame_allocator: &mut T,
+    ) -> Result<&mut PageTable, MapError>
+    where
+        T: FrameAllocator,
+    {
+        let page_table_flags = Self::page_table_flags();
 
         let level_1 = unsafe {
             self.level_zero_page_table.create_next_table(
@@ -253,17 +518,8 @@ impl Mapper {
                 page_table_flags,
                 frame_allocator,
             )
-        };
-        let level_2 = unsafe {
-            level_1.create_next_table(page.p1_index(), page_table_flags, frame_allocator)
-        };
-        let level_3 = unsafe {
-            level_2.create_next_table(page.p2_index(), page_table_flags, frame_allocator)
-        };
-
-        level_3[page.p3_index()].set(frame, flags.accessed(true).page_descriptor(true));
-
-        barrier::isb(barrier::SY);
+        }?;
+        unsafe { level_1.create_next_table(page.p1_index(), page_table_flags, frame_allocator) }
     }
 }
 
@@ -279,7 +535,7 @@ impl PageTable {
         index: usize,
         page_table_flags: PteFlags,
         frame_allocator: &mut T,
-    ) -> &mut PageTable
+    ) -> Result<&mut PageTable, MapError>
     where
         T: FrameAllocator,
     {
@@ -287,11 +543,13 @@ impl PageTable {
         if entry.is_unused() {
             let frame = frame_allocator
                 .allocate_frame()
-                .expect("failed to allocate frame for page table");
+                .ok_or(MapError::FrameAllocationFailed)?;
             unsafe { ptr::write_bytes(frame.start_address().value() as *mut PageTable, 0, 1) };
             entry.set(frame, page_table_flags);
+        } else if !entry.is_table() {
+            return Err(MapError::ParentEntryHugePage);
         }
-        unsafe { entry.as_page_table() }
+        Ok(unsafe { entry.as_page_table() })
     }
 }
 
@@ -318,6 +576,13 @@ impl PageTableEntry {
         self.0 == 0
     }
 
+    /// Returns whether the entry points to a page table, rather than a block.
+    ///
+    /// This is only meaningful for entries in levels 0 to 2.
+    fn is_table(&self) -> bool {
+        self.0.get_bit(1)
+    }
+
     fn output_address(&self) -> PhysicalAddress {
         PhysicalAddress::new_canonical(self.0 as usize & (!(PAGE_SIZE - 1) & !(0xffff << 48)))
     }
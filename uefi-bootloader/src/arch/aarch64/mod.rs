@@ -1,4 +1,5 @@
 use crate::KernelContext;
+use bit_field::BitField;
 use core::arch::asm;
 use cortex_a::{
     asm::barrier,
@@ -7,6 +8,7 @@ use cortex_a::{
 use tock_registers::interfaces::{ReadWriteable, Writeable};
 
 pub(crate) mod memory;
+pub(crate) mod serial;
 
 // The function needs to take ownership of the context so that it remains valid
 // when we switch page tables.
@@ -27,9 +29,10 @@ pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
     unsafe {
         asm!(
             "",
+            in("x4") context.entry_point.value(),
             in("x3") ASID_ZERO as usize,
-            in("x2") context.stack_top.value(),
-            in("x1") context.entry_point.value(),
+            in("x2") context.physical_memory_offset,
+            in("x1") context.stack_top.value(),
             in("x0") context.boot_info,
         )
     }
@@ -45,14 +48,53 @@ pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
             // flush the TLB
             "tlbi aside1, x3",
             // set the stack pointer
-            "mov sp, x2",
+            "mov sp, x1",
             // jump to the entry point
-            "br x1",
+            "br x4",
             options(noreturn)
         )
     }
 }
 
+/// Makes instructions written to `bytes` visible to instruction fetches.
+///
+/// The data cache is cleaned to the point of unification over `bytes`, and the
+/// entire instruction cache is invalidated, as the instructions will be fetched
+/// through a different virtual address.
+pub(crate) fn clean_and_invalidate(bytes: &[u8]) {
+    let cache_type: u64;
+    // SAFETY: Reading CTR_EL0 has no side effects.
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) cache_type) };
+    // DminLine is the log2 of the number of words in the smallest data cache line.
+    let line_size = 4 << cache_type.get_bits(16..20) as usize;
+
+    let start = bytes.as_ptr() as usize & !(line_size - 1);
+    let end = bytes.as_ptr() as usize + bytes.len();
+    for address in (start..end).step_by(line_size) {
+        // SAFETY: Cleaning a cache line doesn't change the contents of memory.
+        unsafe { asm!("dc cvau, {}", in(reg) address) };
+    }
+
+    barrier::dsb(barrier::ISH);
+    // SAFETY: Invalidating the instruction cache doesn't change the contents of
+    // memory.
+    unsafe { asm!("ic iallu") };
+    barrier::dsb(barrier::ISH);
+    barrier::isb(barrier::SY);
+}
+
+/// Returns whether the execute-never bits in page table entries are enforced,
+/// which is always the case.
+pub(crate) fn is_no_execute_enabled() -> bool {
+    true
+}
+
+/// Returns whether read-only pages are write protected at EL1, which is always
+/// the case.
+pub(crate) fn is_write_protect_enabled() -> bool {
+    true
+}
+
 pub(crate) fn halt() -> ! {
     loop {
         // SAFETY: This instruction will stop the CPU.
@@ -0,0 +1,90 @@
+use crate::config::SerialConfig;
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The offset of the data register.
+const DATA: usize = 0x00;
+
+/// The offset of the flag register.
+const FLAG: usize = 0x18;
+
+/// The flag register bit that is set while the transmit FIFO is full.
+const TRANSMIT_FIFO_FULL: u32 = 1 << 5;
+
+/// The address of the registers of the UART opened by [`SerialPort::new`], or
+/// zero if no UART is configured.
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the serial port used by [`SerialPort::new`].
+///
+/// The UART is used as the firmware configured it, as its reference clock
+/// isn't known, so the baud rate is ignored.
+///
+/// # Panics
+///
+/// Panics if the base is zero or isn't aligned to a register.
+pub(crate) fn configure(config: SerialConfig) {
+    let base = usize::try_from(config.base)
+        .ok()
+        .filter(|&base| base != 0 && base % 4 == 0)
+        .unwrap_or_else(|| panic!("unsupported serial port base: {:#x}", config.base));
+    BASE.store(base, Ordering::Relaxed);
+}
+
+/// A PL011 UART.
+///
+/// The location of the UART isn't known without parsing the device tree or
+/// ACPI tables, so output is discarded unless [`configure`] was called.
+pub(crate) struct SerialPort {
+    base: usize,
+}
+
+impl SerialPort {
+    /// Opens the configured serial port.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the serial port isn't simultaneously accessed
+    /// elsewhere.
+    pub(crate) unsafe fn new() -> Self {
+        Self {
+            base: BASE.load(Ordering::Relaxed),
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32) {
+        // SAFETY: The address is a register of the UART, which the firmware
+        // maps as device memory.
+        unsafe { ptr::write_volatile((self.base + offset) as *mut u32, value) };
+    }
+
+    fn read_register(&mut self, offset: usize) -> u32 {
+        // SAFETY: The address is a register of the UART, which the firmware
+        // maps as device memory.
+        unsafe { ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if self.base == 0 {
+            return;
+        }
+        while self.read_register(FLAG) & TRANSMIT_FIFO_FULL != 0 {
+            core::hint::spin_loop();
+        }
+        self.write_register(DATA, u32::from(byte));
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
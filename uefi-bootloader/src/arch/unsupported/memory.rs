@@ -1,5 +1,8 @@
 use crate::{
-    memory::{Frame, FrameAllocator, Page, VirtualAddress},
+    memory::{
+        Frame, FrameAllocator, MapError, Page, PageRange, PhysicalAddress, UsedRanges,
+        VirtualAddress,
+    },
     RuntimeContext,
 };
 use goblin::elf64::program_header::ProgramHeader;
@@ -49,6 +52,16 @@ impl PteFlags {
     }
 }
 
+impl PteFlags {
+    pub(crate) fn is_writable(self) -> bool {
+        unimplemented!();
+    }
+
+    pub(crate) fn is_executable(self) -> bool {
+        unimplemented!();
+    }
+}
+
 pub(crate) struct PageAllocator;
 
 impl PageAllocator {
@@ -63,6 +76,18 @@ impl PageAllocator {
     pub(crate) fn mark_segment_as_used(&mut self, _segment: &ProgramHeader) {
         unimplemented!();
     }
+
+    pub(crate) fn are_pages_free(&self, _pages: &PageRange) -> bool {
+        unimplemented!();
+    }
+
+    pub(crate) fn mark_pages_as_used(&mut self, _pages: &PageRange) {
+        unimplemented!();
+    }
+
+    pub(crate) fn used_ranges(&self) -> UsedRanges<'_> {
+        unimplemented!();
+    }
 }
 
 pub(crate) struct Mapper;
@@ -82,6 +107,10 @@ impl Mapper {
         unimplemented!();
     }
 
+    pub(crate) unsafe fn from_frame(_frame: Frame) -> Self {
+        unimplemented!();
+    }
+
     pub(crate) fn frame(&mut self) -> Frame {
         unimplemented!();
     }
@@ -92,9 +121,38 @@ impl Mapper {
         _frame: Frame,
         _flags: PteFlags,
         _frame_allocator: &mut T,
-    ) where
+    ) -> Result<(), MapError>
+    where
         T: FrameAllocator,
     {
         unimplemented!()
     }
+
+    pub(crate) fn map_huge<T>(
+        &mut self,
+        _page: Page,
+        _frame: Frame,
+        _flags: PteFlags,
+        _frame_allocator: &mut T,
+    ) -> Result<(), MapError>
+    where
+        T: FrameAllocator,
+    {
+        unimplemented!()
+    }
+
+    pub(crate) fn update_flags(&mut self, _page: Page, _flags: PteFlags) -> Result<(), MapError> {
+        unimplemented!()
+    }
+
+    pub(crate) fn translate(
+        &self,
+        _address: VirtualAddress,
+    ) -> Option<(PhysicalAddress, PteFlags)> {
+        unimplemented!()
+    }
+
+    pub(crate) fn translate_page(&self, _page: Page) -> Option<Frame> {
+        unimplemented!()
+    }
 }
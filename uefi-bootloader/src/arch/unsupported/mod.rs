@@ -1,6 +1,7 @@
 use crate::KernelContext;
 
 pub(crate) mod memory;
+pub(crate) mod serial;
 
 // The function needs to take ownership of the context so that it remains valid
 // when we switch page tables.
@@ -9,6 +10,18 @@ pub(crate) unsafe fn jump_to_kernel(_context: KernelContext) -> ! {
     unimplemented!();
 }
 
+pub(crate) fn clean_and_invalidate(_bytes: &[u8]) {
+    unimplemented!();
+}
+
+pub(crate) fn is_no_execute_enabled() -> bool {
+    unimplemented!();
+}
+
+pub(crate) fn is_write_protect_enabled() -> bool {
+    unimplemented!();
+}
+
 pub(crate) fn halt() -> ! {
     unimplemented!();
 }
@@ -0,0 +1,24 @@
+use crate::config::SerialConfig;
+use core::fmt;
+
+pub(crate) fn configure(_config: SerialConfig) {
+    unimplemented!();
+}
+
+pub(crate) struct SerialPort;
+
+impl SerialPort {
+    /// # Safety
+    ///
+    /// The caller must ensure the serial port isn't simultaneously accessed
+    /// elsewhere.
+    pub(crate) unsafe fn new() -> Self {
+        unimplemented!();
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, _s: &str) -> fmt::Result {
+        unimplemented!();
+    }
+}
@@ -1,14 +1,22 @@
 use crate::{
-    memory::{Frame, FrameAllocator, Page, PhysicalAddress, VirtualAddress},
+    memory::{
+        Frame, FrameAllocator, MapError, Page, PageRange, PhysicalAddress, UsedRanges,
+        VirtualAddress,
+    },
     RuntimeContext,
 };
 use bit_field::BitField;
 use goblin::elf64::program_header::ProgramHeader;
 use x86_64::{
     registers::control::{Cr3, Cr3Flags},
-    structures::paging::{self, OffsetPageTable, PageTable, PageTableIndex},
+    structures::paging::{
+        self, mapper::TranslateResult, OffsetPageTable, PageTable, PageTableIndex,
+    },
 };
 
+/// The P4 index of the recursive page table entry.
+pub(crate) const RECURSIVE_INDEX: usize = 510;
+
 pub(crate) fn is_canonical_virtual_address(virt_addr: usize) -> bool {
     matches!(virt_addr.get_bits(47..64), 0 | 0b1_1111_1111_1111_1111)
 }
@@ -33,15 +41,35 @@ pub(crate) const fn canonicalize_physical_address(phys_addr: usize) -> usize {
     phys_addr & 0x000F_FFFF_FFFF_FFFF
 }
 
+// Compile-time checks of the address arithmetic, as the bootloader can't run
+// tests on the host.
+const _: () = {
+    // Bit 47 is sign-extended into the upper bits.
+    assert!(canonicalize_virtual_address(0x0000_7fff_ffff_ffff) == 0x0000_7fff_ffff_ffff);
+    assert!(canonicalize_virtual_address(0x0000_8000_0000_0000) == 0xffff_8000_0000_0000);
+    assert!(canonicalize_virtual_address(0x1234_8000_0000_0000) == 0xffff_8000_0000_0000);
+    assert!(canonicalize_virtual_address(0xffff_7fff_ffff_ffff) == 0x0000_7fff_ffff_ffff);
+    // The upper 12 bits of physical addresses are cleared.
+    assert!(canonicalize_physical_address(0xfff0_0000_0000_1000) == 0x1000);
+    assert!(canonicalize_physical_address(0x000f_ffff_ffff_ffff) == 0x000f_ffff_ffff_ffff);
+
+    // Addresses are rounded down to the containing page or frame.
+    assert!(Page::containing_address(VirtualAddress::new_canonical(0x1fff)).number() == 1);
+    assert!(Page::containing_address(VirtualAddress::new_canonical(0x2000)).number() == 2);
+    assert!(Frame::containing_address(PhysicalAddress::new_canonical(0xfff)).number() == 0);
+    assert!(
+        Page::containing_address(VirtualAddress::new_canonical(0xffff_ff80_0000_0000)).p4_index()
+            == 511
+    );
+};
+
 pub(crate) fn set_up_arch_specific_mappings(context: &mut RuntimeContext) {
     let p4_frame = paging::PhysFrame::from_start_address(x86_64::PhysAddr::new(
         context.mapper.inner.level_4_table() as *const _ as u64,
     ))
     .expect("invalid p4 frame");
 
-    #[allow(clippy::inconsistent_digit_grouping)]
-    let p4_index = x86_64::VirtAddr::new(0o177777_776_000_000_000_0000).p4_index();
-    let entry = &mut context.mapper.inner.level_4_table()[p4_index];
+    let entry = &mut context.mapper.inner.level_4_table()[RECURSIVE_INDEX];
     entry.set_frame(
         p4_frame,
         paging::PageTableFlags::PRESENT | paging::PageTableFlags::WRITABLE,
@@ -87,6 +115,18 @@ impl PteFlags {
     }
 }
 
+impl PteFlags {
+    /// Returns whether pages mapped with these flags are writable.
+    pub(crate) fn is_writable(self) -> bool {
+        self.0 & paging::PageTableFlags::WRITABLE.bits() != 0
+    }
+
+    /// Returns whether pages mapped with these flags are executable.
+    pub(crate) fn is_executable(self) -> bool {
+        self.0 & paging::PageTableFlags::NO_EXECUTE.bits() == 0
+    }
+}
+
 impl From<PteFlags> for paging::PageTableFlags {
     fn from(flags: PteFlags) -> Self {
         paging::PageTableFlags::from_bits_truncate(flags.0)
@@ -133,12 +173,25 @@ pub(crate) struct PageAllocator {
     level_4_entries: [bool; 512],
 }
 
+/// Returns the address of the first page spanned by the level 4 entry at
+/// `index`.
+fn level_4_address(index: usize) -> VirtualAddress {
+    // This is technically a 512 GiB page.
+    paging::Page::from_page_table_indices_1gib(
+        PageTableIndex::new_truncate(index as u16),
+        PageTableIndex::new(0),
+    )
+    .start_address()
+    .into()
+}
+
 impl PageAllocator {
     pub(crate) fn new() -> Self {
         let mut page_allocator = Self {
             level_4_entries: [false; 512],
         };
         page_allocator.level_4_entries[0] = true;
+        page_allocator.level_4_entries[RECURSIVE_INDEX] = true;
 
         page_allocator
     }
@@ -172,13 +225,12 @@ impl PageAllocator {
         const LEVEL_4_SIZE: usize = 4096 * 512 * 512 * 512;
         let num_level_4_entries = (len + (LEVEL_4_SIZE - 1)) / LEVEL_4_SIZE;
 
-        // This is technically a 512 GiB page.
-        paging::Page::from_page_table_indices_1gib(
-            self.get_free_entries(num_level_4_entries as u64),
-            PageTableIndex::new(0),
-        )
-        .start_address()
-        .into()
+        level_4_address(self.get_free_entries(num_level_4_entries as u64).into())
+    }
+
+    /// Returns the pages spanned by each run of allocated level 4 entries.
+    pub(crate) fn used_ranges(&self) -> UsedRanges<'_> {
+        UsedRanges::new(&self.level_4_entries, level_4_address, 256)
     }
 
     pub(crate) fn mark_segment_as_used(&mut self, segment: &ProgramHeader) {
@@ -188,7 +240,27 @@ impl PageAllocator {
         let start_page = Page::containing_address(start);
         let end_page_inclusive = Page::containing_address(end_inclusive);
 
-        for p4_index in start_page.p4_index()..=end_page_inclusive.p4_index() {
+        self.mark_pages_as_used(&PageRange::new(start_page, end_page_inclusive));
+    }
+
+    /// Returns whether none of the level 4 entries spanned by `pages` have been
+    /// allocated.
+    pub(crate) fn are_pages_free(&self, pages: &PageRange) -> bool {
+        (pages.start().p4_index()..=pages.end().p4_index())
+            .all(|index| !self.level_4_entries[index])
+    }
+
+    /// Prevents the level 4 entries spanned by `pages` from being allocated.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pages` overlaps the recursive page table mapping.
+    pub(crate) fn mark_pages_as_used(&mut self, pages: &PageRange) {
+        for p4_index in pages.start().p4_index()..=pages.end().p4_index() {
+            assert_ne!(
+                p4_index, RECURSIVE_INDEX,
+                "{pages:?} overlaps the recursive page table mapping"
+            );
             self.level_4_entries[p4_index] = true;
         }
     }
@@ -211,6 +283,20 @@ where
     }
 }
 
+/// Converts an error from the `x86_64` crate's mapper into a [`MapError`].
+fn map_error<S>(error: paging::mapper::MapToError<S>) -> MapError
+where
+    S: paging::PageSize,
+{
+    match error {
+        paging::mapper::MapToError::PageAlreadyMapped(frame) => {
+            MapError::PageAlreadyMapped(Frame::containing_address(frame.start_address().into()))
+        }
+        paging::mapper::MapToError::FrameAllocationFailed => MapError::FrameAllocationFailed,
+        paging::mapper::MapToError::ParentEntryHugePage => MapError::ParentEntryHugePage,
+    }
+}
+
 pub(crate) struct Mapper {
     inner: OffsetPageTable<'static>,
 }
@@ -270,6 +356,22 @@ impl Mapper {
         }
     }
 
+    /// Creates a mapper for the existing page table in `frame`.
+    ///
+    /// # Safety
+    ///
+    /// `frame` must contain a level 4 page table, which mustn't be accessed
+    /// through another mapper while the returned mapper is in use.
+    pub(crate) unsafe fn from_frame(frame: Frame) -> Self {
+        // SAFETY: Physical memory is identity-mapped, and the caller guarantees
+        // the frame contains a page table that isn't otherwise accessed.
+        let level_4_table = unsafe { &mut *(frame.start_address().value() as *mut PageTable) };
+        Self {
+            // SAFETY: The physical offset is zero.
+            inner: unsafe { OffsetPageTable::new(level_4_table, x86_64::VirtAddr::zero()) },
+        }
+    }
+
     // TODO: This should take a shared reference to self.
     pub(crate) fn frame(&mut self) -> Frame {
         Frame::containing_address(PhysicalAddress::new_canonical(self.inner.level_4_table()
@@ -283,7 +385,8 @@ impl Mapper {
         frame: Frame,
         flags: PteFlags,
         frame_allocator: &mut T,
-    ) where
+    ) -> Result<(), MapError>
+    where
         T: FrameAllocator,
     {
         // SAFETY: 🤷
@@ -298,8 +401,106 @@ impl Mapper {
                 },
             )
         }
-        .expect("failed to map page to frame")
-        // TODO: Do we need to flush everytime?
+        .map_err(map_error)?
+        // This is only needed if the page table is active, as it is when the
+        // bootloader maps memory for itself, and is cheap otherwise.
+        .flush();
+        Ok(())
+    }
+
+    /// Maps the huge page starting at `page` to the huge frame starting at
+    /// `frame`.
+    ///
+    /// Both `page` and `frame` must be aligned to [`HUGE_PAGE_SIZE`].
+    ///
+    /// [`HUGE_PAGE_SIZE`]: crate::memory::HUGE_PAGE_SIZE
+    pub(crate) fn map_huge<T>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: PteFlags,
+        frame_allocator: &mut T,
+    ) -> Result<(), MapError>
+    where
+        T: FrameAllocator,
+    {
+        let page = paging::Page::<paging::Size2MiB>::from_start_address(x86_64::VirtAddr::new(
+            page.start_address().value() as u64,
+        ))
+        .expect("huge page is not aligned");
+        let frame = paging::PhysFrame::<paging::Size2MiB>::from_start_address(
+            x86_64::PhysAddr::new(frame.start_address().value() as u64),
+        )
+        .expect("huge frame is not aligned");
+
+        // SAFETY: 🤷
+        unsafe {
+            paging::Mapper::<paging::Size2MiB>::map_to(
+                &mut self.inner,
+                page,
+                frame,
+                flags.into(),
+                &mut FrameAllocatorWrapper {
+                    inner: frame_allocator,
+                },
+            )
+        }
+        .map_err(map_error)?
+        .flush();
+        Ok(())
+    }
+
+    /// Replaces the flags of the existing mapping of `page`, leaving the frame
+    /// it's mapped to unchanged.
+    ///
+    /// Only 4 KiB pages can be updated, so an error is returned if `page` isn't
+    /// mapped, or is part of a huge page.
+    pub(crate) fn update_flags(&mut self, page: Page, flags: PteFlags) -> Result<(), MapError> {
+        let page: paging::Page<paging::Size4KiB> = page.into();
+        let frame = paging::Mapper::translate_page(&self.inner, page).ok();
+
+        // SAFETY: Only the flags change, so the page still refers to the same memory.
+        unsafe {
+            paging::Mapper::<paging::Size4KiB>::update_flags(&mut self.inner, page, flags.into())
+        }
+        .map_err(|error| match error {
+            paging::mapper::FlagUpdateError::PageNotMapped => MapError::PageNotMapped,
+            paging::mapper::FlagUpdateError::ParentEntryHugePage => MapError::ParentEntryHugePage,
+        })?
         .flush();
+
+        debug_assert_eq!(
+            paging::Mapper::translate_page(&self.inner, page).ok(),
+            frame,
+            "updating the flags of {page:?} changed its frame"
+        );
+        Ok(())
+    }
+
+    /// Returns the physical address that `address` is mapped to, and the
+    /// flags it's mapped with, if it's mapped by a page of any size.
+    pub(crate) fn translate(&self, address: VirtualAddress) -> Option<(PhysicalAddress, PteFlags)> {
+        match paging::Translate::translate(
+            &self.inner,
+            x86_64::VirtAddr::new(address.value() as u64),
+        ) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((
+                PhysicalAddress::from(frame.start_address()) + offset as usize,
+                PteFlags(flags.bits()),
+            )),
+            TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+        }
+    }
+
+    /// Returns the frame that `page` is mapped to, if it's mapped by a 4 KiB
+    /// page.
+    pub(crate) fn translate_page(&self, page: Page) -> Option<Frame> {
+        paging::Mapper::<paging::Size4KiB>::translate_page(&self.inner, page.into())
+            .ok()
+            .map(|frame| Frame::containing_address(frame.start_address().into()))
     }
 }
@@ -1,7 +1,12 @@
 use crate::KernelContext;
 use core::arch::asm;
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags},
+    model_specific::{Efer, EferFlags},
+};
 
 pub(crate) mod memory;
+pub(crate) mod serial;
 
 // The function needs to take ownership of the context so that it remains valid
 // when we switch page tables.
@@ -17,11 +22,30 @@ pub(crate) unsafe fn jump_to_kernel(context: KernelContext) -> ! {
             in(reg) context.stack_top.value(),
             in(reg) context.entry_point.value(),
             in("rdi") context.boot_info,
+            in("rsi") context.stack_top.value(),
+            in("rdx") context.physical_memory_offset,
             options(noreturn),
         );
     }
 }
 
+/// Makes instructions written to `bytes` visible to instruction fetches.
+///
+/// This is a no-op, as x86_64 keeps the instruction cache coherent.
+pub(crate) fn clean_and_invalidate(_bytes: &[u8]) {}
+
+/// Returns whether the no-execute bit in page table entries is enforced, which
+/// requires `EFER.NXE` to be set.
+pub(crate) fn is_no_execute_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+/// Returns whether read-only pages are write protected in ring 0, which
+/// requires `CR0.WP` to be set.
+pub(crate) fn is_write_protect_enabled() -> bool {
+    Cr0::read().contains(Cr0Flags::WRITE_PROTECT)
+}
+
 pub(crate) fn halt() -> ! {
     loop {
         // SAFETY: These instructions will stop the CPU.
@@ -0,0 +1,106 @@
+use crate::config::SerialConfig;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU16, Ordering},
+};
+use x86_64::instructions::port::Port;
+
+/// The I/O port base of the first serial port.
+const COM1: u16 = 0x3f8;
+
+/// The I/O port bases of the standard PC serial ports, COM1 to COM4.
+const STANDARD_BASES: [u16; 4] = [COM1, 0x2f8, 0x3e8, 0x2e8];
+
+/// The UART's input clock divided by 16, which is the baud rate with a
+/// divisor of 1.
+const MAX_BAUD_RATE: u32 = 115_200;
+
+/// The I/O port base of the serial port opened by [`SerialPort::new`].
+static BASE: AtomicU16 = AtomicU16::new(COM1);
+
+/// The divisor latch value programmed by [`SerialPort::new`].
+static DIVISOR: AtomicU16 = AtomicU16::new(1);
+
+/// Sets the serial port and baud rate used by [`SerialPort::new`].
+///
+/// # Panics
+///
+/// Panics if the base isn't that of COM1 to COM4, or the baud rate can't be
+/// produced by the UART's clock.
+pub(crate) fn configure(config: SerialConfig) {
+    let base = u16::try_from(config.base)
+        .ok()
+        .filter(|base| STANDARD_BASES.contains(base))
+        .unwrap_or_else(|| panic!("unsupported serial port base: {:#x}", config.base));
+    let divisor = u16::try_from(MAX_BAUD_RATE / config.baud_rate)
+        .ok()
+        .filter(|&divisor| divisor != 0 && MAX_BAUD_RATE % config.baud_rate == 0)
+        .unwrap_or_else(|| panic!("unsupported baud rate: {}", config.baud_rate));
+
+    BASE.store(base, Ordering::Relaxed);
+    DIVISOR.store(divisor, Ordering::Relaxed);
+}
+
+/// A 16550-compatible UART.
+pub(crate) struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// Initialises the configured serial port for 8N1, which is the first
+    /// serial port at 115200 baud unless [`configure`] was called.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the serial port isn't simultaneously accessed
+    /// elsewhere.
+    pub(crate) unsafe fn new() -> Self {
+        let mut port = Self {
+            base: BASE.load(Ordering::Relaxed),
+        };
+        let [divisor_low, divisor_high] = DIVISOR.load(Ordering::Relaxed).to_le_bytes();
+        // Disable interrupts.
+        port.write_register(1, 0x00);
+        // Enable the divisor latch and set the divisor.
+        port.write_register(3, 0x80);
+        port.write_register(0, divisor_low);
+        port.write_register(1, divisor_high);
+        // Disable the divisor latch and use 8 data bits, no parity, and one stop bit.
+        port.write_register(3, 0x03);
+        // Enable and clear the FIFOs.
+        port.write_register(2, 0xc7);
+        // Assert DTR and RTS.
+        port.write_register(4, 0x03);
+        port
+    }
+
+    fn write_register(&mut self, offset: u16, value: u8) {
+        // SAFETY: The port is a register of the UART.
+        unsafe { Port::new(self.base + offset).write(value) };
+    }
+
+    fn read_register(&mut self, offset: u16) -> u8 {
+        // SAFETY: The port is a register of the UART.
+        unsafe { Port::new(self.base + offset).read() }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        // Wait for the transmit holding register to be empty.
+        while self.read_register(5) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        self.write_register(0, byte);
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
@@ -1,98 +1,346 @@
 use crate::{
-    arch::memory::Mapper,
+    arch,
+    config::Config,
     context::RuntimeContext,
-    memory::{FrameAllocator, Page, PageRange, PteFlags},
+    kernel::Kernel,
+    memory::{PhysicalAddress, VirtualAddress, PAGE_SIZE},
+    memtest::{self, BadFrames},
+    modules::LoadedModules,
+    util::{assume_all_written, assume_written, calculate_pages},
 };
-use core::{alloc::Layout, mem::MaybeUninit, slice};
-use uefi_bootloader_api::{BootInformation, ElfSection, FrameBuffer, MemoryRegion, Module};
+use core::{alloc::Layout, any::type_name, marker::PhantomData, mem::MaybeUninit, slice};
+use uefi_bootloader_api::{
+    BootInformation, CpuCount, ElfSection, FrameBuffer, GopMode, MemoryRegion, Module, Parameter,
+    PhysicalRange, PreMappedRegion, Rsdp, SectionContents, Stack, SymbolTable, UefiMemoryRegion,
+    VirtualRange,
+};
+
+/// The version of the bootloader, and the commit it was built from if known.
+const VERSION: &str = env!("UEFI_BOOTLOADER_VERSION");
 
 impl RuntimeContext {
+    // TODO: Group the arguments.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn create_boot_info(
         mut self,
         frame_buffer: Option<FrameBuffer>,
-        rsdp_address: Option<usize>,
-        modules: &'static [Module],
-        elf_sections: &'static [ElfSection],
+        rsdp: Option<Rsdp>,
+        cpu_count: Option<CpuCount>,
+        modules: LoadedModules,
+        gop_modes: &'static [GopMode],
+        kernel: Kernel,
+        stack: Stack,
+        config: &Config,
     ) -> &'static BootInformation {
-        let boot_info_layout = Layout::new::<BootInformation>();
-
-        let memory_regions_count = self.frame_allocator.len();
-        let memory_regions_layout = Layout::array::<MemoryRegion>(memory_regions_count)
-            .expect("failed to create memory regions layout");
-        let (combined, memory_regions_offset) = boot_info_layout
-            .extend(memory_regions_layout)
-            .expect("failed to extend boot info layout with memory regions");
-
-        let modules_layout =
-            Layout::array::<Module>(modules.len()).expect("failed to create modules layout");
-        let (combined, modules_offset) = combined
-            .extend(modules_layout)
-            .expect("failed to extend boot info layout with modules");
-
-        let elf_sections_layout = Layout::array::<ElfSection>(elf_sections.len())
-            .expect("failed to create elf sections layout");
-        let (combined, elf_sections_offset) = combined
-            .extend(elf_sections_layout)
-            .expect("failed to extend boot info layout with elf sections");
-
-        let boot_info_address = self.page_allocator.get_free_address(combined.size());
-
-        let pages = PageRange::new(
-            Page::containing_address(boot_info_address),
-            Page::containing_address(boot_info_address + combined.size() - 1),
-        );
-
-        let mut bootloader_page_tables = Mapper::current(&mut self.frame_allocator);
-        let flags = PteFlags::new().present(true).writable(true);
-
-        for page in pages {
-            let frame = self
-                .frame_allocator
-                .allocate_frame()
-                .expect("failed to allocate boot info frame");
-            self.mapper
-                .map(page, frame, flags, &mut self.frame_allocator);
-            bootloader_page_tables.map(page, frame, flags, &mut self.frame_allocator);
-        }
+        let elf_sections = kernel.elf_sections;
+        let symbols = kernel.symbols;
+        let section_contents = kernel.section_contents;
+        let build_id = kernel.build_id;
+        let load_bias = kernel.load_bias;
+        let parameters = config.parameters();
 
-        let memory_map_regions_address = boot_info_address + memory_regions_offset;
-        let modules_address = boot_info_address + modules_offset;
-        let elf_sections_address = boot_info_address + elf_sections_offset;
+        let mut builder = BootInfoBuilder::new();
 
-        let uninit_boot_info: &'static mut MaybeUninit<BootInformation> =
-            // SAFETY: We allocated it.
-            unsafe { &mut *(boot_info_address.value() as *mut _) };
-        // SAFETY: We allocated it.
-        let uninit_memory_regions: &'static mut [MaybeUninit<MemoryRegion>] = unsafe {
-            slice::from_raw_parts_mut(
-                memory_map_regions_address.value() as *mut _,
-                memory_regions_count,
-            )
+        let memory_regions_array = builder.add_array::<MemoryRegion>(self.frame_allocator.len());
+        let modules_array = builder.add_array::<Module>(modules.modules.len());
+        let elf_sections_array = builder.add_array::<ElfSection>(elf_sections.len());
+        let gop_modes_array = builder.add_array::<GopMode>(gop_modes.len());
+
+        let (symtab, strtab) = match &symbols {
+            Some(symbols) => (symbols.symtab, symbols.strtab),
+            None => (&[][..], &[][..]),
         };
-        let uninit_modules: &'static mut [MaybeUninit<Module>] =
-            // SAFETY: We allocated it.
-            unsafe { slice::from_raw_parts_mut(modules_address.value() as *mut _, modules.len()) };
-        // SAFETY: We allocated it.
-        let uninit_elf_sections: &'static mut [MaybeUninit<ElfSection>] = unsafe {
-            slice::from_raw_parts_mut(elf_sections_address.value() as *mut _, elf_sections.len())
+        let symtab_array = builder.add_array::<u8>(symtab.len());
+        let strtab_array = builder.add_array::<u8>(strtab.len());
+
+        let parameters_array = builder.add_array::<Parameter>(parameters.len());
+        let parameter_strings_len = parameters
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        let parameter_strings_array = builder.add_array::<u8>(parameter_strings_len);
+        let command_line_array = builder.add_array::<u8>(config.command_line.len());
+        let version_array = builder.add_array::<u8>(VERSION.len());
+        let pre_mapped_regions_array =
+            builder.add_array::<PreMappedRegion>(self.num_pre_mapped_regions);
+        let section_contents_array = builder.add_array::<SectionContents>(section_contents.len());
+        let section_bytes_len = section_contents
+            .iter()
+            .map(|contents| contents.bytes.len())
+            .sum();
+        let section_bytes_array = builder.add_array::<u8>(section_bytes_len);
+
+        let uefi_memory_regions_count = if config.debug {
+            self.frame_allocator.descriptors().count()
+        } else {
+            0
         };
+        let uefi_memory_regions_array =
+            builder.add_array::<UefiMemoryRegion>(uefi_memory_regions_count);
+        // Allocating the boot info adds at most one range, as it may instead extend
+        // an existing one.
+        let used_virtual_ranges_array =
+            builder.add_array::<VirtualRange>(self.page_allocator.used_ranges().count() + 1);
+
+        let allocation = builder.allocate(&mut self, config.boot_info_address);
+
+        let uninit_memory_regions = allocation.array(memory_regions_array);
+        let uninit_modules = allocation.array(modules_array);
+        let uninit_elf_sections = allocation.array(elf_sections_array);
+        let uninit_gop_modes = allocation.array(gop_modes_array);
+        let uninit_symtab = allocation.array(symtab_array);
+        let uninit_strtab = allocation.array(strtab_array);
+        let uninit_parameters = allocation.array(parameters_array);
+        let mut uninit_parameter_strings = allocation.array(parameter_strings_array);
+        let uninit_command_line = allocation.array(command_line_array);
+        let uninit_version = allocation.array(version_array);
+        let uninit_pre_mapped_regions = allocation.array(pre_mapped_regions_array);
+        let uninit_section_contents = allocation.array(section_contents_array);
+        let mut uninit_section_bytes = allocation.array(section_bytes_array);
+        let uninit_uefi_memory_regions = allocation.array(uefi_memory_regions_array);
+        let uninit_used_virtual_ranges = allocation.array(used_virtual_ranges_array);
+
+        let mut used_virtual_ranges_written = 0;
+        for (uninit_range, pages) in uninit_used_virtual_ranges
+            .iter_mut()
+            .zip(self.page_allocator.used_ranges())
+        {
+            uninit_range.write(VirtualRange {
+                start: pages.start_address().value(),
+                len: pages.size_in_bytes(),
+            });
+            used_virtual_ranges_written += 1;
+        }
+        // SAFETY: We initialised the first `used_virtual_ranges_written` ranges.
+        let used_virtual_ranges =
+            unsafe { assume_written(uninit_used_virtual_ranges, used_virtual_ranges_written) }
+                .into();
+
+        let mut uefi_memory_regions_written = 0;
+        for (uninit_region, descriptor) in uninit_uefi_memory_regions
+            .iter_mut()
+            .zip(self.frame_allocator.descriptors())
+        {
+            uninit_region.write(UefiMemoryRegion {
+                ty: descriptor.ty.0,
+                start: descriptor.phys_start as usize,
+                page_count: descriptor.page_count as usize,
+                attributes: descriptor.att.bits(),
+            });
+            uefi_memory_regions_written += 1;
+        }
+        // SAFETY: We initialised the first `uefi_memory_regions_written` regions.
+        let uefi_memory_regions =
+            unsafe { assume_all_written(uninit_uefi_memory_regions, uefi_memory_regions_written) }
+                .into();
+
+        let mut parameters_written = 0;
+        for (uninit_parameter, (key, value)) in uninit_parameters.iter_mut().zip(parameters) {
+            let (uninit_key, rest) =
+                core::mem::take(&mut uninit_parameter_strings).split_at_mut(key.len());
+            let (uninit_value, rest) = rest.split_at_mut(value.len());
+            uninit_parameter_strings = rest;
+
+            uninit_parameter.write(Parameter {
+                key: MaybeUninit::write_slice(uninit_key, key.as_bytes()).into(),
+                value: MaybeUninit::write_slice(uninit_value, value.as_bytes()).into(),
+            });
+            parameters_written += 1;
+        }
+        // SAFETY: We initialised the first `parameters_written` parameters.
+        let parameters =
+            unsafe { assume_all_written(uninit_parameters, parameters_written) }.into();
 
+        let command_line =
+            MaybeUninit::write_slice(uninit_command_line, config.command_line.as_bytes()).into();
+        let bootloader_version =
+            MaybeUninit::write_slice(uninit_version, VERSION.as_bytes()).into();
+        let pre_mapped_regions = MaybeUninit::write_slice(
+            uninit_pre_mapped_regions,
+            &self.pre_mapped_regions[..self.num_pre_mapped_regions],
+        )
+        .into();
+
+        let mut section_contents_written = 0;
+        for (uninit_contents, contents) in uninit_section_contents.iter_mut().zip(section_contents)
+        {
+            let (uninit_bytes, rest) =
+                core::mem::take(&mut uninit_section_bytes).split_at_mut(contents.bytes.len());
+            uninit_section_bytes = rest;
+
+            uninit_contents.write(SectionContents {
+                name: contents.name,
+                bytes: MaybeUninit::write_slice(uninit_bytes, contents.bytes).into(),
+            });
+            section_contents_written += 1;
+        }
+        // SAFETY: We initialised the first `section_contents_written` contents.
+        let section_contents =
+            unsafe { assume_all_written(uninit_section_contents, section_contents_written) }.into();
+
+        let frame_buffer_range = frame_buffer.map(|frame_buffer| {
+            let start = frame_buffer.physical / PAGE_SIZE * PAGE_SIZE;
+            let num_pages = calculate_pages(frame_buffer.physical - start + frame_buffer.info.size);
+            start..start + num_pages * PAGE_SIZE
+        });
+        // Nothing is allocated after the boot info, so any unallocated frame
+        // can be tested.
+        let bad_frames = if config.memtest {
+            memtest::run(&self.frame_allocator, config.memtest_percent)
+        } else {
+            BadFrames::new()
+        };
         let memory_regions = self
             .frame_allocator
-            .construct_memory_map(uninit_memory_regions)
+            .construct_memory_map(
+                uninit_memory_regions,
+                frame_buffer_range,
+                bad_frames.as_slice(),
+            )
             .into();
-        let modules = MaybeUninit::write_slice(uninit_modules, modules).into();
+        let modules_physical_range = modules.physical_range;
+        let modules = MaybeUninit::write_slice(uninit_modules, modules.modules).into();
         let elf_sections = MaybeUninit::write_slice(uninit_elf_sections, elf_sections).into();
+        let gop_modes = MaybeUninit::write_slice(uninit_gop_modes, gop_modes).into();
+        let symbols = symbols.map(|_| SymbolTable {
+            symtab: MaybeUninit::write_slice(uninit_symtab, symtab).into(),
+            strtab: MaybeUninit::write_slice(uninit_strtab, strtab).into(),
+        });
+        let kernel_physical_range = PhysicalRange {
+            start: self.kernel_frames.start_address().value(),
+            len: self.kernel_frames.size_in_bytes(),
+        };
 
-        uninit_boot_info.write({
-            BootInformation {
-                size: combined.size(),
-                frame_buffer,
-                rsdp_address,
-                memory_regions,
-                modules,
-                elf_sections,
-            }
+        allocation.finish(BootInformation {
+            size: allocation.size(),
+            physical_address: allocation.physical().value(),
+            features: config.features(frame_buffer.is_some()),
+            frame_buffer,
+            rsdp,
+            system_table_address: self.system_table_address,
+            cpu_count,
+            memory_regions,
+            modules,
+            modules_physical_range,
+            elf_sections,
+            gop_modes,
+            kernel_physical_range,
+            kernel_load_bias: load_bias as usize,
+            stack,
+            early_heap: self.early_heap,
+            firmware: self.firmware,
+            identity_mapped: self.identity_mapped,
+            kernel_identity_mapped: config.identity_map_kernel,
+            trampoline: self.trampoline.map(|frame| frame.start_address().value()),
+            nx_enabled: arch::is_no_execute_enabled(),
+            write_protect_enabled: arch::is_write_protect_enabled(),
+            parameters,
+            command_line,
+            bootloader_version,
+            debug: config.debug,
+            uefi_memory_regions,
+            pre_mapped_regions,
+            used_virtual_ranges,
+            section_contents,
+            symbols,
+            build_id,
         })
     }
 }
+
+/// Lays out the boot information and the arrays it points to in a single
+/// physically contiguous allocation, which is mapped in both the bootloader's
+/// and the kernel's page tables.
+///
+/// Every array is added with [`add_array`](Self::add_array) before the memory
+/// is allocated, so the layout is only computed in one place.
+struct BootInfoBuilder {
+    layout: Layout,
+}
+
+/// The position of an array of `T` in the boot information allocation.
+///
+/// This isn't `Copy`, so each array can only be retrieved once.
+struct Array<T> {
+    offset: usize,
+    len: usize,
+    _type: PhantomData<T>,
+}
+
+/// The memory allocated for the boot information by a [`BootInfoBuilder`].
+struct BootInfoAllocation {
+    address: VirtualAddress,
+    physical: PhysicalAddress,
+    size: usize,
+}
+
+impl BootInfoBuilder {
+    fn new() -> Self {
+        Self {
+            layout: Layout::new::<BootInformation>(),
+        }
+    }
+
+    /// Reserves space for `len` elements of type `T` after the previously
+    /// added arrays.
+    fn add_array<T>(&mut self, len: usize) -> Array<T> {
+        let (layout, offset) = Layout::array::<T>(len)
+            .and_then(|array| self.layout.extend(array))
+            .unwrap_or_else(|_| {
+                panic!(
+                    "failed to extend boot info layout with {len} {}",
+                    type_name::<T>()
+                )
+            });
+        self.layout = layout;
+        Array {
+            offset,
+            len,
+            _type: PhantomData,
+        }
+    }
+
+    /// Allocates the boot information, placing it at `hint` if possible.
+    fn allocate(
+        self,
+        context: &mut RuntimeContext,
+        hint: Option<VirtualAddress>,
+    ) -> BootInfoAllocation {
+        let size = self.layout.size();
+        let (_, address, physical) = context.allocate_mapped_at::<u8>(size, hint);
+        BootInfoAllocation {
+            address,
+            physical,
+            size,
+        }
+    }
+}
+
+impl BootInfoAllocation {
+    /// Returns the size of the allocation in bytes.
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the physical address of the start of the allocation.
+    fn physical(&self) -> PhysicalAddress {
+        self.physical
+    }
+
+    /// Returns the uninitialised memory reserved for `array`.
+    fn array<T>(&self, array: Array<T>) -> &'static mut [MaybeUninit<T>] {
+        // SAFETY: The array lies within the allocation, which is mapped, and it's
+        // only returned once as `Array` isn't `Copy`.
+        unsafe {
+            slice::from_raw_parts_mut((self.address + array.offset).value() as *mut _, array.len)
+        }
+    }
+
+    /// Writes `boot_info` to the start of the allocation.
+    fn finish(self, boot_info: BootInformation) -> &'static BootInformation {
+        let uninit_boot_info: &'static mut MaybeUninit<BootInformation> =
+            // SAFETY: The allocation starts with space for the boot information.
+            unsafe { &mut *(self.address.value() as *mut _) };
+        uninit_boot_info.write(boot_info)
+    }
+}
@@ -0,0 +1,124 @@
+//! Decoding of HTTP response bodies sent with the `chunked` transfer coding.
+//!
+//! The firmware's HTTP protocol hands over the body as it was sent, so the
+//! chunk framing has to be removed by the bootloader.
+//!
+//! This only depends on `core`, so that it can also be built for the host by
+//! the fuzz targets in `fuzz/`.
+
+use core::ops::Range;
+
+/// The reason a chunked body was rejected.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChunkedError(pub(crate) &'static str);
+
+/// The next part of a chunked body.
+enum Part {
+    /// A chunk whose data is at `data`, followed by the next part at `next`.
+    Chunk { data: Range<usize>, next: usize },
+    /// The last chunk and the trailer, which end the body.
+    End,
+    /// More bytes are needed to parse the part.
+    Incomplete,
+}
+
+/// Returns the length of the decoded body if `bytes` is a complete chunked
+/// body, or `None` if more bytes are needed.
+pub(crate) fn decoded_len(bytes: &[u8]) -> Result<Option<usize>, ChunkedError> {
+    let mut len = 0;
+    let mut start = 0;
+    loop {
+        match parse_part(bytes, start)? {
+            Part::Chunk { data, next } => {
+                len += data.len();
+                start = next;
+            }
+            Part::End => return Ok(Some(len)),
+            Part::Incomplete => return Ok(None),
+        }
+    }
+}
+
+/// Removes the chunk framing from the chunked body `bytes`, moving the decoded
+/// body to the start of `bytes` and returning its length.
+///
+/// `bytes` must be a complete chunked body, which [`decoded_len`] checks.
+pub(crate) fn decode_in_place(bytes: &mut [u8]) -> Result<usize, ChunkedError> {
+    let mut len = 0;
+    let mut start = 0;
+    loop {
+        match parse_part(bytes, start)? {
+            Part::Chunk { data, next } => {
+                // Each chunk is preceded by its size line, so the decoded body
+                // never overwrites framing that is yet to be parsed.
+                let data_len = data.len();
+                bytes.copy_within(data, len);
+                len += data_len;
+                start = next;
+            }
+            Part::End => return Ok(len),
+            Part::Incomplete => return Err(ChunkedError("body is incomplete")),
+        }
+    }
+}
+
+/// Parses the part of a chunked body starting at `start`.
+fn parse_part(bytes: &[u8], start: usize) -> Result<Part, ChunkedError> {
+    let Some((size_line, data_start)) = line(bytes, start) else {
+        return Ok(Part::Incomplete);
+    };
+    // The size may be followed by chunk extensions, which are ignored.
+    let size = size_line.split(|byte| *byte == b';').next().unwrap_or(&[]);
+    let size = trim(size);
+    if size.is_empty() || size.len() > 16 {
+        return Err(ChunkedError("invalid chunk size"));
+    }
+    let size = core::str::from_utf8(size)
+        .ok()
+        .and_then(|size| u64::from_str_radix(size, 16).ok())
+        .and_then(|size| usize::try_from(size).ok())
+        .ok_or(ChunkedError("invalid chunk size"))?;
+
+    if size == 0 {
+        // Skip the trailer fields, which end with an empty line.
+        let mut start = data_start;
+        loop {
+            match line(bytes, start) {
+                Some((field, _)) if field.is_empty() => return Ok(Part::End),
+                Some((_, next)) => start = next,
+                None => return Ok(Part::Incomplete),
+            }
+        }
+    }
+
+    let data_end = data_start
+        .checked_add(size)
+        .ok_or(ChunkedError("invalid chunk size"))?;
+    match bytes.get(data_end..data_end.saturating_add(2)) {
+        Some(b"\r\n") => Ok(Part::Chunk {
+            data: data_start..data_end,
+            next: data_end + 2,
+        }),
+        Some(_) => Err(ChunkedError("chunk is not followed by CRLF")),
+        None => Ok(Part::Incomplete),
+    }
+}
+
+/// Returns the line starting at `start`, without its CRLF, and the offset of
+/// the next line, or `None` if the line is incomplete.
+fn line(bytes: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let rest = bytes.get(start..)?;
+    let len = rest.windows(2).position(|window| window == b"\r\n")?;
+    Some((&rest[..len], start + len + 2))
+}
+
+/// Removes the spaces and tabs around `bytes`.
+fn trim(mut bytes: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = bytes {
+        bytes = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = bytes {
+        bytes = rest;
+    }
+    bytes
+}
@@ -0,0 +1,676 @@
+use crate::{
+    logger::LogFormat,
+    memory::{
+        PhysicalAddress, VirtualAddress, KERNEL_MEMORY, MODULE_MEMORY, PAGE_SIZE,
+        RECLAIMABLE_MEMORY,
+    },
+    BootContext,
+};
+use noto_sans_mono_bitmap::RasterHeight;
+use uefi::{
+    prelude::cstr16,
+    proto::media::file::{File, FileAttribute, FileMode, RegularFile},
+    table::boot::MemoryType,
+    CStr16,
+};
+use uefi_bootloader_api::{
+    Features, MappingFlags, PixelFormat, PreMappedRegion, GUARD_PAGE_POISON, MAX_COMMAND_LINE_SIZE,
+    MAX_PARAMETERS, MAX_PARAMETERS_SIZE, MAX_PRE_MAPPED_REGIONS,
+};
+
+const CONFIG_NAME: &CStr16 = cstr16!("bootloader.cfg");
+
+/// The default page table budget for identity-mapping RAM, which is enough for
+/// 16 GiB.
+const DEFAULT_IDENTITY_MAP_MAX_TABLES: usize = 8192 + 16 + 1;
+
+/// The default percentage of usable frames tested by the memory test.
+const DEFAULT_MEMTEST_PERCENT: usize = 10;
+
+/// The default number of UEFI memory map entries to log.
+const DEFAULT_MEMORY_MAP_DUMP: usize = 8;
+
+/// The serial port configured by the `serial` option.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SerialConfig {
+    /// The base address of the UART, which is an I/O port on x86_64, and the
+    /// address of a PL011's registers on aarch64.
+    pub(crate) base: u64,
+    /// The baud rate of the UART.
+    ///
+    /// This is ignored on aarch64, where the UART is used as the firmware
+    /// configured it.
+    pub(crate) baud_rate: u32,
+}
+
+/// Bootloader configuration read from `bootloader.cfg` on the ESP.
+///
+/// The file consists of `key=value` lines. Blank lines and lines starting with
+/// `#` are ignored, as is whitespace surrounding keys and values.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Config {
+    /// The height of the framebuffer console font in pixels.
+    pub(crate) font_size: RasterHeight,
+    /// The path of the kernel, relative to the root of the partition it is
+    /// loaded from.
+    ///
+    /// Components may be separated by `/` or `\`.
+    pub(crate) kernel_path: &'static str,
+    /// The path of the kernel to boot if the kernel at
+    /// [`kernel_path`](Self::kernel_path) is missing or invalid.
+    pub(crate) kernel_fallback: Option<&'static str>,
+    /// The path of the directory containing the modules, relative to the root
+    /// of the partition they are loaded from.
+    ///
+    /// Components may be separated by `/` or `\`.
+    pub(crate) modules_path: &'static str,
+    /// The path of the `newc` cpio archive containing the modules, relative to
+    /// the root of the partition it is loaded from.
+    ///
+    /// Loading one archive is faster than loading many files. If the archive
+    /// doesn't exist, the modules are loaded from
+    /// [`modules_path`](Self::modules_path).
+    pub(crate) modules_archive: &'static str,
+    /// The `http://` URL of the directory to download the kernel and module
+    /// archive from, instead of loading them from a partition.
+    ///
+    /// The kernel and archive paths are appended to the URL. Modules can only
+    /// be downloaded as an archive, as HTTP has no directory listings.
+    pub(crate) http_url: Option<&'static str>,
+    /// Whether to pass the kernel's symbol table to the kernel.
+    pub(crate) symbols: bool,
+    /// Whether to boot a debug kernel, which implies `symbols`, passes the raw
+    /// UEFI memory map to the kernel, and enables trace logging.
+    pub(crate) debug: bool,
+    /// The GPT unique partition GUID of the partition to load the kernel and
+    /// modules from, in its on-disk byte order.
+    ///
+    /// If this is `None`, or no such partition exists, the boot partition is
+    /// used.
+    pub(crate) partition: Option<[u8; 16]>,
+    /// The virtual address at which to load relocatable kernels.
+    ///
+    /// This is ignored for kernels that aren't position independent.
+    pub(crate) kernel_base: Option<u64>,
+    /// Whether to load relocatable kernels at a random base in the last 512 GiB
+    /// of the address space, using the firmware's random number generator.
+    ///
+    /// This overrides [`kernel_base`](Self::kernel_base).
+    pub(crate) kaslr: bool,
+    /// The serial port used for logging and panic dumps.
+    ///
+    /// If this is `None`, COM1 is used at 115200 baud on x86_64. On aarch64,
+    /// the location of the UART isn't known otherwise, so serial output is
+    /// discarded and options that rely on it are rejected.
+    pub(crate) serial: Option<SerialConfig>,
+    /// Whether to dump recent log output over serial if the bootloader
+    /// panics.
+    pub(crate) panic_dump: bool,
+    /// The format of the log output written to serial when the framebuffer is
+    /// disabled, which is set with `log_format=text` or `log_format=json`.
+    pub(crate) log_format: LogFormat,
+    /// Whether to identity-map all RAM in the kernel's page table, like the
+    /// firmware does.
+    pub(crate) identity_map: bool,
+    /// The maximum number of page table frames that identity-mapping RAM may
+    /// use.
+    ///
+    /// The bootloader refuses to boot if the identity mapping could exceed
+    /// this, which would otherwise exhaust memory on machines with a lot of
+    /// RAM.
+    pub(crate) identity_map_max_tables: usize,
+    /// Whether to also map the kernel's segments at their physical addresses
+    /// in the kernel's page table, for kernels that run code there before
+    /// jumping to their link address.
+    pub(crate) identity_map_kernel: bool,
+    /// Whether to map read-only kernel segments with 2 MiB pages where
+    /// possible.
+    ///
+    /// This reduces TLB pressure, but the kernel must be able to handle huge
+    /// pages in the page table it inherits.
+    pub(crate) huge_pages: bool,
+    /// Whether to fail the boot if any module fails to load, rather than
+    /// skipping it.
+    pub(crate) strict_modules: bool,
+    /// The UEFI memory type with which to allocate the module contents.
+    ///
+    /// The kernel sees module memory as a region of this type.
+    pub(crate) module_memory: MemoryType,
+    /// The alignment of each module's offset from the start of the module
+    /// contents, which is a power of two no larger than the page size.
+    ///
+    /// Modules share pages unless this is the page size.
+    pub(crate) module_align: usize,
+    /// The index of the display to use, in the order the firmware lists
+    /// graphics output handles.
+    ///
+    /// If this is `None`, the display with the highest resolution is used.
+    pub(crate) display: Option<usize>,
+    /// Whether to use a framebuffer at all.
+    ///
+    /// If this is `false`, the firmware's graphics output isn't touched, the
+    /// kernel isn't passed a framebuffer, and the bootloader logs over serial.
+    pub(crate) frame_buffer: bool,
+    /// The pixel format of the framebuffer, overriding the one reported by the
+    /// firmware.
+    ///
+    /// This is for firmware that reports RGB for a BGR framebuffer or vice
+    /// versa. It doesn't make bitmask or BLT-only framebuffers usable.
+    pub(crate) pixel_format: Option<PixelFormat>,
+    /// The number of UEFI memory map entries to log after exiting boot
+    /// services.
+    ///
+    /// The entries are only logged if trace logging is enabled.
+    pub(crate) memory_map_dump: usize,
+    /// The virtual address of the bottom of the kernel's stack.
+    ///
+    /// If this is `None`, or the address isn't available, the stack is placed
+    /// at the lowest free address. Allocations without a configured address
+    /// land at the same addresses on every boot of the same kernel.
+    pub(crate) stack_address: Option<VirtualAddress>,
+    /// Whether to map the guard page below the stack to a frame filled with
+    /// [`GUARD_PAGE_POISON`], rather than leaving it unmapped.
+    pub(crate) poison_guard_page: bool,
+    /// The size in bytes of the memory to reserve and map for the kernel's
+    /// early allocator, which is a non-zero multiple of the page size.
+    pub(crate) early_heap_size: Option<usize>,
+    /// The virtual address at which to map the framebuffer, if it isn't
+    /// disabled.
+    ///
+    /// This falls back like [`stack_address`](Self::stack_address).
+    pub(crate) frame_buffer_address: Option<VirtualAddress>,
+    /// The virtual address at which to map the boot information.
+    ///
+    /// This falls back like [`stack_address`](Self::stack_address).
+    pub(crate) boot_info_address: Option<VirtualAddress>,
+    /// The comma-separated names of the kernel sections whose contents are
+    /// passed to the kernel.
+    pub(crate) sections: &'static str,
+    /// Whether to test a sample of the unallocated usable frames for faulty
+    /// memory before jumping to the kernel, which is enabled with
+    /// `memtest=quick`.
+    ///
+    /// Frames that fail are reported to the kernel as unusable. This is off by
+    /// default, as it slows down booting.
+    pub(crate) memtest: bool,
+    /// The percentage of unallocated usable frames tested by the memory test.
+    pub(crate) memtest_percent: usize,
+    /// Whether to check the kernel's page table and halt, rather than jumping
+    /// to the kernel.
+    pub(crate) self_test: bool,
+    /// The kernel command line, which the load options the bootloader was
+    /// started with are appended to.
+    pub(crate) command_line: &'static str,
+    /// The physical memory regions requested by `map_phys` options, which are
+    /// mapped for the kernel.
+    ///
+    /// The virtual addresses aren't chosen until the regions are mapped.
+    pre_mapped_regions: [PreMappedRegion; MAX_PRE_MAPPED_REGIONS],
+    num_pre_mapped_regions: usize,
+    /// The entries that the bootloader doesn't recognise, which are passed to
+    /// the kernel.
+    parameters: [(&'static str, &'static str); MAX_PARAMETERS],
+    num_parameters: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            font_size: RasterHeight::Size16,
+            kernel_path: "kernel.elf",
+            kernel_fallback: None,
+            modules_path: "modules",
+            modules_archive: "modules.cpio",
+            http_url: None,
+            symbols: false,
+            debug: false,
+            partition: None,
+            kernel_base: None,
+            kaslr: false,
+            serial: None,
+            panic_dump: false,
+            log_format: LogFormat::Text,
+            identity_map: false,
+            identity_map_max_tables: DEFAULT_IDENTITY_MAP_MAX_TABLES,
+            identity_map_kernel: false,
+            huge_pages: false,
+            strict_modules: false,
+            module_memory: MODULE_MEMORY,
+            module_align: PAGE_SIZE,
+            display: None,
+            frame_buffer: true,
+            pixel_format: None,
+            memory_map_dump: DEFAULT_MEMORY_MAP_DUMP,
+            stack_address: None,
+            poison_guard_page: false,
+            early_heap_size: None,
+            frame_buffer_address: None,
+            boot_info_address: None,
+            sections: "",
+            memtest: false,
+            memtest_percent: DEFAULT_MEMTEST_PERCENT,
+            self_test: false,
+            command_line: "",
+            pre_mapped_regions: [PreMappedRegion {
+                phys: 0,
+                virt: 0,
+                len: 0,
+                flags: MappingFlags::empty(),
+            }; MAX_PRE_MAPPED_REGIONS],
+            num_pre_mapped_regions: 0,
+            parameters: [("", ""); MAX_PARAMETERS],
+            num_parameters: 0,
+        }
+    }
+}
+
+impl Config {
+    fn parse(text: &'static str) -> Self {
+        let mut config = Self::default();
+
+        for (key, value) in entries(text) {
+            match key {
+                "font_size" => {
+                    config.font_size = match value {
+                        "16" => RasterHeight::Size16,
+                        "20" => RasterHeight::Size20,
+                        "24" => RasterHeight::Size24,
+                        "32" => RasterHeight::Size32,
+                        _ => panic!("invalid value for {key}: {value}"),
+                    };
+                }
+                "kernel_path" => config.kernel_path = value,
+                "kernel_fallback" => config.kernel_fallback = Some(value),
+                "modules_path" => config.modules_path = value,
+                "modules_archive" => config.modules_archive = value,
+                "http_url" => config.http_url = Some(value),
+                "symbols" => config.symbols = parse_bool(key, value),
+                "debug" => config.debug = parse_bool(key, value),
+                "partition" => {
+                    config.partition = Some(
+                        parse_guid(value)
+                            .unwrap_or_else(|| panic!("invalid value for {key}: {value}")),
+                    );
+                }
+                "display" => {
+                    config.display = Some(
+                        value
+                            .parse()
+                            .unwrap_or_else(|_| panic!("invalid value for {key}: {value}")),
+                    );
+                }
+                "framebuffer" => config.frame_buffer = parse_bool(key, value),
+                "pixel_format" => {
+                    config.pixel_format = Some(match value {
+                        "rgb" => PixelFormat::Rgb,
+                        "bgr" => PixelFormat::Bgr,
+                        _ => panic!("invalid value for {key}: {value}"),
+                    });
+                }
+                "identity_map" => config.identity_map = parse_bool(key, value),
+                "identity_map_kernel" => config.identity_map_kernel = parse_bool(key, value),
+                "identity_map_max_tables" => {
+                    config.identity_map_max_tables = value
+                        .parse()
+                        .unwrap_or_else(|_| panic!("invalid value for {key}: {value}"));
+                }
+                "memory_map_dump" => {
+                    config.memory_map_dump = value
+                        .parse()
+                        .unwrap_or_else(|_| panic!("invalid value for {key}: {value}"));
+                }
+                "huge_pages" => config.huge_pages = parse_bool(key, value),
+                "panic_dump" => config.panic_dump = parse_bool(key, value),
+                "log_format" => {
+                    config.log_format = match value {
+                        "text" => LogFormat::Text,
+                        "json" => LogFormat::Json,
+                        _ => panic!("invalid value for {key}: {value}"),
+                    };
+                }
+                "serial" => config.serial = Some(parse_serial(key, value)),
+                "kernel_base" => config.kernel_base = Some(parse_address(key, value)),
+                "kaslr" => config.kaslr = parse_bool(key, value),
+                "stack_address" => config.stack_address = Some(parse_page_address(key, value)),
+                "poison_guard_page" => config.poison_guard_page = parse_bool(key, value),
+                "early_heap_size" => {
+                    config.early_heap_size = usize::try_from(parse_address(key, value))
+                        .ok()
+                        .filter(|&size| size != 0 && size % PAGE_SIZE == 0)
+                        .map(Some)
+                        .unwrap_or_else(|| panic!("invalid value for {key}: {value}"));
+                }
+                "frame_buffer_address" => {
+                    config.frame_buffer_address = Some(parse_page_address(key, value));
+                }
+                "boot_info_address" => {
+                    config.boot_info_address = Some(parse_page_address(key, value));
+                }
+                "strict_modules" => config.strict_modules = parse_bool(key, value),
+                "module_memory_type" => config.module_memory = parse_memory_type(key, value),
+                "module_align" => {
+                    config.module_align = value
+                        .parse()
+                        .ok()
+                        .filter(|align: &usize| align.is_power_of_two() && *align <= PAGE_SIZE)
+                        .unwrap_or_else(|| panic!("invalid value for {key}: {value}"));
+                }
+                "map_phys" => config.add_pre_mapped_region(parse_pre_mapped_region(key, value)),
+                "sections" => config.sections = value,
+                "selftest" => config.self_test = parse_bool(key, value),
+                "memtest" => {
+                    config.memtest = match value {
+                        "quick" => true,
+                        "off" => false,
+                        _ => panic!("invalid value for {key}: {value}"),
+                    };
+                }
+                "memtest_percent" => {
+                    config.memtest_percent = value
+                        .parse()
+                        .ok()
+                        .filter(|percent| (1..=100).contains(percent))
+                        .unwrap_or_else(|| panic!("invalid value for {key}: {value}"));
+                }
+                "command_line" => {
+                    assert!(
+                        value.len() <= MAX_COMMAND_LINE_SIZE,
+                        "command line is too long (the maximum is {MAX_COMMAND_LINE_SIZE} bytes)"
+                    );
+                    config.command_line = value;
+                }
+                _ => config.add_parameter(key, value),
+            }
+        }
+
+        if config.debug {
+            config.symbols = true;
+        }
+
+        if cfg!(target_arch = "aarch64") && config.serial.is_none() {
+            for (is_set, option) in [
+                (!config.frame_buffer, "framebuffer=false"),
+                (
+                    matches!(config.log_format, LogFormat::Json),
+                    "log_format=json",
+                ),
+                (config.panic_dump, "panic_dump"),
+                (config.self_test, "selftest"),
+            ] {
+                assert!(
+                    !is_set,
+                    "{option} requires the serial option on aarch64, as serial output is \
+                     discarded otherwise"
+                );
+            }
+        }
+
+        config
+    }
+
+    /// Returns the features provided to the kernel with this configuration.
+    pub(crate) fn features(&self, has_frame_buffer: bool) -> Features {
+        let mut features = Features::empty();
+        if self.identity_map {
+            features = features.union(Features::IDENTITY_MAP);
+        }
+        if self.symbols {
+            features = features.union(Features::SYMBOLS);
+        }
+        if self.debug {
+            features = features.union(Features::UEFI_MEMORY_MAP);
+        }
+        if has_frame_buffer {
+            features = features.union(Features::FRAME_BUFFER);
+        }
+        if self.identity_map_kernel {
+            features = features.union(Features::KERNEL_IDENTITY_MAP);
+        }
+        if self.poison_guard_page {
+            features = features.union(Features::POISONED_GUARD_PAGE);
+        }
+        if self.early_heap_size.is_some() {
+            features = features.union(Features::EARLY_HEAP);
+        }
+        features
+    }
+
+    /// Returns the physical memory regions requested by `map_phys` options.
+    pub(crate) fn pre_mapped_regions(&self) -> &[PreMappedRegion] {
+        &self.pre_mapped_regions[..self.num_pre_mapped_regions]
+    }
+
+    fn add_pre_mapped_region(&mut self, region: PreMappedRegion) {
+        assert!(
+            self.num_pre_mapped_regions < MAX_PRE_MAPPED_REGIONS,
+            "too many map_phys options (the maximum is {MAX_PRE_MAPPED_REGIONS})"
+        );
+        self.pre_mapped_regions[self.num_pre_mapped_regions] = region;
+        self.num_pre_mapped_regions += 1;
+    }
+
+    /// Returns the entries that the bootloader doesn't recognise.
+    pub(crate) fn parameters(&self) -> &[(&'static str, &'static str)] {
+        &self.parameters[..self.num_parameters]
+    }
+
+    fn add_parameter(&mut self, key: &'static str, value: &'static str) {
+        assert!(
+            self.num_parameters < MAX_PARAMETERS,
+            "too many parameters (the maximum is {MAX_PARAMETERS})"
+        );
+        let size: usize = self
+            .parameters()
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        assert!(
+            size + key.len() + value.len() <= MAX_PARAMETERS_SIZE,
+            "parameters are too long (the maximum is {MAX_PARAMETERS_SIZE} bytes)"
+        );
+
+        self.parameters[self.num_parameters] = (key, value);
+        self.num_parameters += 1;
+    }
+}
+
+/// Parses a GUID in its canonical `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` form,
+/// returning its bytes in the mixed-endian order used by GPT.
+fn parse_guid(value: &str) -> Option<[u8; 16]> {
+    let mut fields = value.split('-');
+    let mut next_field = |len| {
+        let field = fields.next().filter(|field| field.len() == len)?;
+        u64::from_str_radix(field, 16).ok()
+    };
+
+    let time_low = next_field(8)? as u32;
+    let time_mid = next_field(4)? as u16;
+    let time_high = next_field(4)? as u16;
+    let clock_seq = next_field(4)? as u16;
+    let node = next_field(12)?;
+    if fields.next().is_some() {
+        return None;
+    }
+
+    let mut bytes = [0; 16];
+    bytes[0..4].copy_from_slice(&time_low.to_le_bytes());
+    bytes[4..6].copy_from_slice(&time_mid.to_le_bytes());
+    bytes[6..8].copy_from_slice(&time_high.to_le_bytes());
+    bytes[8..10].copy_from_slice(&clock_seq.to_be_bytes());
+    bytes[10..16].copy_from_slice(&node.to_be_bytes()[2..]);
+    Some(bytes)
+}
+
+fn parse_address(key: &str, value: &str) -> u64 {
+    value
+        .strip_prefix("0x")
+        .and_then(|digits| u64::from_str_radix(digits, 16).ok())
+        .unwrap_or_else(|| panic!("invalid value for {key}: {value}"))
+}
+
+/// Parses a canonical, page-aligned virtual address.
+fn parse_page_address(key: &str, value: &str) -> VirtualAddress {
+    usize::try_from(parse_address(key, value))
+        .ok()
+        .filter(|address| address % PAGE_SIZE == 0)
+        .and_then(VirtualAddress::new)
+        .unwrap_or_else(|| panic!("invalid value for {key}: {value}"))
+}
+
+/// Parses a `<start>:<len>:<flags>` physical memory region, where `start` and
+/// `len` are hexadecimal and `flags` is one of `r`, `rw`, `rx`, or `rwx`.
+fn parse_pre_mapped_region(key: &str, value: &str) -> PreMappedRegion {
+    let mut fields = value.split(':');
+    let (Some(start), Some(len), Some(flags), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        panic!("invalid value for {key}: {value}");
+    };
+
+    let phys = parse_address(key, start) as usize;
+    let len = parse_address(key, len) as usize;
+    let flags = match flags {
+        "r" => MappingFlags::empty(),
+        "rw" => MappingFlags::WRITABLE,
+        "rx" => MappingFlags::EXECUTABLE,
+        "rwx" => MappingFlags::WRITABLE.union(MappingFlags::EXECUTABLE),
+        _ => panic!("invalid value for {key}: {value}"),
+    };
+    let is_valid = len != 0
+        && phys
+            .checked_add(len - 1)
+            .and_then(PhysicalAddress::new)
+            .is_some();
+    assert!(is_valid, "invalid value for {key}: {value}");
+
+    PreMappedRegion {
+        phys,
+        virt: 0,
+        len,
+        flags,
+    }
+}
+
+/// Parses a `<base>:<baud rate>` serial port, where `base` is hexadecimal and
+/// `baud rate` is decimal.
+fn parse_serial(key: &str, value: &str) -> SerialConfig {
+    let (base, baud_rate) = value
+        .split_once(':')
+        .unwrap_or_else(|| panic!("invalid value for {key}: {value}"));
+    let baud_rate = baud_rate
+        .parse()
+        .ok()
+        .filter(|&baud_rate| baud_rate != 0)
+        .unwrap_or_else(|| panic!("invalid value for {key}: {value}"));
+
+    SerialConfig {
+        base: parse_address(key, base),
+        baud_rate,
+    }
+}
+
+/// Parses a memory type in the range reserved for OS loaders, which must be
+/// distinct from [`KERNEL_MEMORY`] and [`RECLAIMABLE_MEMORY`].
+fn parse_memory_type(key: &str, value: &str) -> MemoryType {
+    let ty = u32::try_from(parse_address(key, value))
+        .ok()
+        .filter(|&ty| ty >= 0x8000_0000)
+        .map(MemoryType::custom)
+        .unwrap_or_else(|| panic!("invalid value for {key}: {value}"));
+    assert_ne!(
+        ty, KERNEL_MEMORY,
+        "{key} must differ from the kernel memory type"
+    );
+    assert_ne!(
+        ty, RECLAIMABLE_MEMORY,
+        "{key} must differ from the reclaimable memory type"
+    );
+    ty
+}
+
+fn parse_bool(key: &str, value: &str) -> bool {
+    match value {
+        "true" | "on" | "1" => true,
+        "false" | "off" | "0" => false,
+        _ => panic!("invalid value for {key}: {value}"),
+    }
+}
+
+/// Returns an iterator over the `key=value` entries in `text`.
+fn entries(text: &'static str) -> impl Iterator<Item = (&'static str, &'static str)> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+}
+
+impl BootContext {
+    /// Loads the bootloader configuration, falling back to the default
+    /// configuration if the ESP doesn't contain a config file, and appends the
+    /// bootloader's load options to the kernel command line.
+    pub(crate) fn load_config(&self) -> Config {
+        let mut config = self.read_config();
+        if let Some(options) = self.load_options() {
+            config.command_line = self.append_command_line(config.command_line, options);
+        }
+        config
+    }
+
+    fn read_config(&self) -> Config {
+        let mut root = self
+            .open_file_system_root()
+            .unwrap_or_else(|error| panic!("failed to open file system root: {error}"));
+
+        let mut file = match root.open(CONFIG_NAME, FileMode::Read, FileAttribute::empty()) {
+            Ok(file) => file
+                .into_regular_file()
+                .expect("config file was closed or deleted"),
+            Err(_) => return Config::default(),
+        };
+
+        file.set_position(RegularFile::END_OF_FILE)
+            .expect("failed to set config file position to end");
+        let len = file
+            .get_position()
+            .expect("failed to get config file length") as usize;
+        if len == 0 {
+            return Config::default();
+        }
+        file.set_position(0)
+            .expect("failed to set config file position to start");
+
+        // The parsed configuration borrows its strings from this buffer, and some,
+        // like the kernel parameters, are still read after exiting boot services.
+        // The buffer stays valid, as reclaimable memory isn't freed until the
+        // kernel reclaims it.
+        let buffer = self.allocate_byte_slice(len, RECLAIMABLE_MEMORY);
+        file.read(buffer).expect("failed to read config file");
+        let text = core::str::from_utf8(buffer).expect("config file is not valid UTF-8");
+
+        Config::parse(text)
+    }
+
+    /// Returns `command_line` followed by `options`, separated by a space.
+    fn append_command_line(
+        &self,
+        command_line: &'static str,
+        options: &'static str,
+    ) -> &'static str {
+        if command_line.is_empty() {
+            return options;
+        }
+
+        let len = command_line.len() + 1 + options.len();
+        assert!(
+            len <= MAX_COMMAND_LINE_SIZE,
+            "command line is too long (the maximum is {MAX_COMMAND_LINE_SIZE} bytes)"
+        );
+        let buffer = self.allocate_byte_slice(len, RECLAIMABLE_MEMORY);
+        let (start, rest) = buffer.split_at_mut(command_line.len());
+        start.copy_from_slice(command_line.as_bytes());
+        rest[0] = b' ';
+        rest[1..].copy_from_slice(options.as_bytes());
+        core::str::from_utf8(buffer).expect("command line is not valid UTF-8")
+    }
+}
@@ -1,24 +1,41 @@
 use crate::{
+    config::Config,
+    error::BootError,
+    logger,
     memory::{
-        Frame, FrameRange, LegacyFrameAllocator, Mapper, Page, PageAllocator, PageRange,
-        PhysicalAddress, PteFlags, UefiFrameAllocator, VirtualAddress, KERNEL_MEMORY,
+        spanned_ranges, Frame, FrameAllocator, FrameRange, LegacyFrameAllocator, Mapper,
+        PageAllocator, PageRange, PhysicalAddress, PteFlags, UefiFrameAllocator, VirtualAddress,
+        HUGE_PAGE_SIZE, KERNEL_MEMORY, PAGE_SIZE, RECLAIMABLE_MEMORY,
     },
-    util::calculate_pages,
+    util::{calculate_pages, ucs2_to_utf8},
 };
-use core::mem::MaybeUninit;
+use core::{alloc::Layout, any::type_name, cell::Cell, fmt, mem::MaybeUninit, ptr, slice};
 use goblin::elf64::program_header::ProgramHeader;
+use log::{info, log_enabled, trace, warn, Level};
 use uefi::{
     proto::{
-        device_path::DevicePath,
+        device_path::{DevicePath, DeviceSubType, DeviceType},
         loaded_image::LoadedImage,
         media::{file::Directory, fs::SimpleFileSystem},
     },
     table::{
-        boot::{AllocateType, MemoryType},
+        boot::{AllocateType, MemoryType, SearchType},
         Boot, SystemTable,
     },
-    Handle,
+    Handle, Status,
 };
+use uefi_bootloader_api::{
+    EarlyHeap, FirmwareInfo, MappingFlags, PreMappedRegion, MAX_COMMAND_LINE_SIZE,
+    MAX_PRE_MAPPED_REGIONS,
+};
+
+/// The number of pages set aside when the bootloader starts, and freed to make
+/// room for an allocation that would otherwise run out of memory.
+///
+/// The reserve is allocated before the firmware's memory is fragmented by the
+/// bootloader's own allocations, so freeing it makes a contiguous range
+/// available again.
+const RESERVE_PAGES: usize = 256;
 
 /// Bootloader context before extiting boot services.
 pub(crate) struct BootContext {
@@ -26,6 +43,12 @@ pub(crate) struct BootContext {
     pub(crate) system_table: SystemTable<Boot>,
     pub(crate) page_allocator: PageAllocator,
     pub(crate) mapper: Mapper,
+    /// The frames spanned by the kernel's loaded segments.
+    pub(crate) kernel_frames: FrameRange,
+    /// The address of the [`RESERVE_PAGES`] pages set aside for
+    /// [`allocate_slice_inner`](Self::allocate_slice_inner), if they haven't
+    /// been freed yet.
+    reserve: Cell<Option<u64>>,
 }
 
 impl BootContext {
@@ -34,29 +57,156 @@ impl BootContext {
             system_table: &system_table,
         };
         let mapper = Mapper::new(&mut frame_allocator);
+        let reserve = match system_table.boot_services().allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::LOADER_DATA,
+            RESERVE_PAGES,
+        ) {
+            Ok(address) => Some(address),
+            Err(error) => {
+                warn!("failed to allocate memory reserve: {:?}", error.status());
+                None
+            }
+        };
 
         Self {
             image_handle,
             system_table,
             page_allocator: PageAllocator::new(),
             mapper,
+            kernel_frames: FrameRange::empty(),
+            reserve: Cell::new(reserve),
+        }
+    }
+
+    /// Frees the memory reserve, returning whether it hadn't already been
+    /// freed.
+    fn free_reserve(&self) -> bool {
+        match self.reserve.take() {
+            Some(address) => {
+                // The reserve isn't referenced anywhere, so failing to free it
+                // only wastes memory.
+                let _ = self
+                    .system_table
+                    .boot_services()
+                    .free_pages(address, RESERVE_PAGES);
+                true
+            }
+            None => false,
         }
     }
 
-    pub(crate) fn open_file_system_root(&self) -> Option<Directory> {
+    pub(crate) fn open_file_system_root(&self) -> Result<Directory, FileSystemError> {
         let boot_services = self.system_table.boot_services();
 
         let loaded_image = boot_services
             .open_protocol_exclusive::<LoadedImage>(self.image_handle)
-            .ok()?;
+            .map_err(|error| FileSystemError::LoadedImage(error.status()))?;
+        let device = loaded_image.device();
+        let device_handle = match self.locate_file_system(device) {
+            Err(FileSystemError::SimpleFileSystem(Status::NOT_FOUND)) => {
+                // Minimal firmware may only have connected a block I/O driver
+                // to the boot device.
+                warn!("no file system driver for boot device, connecting drivers");
+                if let Err(error) = boot_services.connect_controller(device, None, None, true) {
+                    warn!("failed to connect drivers: {:?}", error.status());
+                }
+                self.locate_file_system(device)
+                    .map_err(|error| match error {
+                        FileSystemError::SimpleFileSystem(Status::NOT_FOUND) => {
+                            FileSystemError::NoFileSystemDriver
+                        }
+                        error => error,
+                    })?
+            }
+            result => result?,
+        };
+        boot_services
+            .open_protocol_exclusive::<SimpleFileSystem>(device_handle)
+            .map_err(|error| FileSystemError::SimpleFileSystem(error.status()))?
+            .open_volume()
+            .map_err(|error| FileSystemError::OpenVolume(error.status()))
+    }
+
+    /// Returns the handle on the path to `device` that supports the simple file
+    /// system protocol.
+    fn locate_file_system(&self, device: Handle) -> Result<Handle, FileSystemError> {
+        let boot_services = self.system_table.boot_services();
         let device_path = boot_services
-            .open_protocol_exclusive::<DevicePath>(loaded_image.device())
-            .ok()?;
-        let device_handle = boot_services
+            .open_protocol_exclusive::<DevicePath>(device)
+            .map_err(|error| FileSystemError::DevicePath(error.status()))?;
+        boot_services
             .locate_device_path::<SimpleFileSystem>(&mut &*device_path)
+            .map_err(|error| FileSystemError::SimpleFileSystem(error.status()))
+    }
+
+    /// Returns the load options the bootloader was started with, converted
+    /// from UCS-2.
+    ///
+    /// Returns `None` if there are no load options, or they aren't a UCS-2
+    /// string. Options longer than [`MAX_COMMAND_LINE_SIZE`] bytes are
+    /// truncated.
+    pub(crate) fn load_options(&self) -> Option<&'static str> {
+        let loaded_image = self
+            .system_table
+            .boot_services()
+            .open_protocol_exclusive::<LoadedImage>(self.image_handle)
+            .expect("failed to open loaded image protocol");
+        if loaded_image
+            .load_options_as_bytes()
+            .map_or(true, <[u8]>::is_empty)
+        {
+            return None;
+        }
+        let Ok(options) = loaded_image.load_options_as_cstr16() else {
+            warn!("ignoring load options that aren't a UCS-2 string");
+            return None;
+        };
+
+        // Each UCS-2 character is at most three bytes in UTF-8.
+        let max_len = options.to_u16_slice().len() * 3;
+        if max_len > MAX_COMMAND_LINE_SIZE {
+            warn!("load options may be truncated to {MAX_COMMAND_LINE_SIZE} bytes");
+        }
+        let buffer =
+            self.allocate_byte_slice(max_len.min(MAX_COMMAND_LINE_SIZE), RECLAIMABLE_MEMORY);
+        let len = ucs2_to_utf8(options, buffer);
+        let options = core::str::from_utf8(&buffer[..len])
+            .expect("converted load options are not valid UTF-8")
+            .trim();
+        (!options.is_empty()).then_some(options)
+    }
+
+    /// Opens the root directory of the partition the kernel and modules are
+    /// loaded from.
+    pub(crate) fn open_kernel_root(&self, config: &Config) -> Result<Directory, FileSystemError> {
+        if let Some(guid) = config.partition {
+            match self.open_partition_root(&guid) {
+                Some(root) => return Ok(root),
+                None => warn!("configured partition not found, using boot partition"),
+            }
+        }
+        self.open_file_system_root()
+    }
+
+    /// Opens the root directory of the partition with the given GPT unique
+    /// partition GUID.
+    fn open_partition_root(&self, guid: &[u8; 16]) -> Option<Directory> {
+        let boot_services = self.system_table.boot_services();
+        let handles = boot_services
+            .locate_handle_buffer(SearchType::from_proto::<SimpleFileSystem>())
             .ok()?;
+
+        let handle = handles.handles().iter().copied().find(|handle| {
+            boot_services
+                .open_protocol_exclusive::<DevicePath>(*handle)
+                .map_or(false, |device_path| {
+                    partition_guid(&device_path).as_ref() == Some(guid)
+                })
+        })?;
+
         boot_services
-            .open_protocol_exclusive::<SimpleFileSystem>(device_handle)
+            .open_protocol_exclusive::<SimpleFileSystem>(handle)
             .ok()?
             .open_volume()
             .ok()
@@ -66,27 +216,68 @@ impl BootContext {
         &self.system_table
     }
 
-    fn allocate_slice_inner<T>(
+    /// Allocates a zeroed slice of `len` elements.
+    ///
+    /// If the firmware is out of memory, the memory reserve is freed and the
+    /// allocation is retried once, unless it must be at a specific address.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocation fails, naming the caller and what was being
+    /// allocated.
+    #[track_caller]
+    pub(crate) fn allocate_slice_inner<T>(
         &self,
         len: usize,
         allocate_type: AllocateType,
         memory_type: MemoryType,
     ) -> &'static mut [MaybeUninit<T>] {
+        // Boot services can't allocate zero pages.
+        if len == 0 {
+            return &mut [];
+        }
+
         let bytes_len = core::mem::size_of::<T>() * len;
         let num_pages = calculate_pages(bytes_len);
-        let pointer = self
-            .system_table
-            .boot_services()
-            // TODO: Allocate pool?
+        let boot_services = self.system_table.boot_services();
+        // TODO: Allocate pool?
+        let pointer = boot_services
             .allocate_pages(allocate_type, memory_type, num_pages)
-            .expect("failed to allocate pages for slice") as *mut _;
+            .or_else(|error| {
+                if error.status() == Status::OUT_OF_RESOURCES
+                    && matches!(allocate_type, AllocateType::AnyPages)
+                    && self.free_reserve()
+                {
+                    warn!("out of memory allocating {num_pages} pages, freed reserve to retry");
+                    boot_services.allocate_pages(allocate_type, memory_type, num_pages)
+                } else {
+                    Err(error)
+                }
+            })
+            .unwrap_or_else(|error| {
+                panic!(
+                    "failed to allocate {num_pages} pages of {memory_type:?} memory for {len} \
+                     {}: {:?}",
+                    core::any::type_name::<T>(),
+                    error.status()
+                )
+            }) as *mut MaybeUninit<T>;
+        // `write_bytes` counts in units of `T`, so this zeroes all `bytes_len` bytes.
         // SAFETY: We just allocated the memory at `pointer`.
         unsafe { core::ptr::write_bytes(pointer, 0, len) };
+        debug_assert!(
+            // SAFETY: We just zeroed the memory at `pointer`.
+            unsafe { core::slice::from_raw_parts(pointer.cast::<u8>(), bytes_len) }
+                .iter()
+                .all(|byte| *byte == 0),
+            "allocated slice was not fully zeroed"
+        );
         // SAFETY: We just allocated the memory at `pointer`.
         let slice = unsafe { core::slice::from_raw_parts_mut(pointer, len) };
         slice
     }
 
+    #[track_caller]
     pub(crate) fn allocate_slice<T>(
         &self,
         len: usize,
@@ -95,16 +286,86 @@ impl BootContext {
         self.allocate_slice_inner(len, AllocateType::AnyPages, memory_type)
     }
 
+    #[track_caller]
     pub(crate) fn allocate_byte_slice(&self, len: usize, ty: MemoryType) -> &'static mut [u8] {
         let slice = self.allocate_slice(len, ty);
         // SAFETY: allocate_slice zeroed the bytes so they are initialised.
         unsafe { MaybeUninit::slice_assume_init_mut(slice) }
     }
 
-    pub(crate) fn map_segment(&mut self, segment: &ProgramHeader) -> &'static mut [u8] {
+    /// Allocates a zeroed byte slice whose address is `offset` more than a
+    /// multiple of `align`.
+    ///
+    /// `align` must be a power of two that is at least the page size, and
+    /// `offset` must be a multiple of the page size that is less than `align`.
+    #[track_caller]
+    pub(crate) fn allocate_aligned_byte_slice(
+        &self,
+        len: usize,
+        align: usize,
+        offset: usize,
+        ty: MemoryType,
+    ) -> &'static mut [u8] {
+        debug_assert!(align.is_power_of_two() && align >= PAGE_SIZE);
+        debug_assert!(offset < align && offset % PAGE_SIZE == 0);
+
+        // Over-allocate so that a suitably aligned start address is guaranteed
+        // to exist, and then give back the pages on either side of it.
+        let aligned_len = calculate_pages(len) * PAGE_SIZE;
+        let slack = align - PAGE_SIZE;
+        let slice = self.allocate_byte_slice(aligned_len + slack, ty);
+        let skip = offset.wrapping_sub(slice.as_ptr() as usize) & (align - 1);
+        let (head, rest) = slice.split_at_mut(skip);
+        let (aligned, tail) = rest.split_at_mut(aligned_len);
+        // SAFETY: Both slices consist of whole pages returned by
+        // `allocate_byte_slice`.
+        unsafe {
+            self.free_byte_slice(head);
+            self.free_byte_slice(tail);
+        }
+        &mut aligned[..len]
+    }
+
+    /// Frees a slice returned by [`allocate_byte_slice`](Self::allocate_byte_slice)
+    /// once it is no longer needed, so that the memory is available for later
+    /// allocations.
+    ///
+    /// # Safety
+    ///
+    /// `slice` must have been returned by `allocate_byte_slice`, or consist of
+    /// whole pages of such a slice.
+    pub(crate) unsafe fn free_byte_slice(&self, slice: &'static mut [u8]) {
+        if slice.is_empty() {
+            return;
+        }
+
+        self.system_table
+            .boot_services()
+            .free_pages(slice.as_ptr() as u64, calculate_pages(slice.len()))
+            .expect("failed to free slice");
+    }
+
+    /// Allocates memory for `segment` and maps it into the kernel's page table.
+    ///
+    /// If `huge_pages` is set, the segment is placed so that its physical
+    /// address is congruent to its virtual address modulo [`HUGE_PAGE_SIZE`],
+    /// and the 2 MiB aligned interior of the segment is mapped with huge pages.
+    /// The unaligned edges are mapped with 4 KiB pages.
+    pub(crate) fn map_segment(
+        &mut self,
+        segment: &ProgramHeader,
+        huge_pages: bool,
+    ) -> Result<&'static mut [u8], BootError> {
         let in_page_offset = (segment.p_vaddr as usize) & 0xfff;
         let size_from_page_start = in_page_offset + segment.p_memsz as usize;
 
+        // `elf::parse_program_header` checked that the alignment is zero, one,
+        // or a power of two that the segment's address is congruent with.
+        let mut align = (segment.p_align as usize).max(PAGE_SIZE);
+        if huge_pages && size_from_page_start >= HUGE_PAGE_SIZE {
+            align = align.max(HUGE_PAGE_SIZE);
+        }
+
         cfg_if::cfg_if! {
             if #[cfg(target_arch = "x86_64")] {
                 let is_x86_64_init_section = segment.p_paddr == 0x10_0000;
@@ -121,29 +382,37 @@ impl BootContext {
             );
             // SAFETY: allocate_slice_inner zeroed the bytes so they are initialised.
             unsafe { MaybeUninit::slice_assume_init_mut(maybe_uninit_slice) }
-        } else {
+        } else if align == PAGE_SIZE {
             self.allocate_byte_slice(size_from_page_start, KERNEL_MEMORY)
+        } else {
+            // The physical address must be congruent to the virtual address
+            // modulo the alignment, so that the segment can be mapped with
+            // pages of that size.
+            let offset = segment.p_vaddr as usize & (align - 1) & !(PAGE_SIZE - 1);
+            self.allocate_aligned_byte_slice(size_from_page_start, align, offset, KERNEL_MEMORY)
         };
 
         self.page_allocator.mark_segment_as_used(segment);
 
         let virtual_start = VirtualAddress::new_canonical(segment.p_vaddr as usize);
-        let virtual_end_inclusive = virtual_start + segment.p_memsz as usize - 1;
-
         let physical_start =
             PhysicalAddress::new_canonical(slice.as_ptr() as usize + in_page_offset);
-        let physical_end_inclusive = physical_start + segment.p_memsz as usize - 1;
-
-        let pages = PageRange::new(
-            Page::containing_address(virtual_start),
-            Page::containing_address(virtual_end_inclusive),
-        )
-        .into_iter();
-        let frames = FrameRange::new(
-            Frame::containing_address(physical_start),
-            Frame::containing_address(physical_end_inclusive),
+        let (pages, frames) =
+            spanned_ranges(virtual_start, physical_start, segment.p_memsz as usize);
+        // The virtual and physical ends are calculated independently, so an
+        // off-by-one in either would silently under-map the segment.
+        assert_eq!(
+            pages.size_in_pages(),
+            frames.size_in_frames(),
+            "segment at {virtual_start:?} (physical {physical_start:?}) spans {pages:?} but is \
+             backed by {frames:?}"
         );
 
+        self.kernel_frames = self
+            .kernel_frames
+            .to_extended(*frames.start())
+            .to_extended(*frames.end());
+
         let mut flags = PteFlags::new().present(true);
 
         // If the first bit isn't set
@@ -156,26 +425,145 @@ impl BootContext {
             flags = flags.writable(true);
         }
 
-        for (page, frame) in pages.zip(frames) {
-            self.mapper.map(
-                page,
-                frame,
-                flags,
-                &mut UefiFrameAllocator {
-                    system_table: &self.system_table,
-                },
-            );
+        info!(
+            "mapping segment {:#x}..{:#x} to {:#x}..{:#x} ({flags}{})",
+            virtual_start.value(),
+            virtual_start.value() + segment.p_memsz as usize,
+            physical_start.value(),
+            physical_start.value() + segment.p_memsz as usize,
+            if huge_pages { ", huge pages" } else { "" }
+        );
+
+        let mut frame_allocator = UefiFrameAllocator {
+            system_table: &self.system_table,
+        };
+        if huge_pages {
+            self.mapper
+                .map_range(pages, frames, flags, &mut frame_allocator)?;
+        } else {
+            for (page, frame) in pages.into_iter().zip(frames) {
+                self.mapper.map(page, frame, flags, &mut frame_allocator)?;
+            }
         }
 
-        &mut slice[in_page_offset..]
+        Ok(&mut slice[in_page_offset..])
     }
 
-    pub(crate) fn exit_boot_services(self) -> RuntimeContext {
+    /// Returns the firmware vendor and revision.
+    ///
+    /// The firmware vendor string is only accessible before exiting boot
+    /// services.
+    fn firmware_info(&self) -> FirmwareInfo {
+        let mut vendor = [0; 64];
+        // Leave space for the null terminator.
+        let len = vendor.len() - 1;
+        ucs2_to_utf8(self.system_table.firmware_vendor(), &mut vendor[..len]);
+
+        FirmwareInfo {
+            vendor,
+            revision: self.system_table.firmware_revision(),
+        }
+    }
+
+    pub(crate) fn exit_boot_services(self, config: &Config) -> RuntimeContext {
+        let firmware = self.firmware_info();
+        let system_table_address = self.system_table.as_ptr() as usize;
+        // Return the reserve to the firmware, so that it's usable by the kernel.
+        self.free_reserve();
+        // The console can't be used after exiting boot services.
+        logger::CONSOLE_LOGGER.disable();
         let (_, memory_map) = self.system_table.exit_boot_services();
+
+        if log_enabled!(Level::Trace) {
+            for descriptor in memory_map.clone().take(config.memory_map_dump) {
+                trace!(
+                    "{:?}: {:#x} ({} pages, {:?})",
+                    descriptor.ty,
+                    descriptor.phys_start,
+                    descriptor.page_count,
+                    descriptor.att
+                );
+            }
+        }
+
         RuntimeContext {
             page_allocator: self.page_allocator,
             frame_allocator: LegacyFrameAllocator::new(memory_map),
             mapper: self.mapper,
+            kernel_frames: self.kernel_frames,
+            firmware,
+            system_table_address,
+            identity_mapped: false,
+            trampoline: None,
+            early_heap: None,
+            bootloader_mapper: None,
+            pre_mapped_regions: [PreMappedRegion {
+                phys: 0,
+                virt: 0,
+                len: 0,
+                flags: MappingFlags::empty(),
+            }; MAX_PRE_MAPPED_REGIONS],
+            num_pre_mapped_regions: 0,
+        }
+    }
+}
+
+/// Returns the GPT unique partition GUID of the partition described by
+/// `device_path`, in its on-disk byte order.
+fn partition_guid(device_path: &DevicePath) -> Option<[u8; 16]> {
+    /// The signature type of GPT partitions in a hard drive device path node.
+    const SIGNATURE_TYPE_GUID: u8 = 2;
+
+    let node = device_path.node_iter().find(|node| {
+        node.device_type() == DeviceType::MEDIA
+            && node.sub_type() == DeviceSubType::MEDIA_HARD_DRIVE
+    })?;
+
+    // The hard drive node data consists of the partition number (4 bytes), start
+    // (8 bytes), size (8 bytes), signature (16 bytes), format (1 byte), and
+    // signature type (1 byte).
+    let data = node.data();
+    if data.get(37) != Some(&SIGNATURE_TYPE_GUID) {
+        return None;
+    }
+    data.get(20..36)?.try_into().ok()
+}
+
+/// An error encountered while opening the file system the bootloader was
+/// loaded from.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum FileSystemError {
+    /// The loaded image protocol couldn't be opened on the bootloader image.
+    LoadedImage(Status),
+    /// The device path protocol couldn't be opened on the boot device.
+    DevicePath(Status),
+    /// The simple file system protocol couldn't be located or opened on the
+    /// boot device.
+    SimpleFileSystem(Status),
+    /// No file system driver supports the boot device, even after connecting
+    /// drivers to it.
+    NoFileSystemDriver,
+    /// The file system volume couldn't be opened.
+    OpenVolume(Status),
+}
+
+impl fmt::Display for FileSystemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LoadedImage(status) => {
+                write!(f, "failed to open loaded image protocol: {status:?}")
+            }
+            Self::DevicePath(status) => {
+                write!(f, "failed to open boot device path protocol: {status:?}")
+            }
+            Self::SimpleFileSystem(status) => {
+                write!(f, "failed to open boot device file system: {status:?}")
+            }
+            Self::NoFileSystemDriver => write!(
+                f,
+                "no file system driver for boot device (is the ESP FAT formatted?)"
+            ),
+            Self::OpenVolume(status) => write!(f, "failed to open boot volume: {status:?}"),
         }
     }
 }
@@ -185,6 +573,29 @@ pub(crate) struct RuntimeContext {
     pub(crate) page_allocator: PageAllocator,
     pub(crate) frame_allocator: LegacyFrameAllocator,
     pub(crate) mapper: Mapper,
+    pub(crate) kernel_frames: FrameRange,
+    pub(crate) firmware: FirmwareInfo,
+    /// The physical address of the EFI system table, which remains valid
+    /// after exiting boot services.
+    pub(crate) system_table_address: usize,
+    /// Whether all RAM is identity-mapped in the kernel's page table.
+    pub(crate) identity_mapped: bool,
+    /// The identity-mapped frame containing the context switch function, if
+    /// it had to be mapped separately.
+    pub(crate) trampoline: Option<Frame>,
+    /// The memory reserved for the kernel's early allocator, if any.
+    pub(crate) early_heap: Option<EarlyHeap>,
+    /// The bootloader's page table, through which memory allocated by
+    /// [`allocate_mapped`](Self::allocate_mapped) is written.
+    ///
+    /// The kernel's page table isn't active until the bootloader jumps to the
+    /// kernel, so memory the kernel accesses at a virtual address chosen by the
+    /// bootloader must also be mapped here for the bootloader to write it.
+    /// This is created on first use.
+    pub(crate) bootloader_mapper: Option<Mapper>,
+    /// The physical memory regions mapped for `map_phys` options.
+    pub(crate) pre_mapped_regions: [PreMappedRegion; MAX_PRE_MAPPED_REGIONS],
+    pub(crate) num_pre_mapped_regions: usize,
 }
 
 impl RuntimeContext {
@@ -192,4 +603,102 @@ impl RuntimeContext {
     pub(crate) fn page_table(&mut self) -> Frame {
         self.mapper.frame()
     }
+
+    /// Maps each frame in `frames` to the page with the same number.
+    pub(crate) fn identity_map(&mut self, frames: FrameRange, flags: PteFlags) {
+        self.mapper
+            .identity_map(frames.clone(), flags, &mut self.frame_allocator)
+            .unwrap_or_else(|error| panic!("failed to identity-map {frames:?}: {error}"));
+    }
+
+    /// Allocates physically contiguous frames for `len` elements of `T` and
+    /// maps them at a free virtual address, returning the memory and its
+    /// virtual and physical addresses.
+    ///
+    /// The memory is mapped at the same address in both the kernel's page table
+    /// and the bootloader's page table, so the bootloader can write it through
+    /// the returned slice and the kernel can read it at the returned address.
+    pub(crate) fn allocate_mapped<T>(
+        &mut self,
+        len: usize,
+    ) -> (
+        &'static mut [MaybeUninit<T>],
+        VirtualAddress,
+        PhysicalAddress,
+    ) {
+        self.allocate_mapped_at(len, None)
+    }
+
+    /// Like [`allocate_mapped`](Self::allocate_mapped), but places the memory
+    /// at `hint` if possible.
+    pub(crate) fn allocate_mapped_at<T>(
+        &mut self,
+        len: usize,
+        hint: Option<VirtualAddress>,
+    ) -> (
+        &'static mut [MaybeUninit<T>],
+        VirtualAddress,
+        PhysicalAddress,
+    ) {
+        let layout = Layout::array::<T>(len)
+            .unwrap_or_else(|_| panic!("failed to create layout for {len} {}", type_name::<T>()));
+        assert!(
+            layout.align() <= PAGE_SIZE,
+            "{} is aligned to more than a page",
+            type_name::<T>()
+        );
+        // Empty allocations still get a page, so that they have a unique address.
+        let size = layout.size().max(1);
+
+        let address = match hint {
+            Some(hint) => self.page_allocator.get_free_address_at(hint, size),
+            None => self.page_allocator.get_free_address(size),
+        };
+        let pages = PageRange::from_virt_addr(address, size);
+
+        let bootloader_mapper = self
+            .bootloader_mapper
+            .get_or_insert_with(|| Mapper::current(&mut self.frame_allocator));
+        let flags = PteFlags::new().present(true).writable(true);
+        let frames = self
+            .frame_allocator
+            .allocate_frames(pages.size_in_pages())
+            .expect("failed to allocate frames for mapped memory");
+
+        for (page, frame) in pages.into_iter().zip(frames.clone()) {
+            self.mapper
+                .map(page, frame, flags, &mut self.frame_allocator)
+                .unwrap_or_else(|error| panic!("failed to map {page:?} to {frame:?}: {error}"));
+            // This flushes the TLB entry for the page, as the bootloader's page table
+            // is active.
+            bootloader_mapper
+                .map(page, frame, flags, &mut self.frame_allocator)
+                .unwrap_or_else(|error| {
+                    panic!("failed to map {page:?} to {frame:?} for the bootloader: {error}")
+                });
+
+            // A mismatch would only show up as the kernel reading garbage or
+            // faulting.
+            debug_assert_eq!(
+                self.mapper.translate_page(page),
+                Some(frame),
+                "{page:?} is mapped incorrectly in the kernel's page table"
+            );
+            debug_assert_eq!(
+                bootloader_mapper.translate_page(page),
+                Some(frame),
+                "{page:?} is mapped incorrectly in the bootloader's page table"
+            );
+        }
+
+        // Zeroing the memory checks that it's writable through the new mapping
+        // before the bootloader relies on it.
+        // SAFETY: The memory was just mapped in the current page table, and isn't
+        // used by anything else.
+        unsafe { ptr::write_bytes(address.value() as *mut u8, 0, size) };
+
+        // SAFETY: As above.
+        let slice = unsafe { slice::from_raw_parts_mut(address.value() as *mut _, len) };
+        (slice, address, frames.start_address())
+    }
 }
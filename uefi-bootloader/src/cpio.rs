@@ -0,0 +1,102 @@
+//! Parsing of the module archive, which is a cpio archive in the `newc` format
+//! created by `cpio -o -H newc`.
+//!
+//! This only depends on `core`, so that it can also be built for the host by
+//! the fuzz targets in `fuzz/`.
+
+/// The size of an entry's header, which is followed by its name.
+const HEADER_SIZE: usize = 110;
+
+/// The name of the entry that marks the end of the archive.
+const TRAILER_NAME: &[u8] = b"TRAILER!!!";
+
+/// The mask of the file type bits in an entry's mode.
+const S_IFMT: u32 = 0o170_000;
+
+/// The file type of regular files.
+const S_IFREG: u32 = 0o100_000;
+
+/// The reason an archive was rejected.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CpioError(pub(crate) &'static str);
+
+/// A file, directory, or other entry in an archive.
+pub(crate) struct Entry<'a> {
+    /// The path of the entry, without the null terminator.
+    pub(crate) name: &'a [u8],
+    mode: u32,
+    pub(crate) data: &'a [u8],
+}
+
+impl Entry<'_> {
+    /// Returns whether the entry is a regular file.
+    pub(crate) fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+}
+
+/// Returns an iterator over the entries in the archive `bytes`, excluding the
+/// trailer.
+///
+/// Iteration stops after the first error, which is also returned if the
+/// archive ends without a trailer.
+pub(crate) fn entries(bytes: &[u8]) -> impl Iterator<Item = Result<Entry<'_>, CpioError>> {
+    let mut rest = Some(bytes);
+    core::iter::from_fn(move || {
+        let (entry, next) = match parse_entry(rest?) {
+            Ok((entry, _)) if entry.name == TRAILER_NAME => {
+                rest = None;
+                return None;
+            }
+            Ok(result) => result,
+            Err(error) => {
+                rest = None;
+                return Some(Err(error));
+            }
+        };
+        rest = Some(next);
+        Some(Ok(entry))
+    })
+}
+
+/// Parses the entry at the start of `bytes`, returning it and the bytes after
+/// it.
+fn parse_entry(bytes: &[u8]) -> Result<(Entry<'_>, &[u8]), CpioError> {
+    let header = bytes
+        .get(..HEADER_SIZE)
+        .ok_or(CpioError("archive ends without a trailer"))?;
+    if &header[..6] != b"070701" && &header[..6] != b"070702" {
+        return Err(CpioError("entry is not in the newc format"));
+    }
+    let field = |index: usize| {
+        let start = 6 + index * 8;
+        core::str::from_utf8(&header[start..start + 8])
+            .ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or(CpioError("invalid header field"))
+    };
+    let mode = field(1)?;
+    let file_size = field(6)? as usize;
+    let name_size = field(11)? as usize;
+
+    let name_end = HEADER_SIZE + name_size;
+    let name = bytes
+        .get(HEADER_SIZE..name_end)
+        .and_then(|name| name.strip_suffix(&[0]))
+        .ok_or(CpioError("invalid entry name"))?;
+    let data_start = align(name_end);
+    let data_end = data_start
+        .checked_add(file_size)
+        .ok_or(CpioError("entry extends beyond the end of the archive"))?;
+    let data = bytes
+        .get(data_start..data_end)
+        .ok_or(CpioError("entry extends beyond the end of the archive"))?;
+    let next = bytes.get(align(data_end)..).unwrap_or(&[]);
+
+    Ok((Entry { name, mode, data }, next))
+}
+
+/// Rounds `offset` up to the 4 byte alignment of names and data.
+const fn align(offset: usize) -> usize {
+    offset.saturating_add(3) & !3
+}
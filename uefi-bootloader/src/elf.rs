@@ -0,0 +1,184 @@
+//! Parsing and validation of the kernel's ELF headers.
+//!
+//! This only depends on `core`, `goblin`, and `plain`, so that it can also be
+//! built for the host by the fuzz targets in `fuzz/`. Headers are copied out of
+//! the byte buffers rather than cast, as the buffers needn't be aligned.
+
+use goblin::elf64::{
+    header::{Header, EI_CLASS, EI_DATA, ELFCLASS64, ELFDATA2LSB, ELFDATA2MSB, ELFMAG, SELFMAG},
+    program_header::{ProgramHeader, PT_LOAD, PT_NOTE, SIZEOF_PHDR},
+    section_header::{SectionHeader, SHT_NOBITS, SIZEOF_SHDR},
+};
+
+/// The reason an ELF file was rejected.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ElfError(pub(crate) &'static str);
+
+/// Parses the ELF header at the start of `bytes`, checking that it describes
+/// a file the loader supports, and that its program and section header tables
+/// lie within the `file_size` byte file.
+pub(crate) fn parse_header(bytes: &[u8], file_size: u64) -> Result<Header, ElfError> {
+    let header: Header = copy_from_bytes(bytes, "file is too short for an ELF header")?;
+    check_ident(&header)?;
+
+    // The header counts are used for allocation sizes and loop bounds, so a
+    // corrupt header could otherwise cause huge allocations.
+    check_range(
+        "program header table extends beyond the end of the file",
+        header.e_phoff,
+        u64::from(header.e_phnum) * SIZEOF_PHDR as u64,
+        file_size,
+    )?;
+    check_range(
+        "section header table extends beyond the end of the file",
+        header.e_shoff,
+        u64::from(header.e_shnum) * SIZEOF_SHDR as u64,
+        file_size,
+    )?;
+    Ok(header)
+}
+
+/// Parses a program header, checking that the contents of loadable and note
+/// segments lie within the `file_size` byte file, and that loadable segments
+/// can be mapped at their alignment.
+pub(crate) fn parse_program_header(
+    bytes: &[u8],
+    file_size: u64,
+) -> Result<ProgramHeader, ElfError> {
+    let header: ProgramHeader = copy_from_bytes(bytes, "truncated program header")?;
+    match header.p_type {
+        PT_LOAD => {
+            if header.p_filesz > header.p_memsz {
+                return Err(ElfError("segment is larger in the file than in memory"));
+            }
+            if header.p_vaddr.checked_add(header.p_memsz).is_none() {
+                return Err(ElfError(
+                    "segment extends beyond the end of the address space",
+                ));
+            }
+            // An alignment of zero or one means the segment has no alignment
+            // requirement.
+            if header.p_align > 1 {
+                if !header.p_align.is_power_of_two() {
+                    return Err(ElfError("segment alignment is not a power of two"));
+                }
+                if header.p_vaddr % header.p_align != header.p_offset % header.p_align {
+                    return Err(ElfError(
+                        "segment address is not congruent to its file offset modulo its alignment",
+                    ));
+                }
+            }
+            check_range(
+                "segment extends beyond the end of the file",
+                header.p_offset,
+                header.p_filesz,
+                file_size,
+            )?;
+        }
+        PT_NOTE => check_range(
+            "note segment extends beyond the end of the file",
+            header.p_offset,
+            header.p_filesz,
+            file_size,
+        )?,
+        _ => {}
+    }
+    Ok(header)
+}
+
+/// Parses a section header, checking that the section's contents lie within
+/// the `file_size` byte file.
+pub(crate) fn parse_section_header(
+    bytes: &[u8],
+    file_size: u64,
+) -> Result<SectionHeader, ElfError> {
+    let header: SectionHeader = copy_from_bytes(bytes, "truncated section header")?;
+    if header.sh_type != SHT_NOBITS {
+        check_range(
+            "section extends beyond the end of the file",
+            header.sh_offset,
+            header.sh_size,
+            file_size,
+        )?;
+    }
+    Ok(header)
+}
+
+/// Checks that `header` identifies a little-endian, 64-bit ELF file, which is
+/// the only kind the loader can parse.
+fn check_ident(header: &Header) -> Result<(), ElfError> {
+    if &header.e_ident[..SELFMAG] != ELFMAG {
+        return Err(ElfError("missing ELF magic"));
+    }
+    if header.e_ident[EI_CLASS] != ELFCLASS64 {
+        return Err(ElfError("not a 64-bit ELF file"));
+    }
+    match header.e_ident[EI_DATA] {
+        ELFDATA2LSB => Ok(()),
+        ELFDATA2MSB => Err(ElfError(
+            "big-endian, but only little-endian kernels are supported",
+        )),
+        _ => Err(ElfError("invalid data encoding")),
+    }
+}
+
+/// Checks that the `len` bytes at `offset` lie within the `file_size` byte
+/// file, returning an error with `reason` if they don't.
+fn check_range(
+    reason: &'static str,
+    offset: u64,
+    len: u64,
+    file_size: u64,
+) -> Result<(), ElfError> {
+    match offset.checked_add(len) {
+        Some(end) if end <= file_size => Ok(()),
+        _ => Err(ElfError(reason)),
+    }
+}
+
+/// Copies a `T` out of the start of `bytes`, returning an error with `reason`
+/// if `bytes` is too short.
+fn copy_from_bytes<T>(bytes: &[u8], reason: &'static str) -> Result<T, ElfError>
+where
+    T: plain::Plain + Default,
+{
+    let mut value = T::default();
+    plain::copy_from_bytes(&mut value, bytes).map_err(|_| ElfError(reason))?;
+    Ok(value)
+}
+
+/// An ELF note.
+pub(crate) struct Note<'a> {
+    /// The name of the note's owner, including the null terminator.
+    pub(crate) name: &'a [u8],
+    pub(crate) ty: u32,
+    pub(crate) desc: &'a [u8],
+}
+
+/// Returns an iterator over the notes in `bytes`.
+///
+/// Iteration stops at the first malformed note.
+pub(crate) fn notes(mut bytes: &[u8]) -> impl Iterator<Item = Note<'_>> {
+    let align = |len: u32| (len as usize).checked_add(3).map(|len| len & !3);
+    let word = |bytes: &[u8], index: usize| {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[index * 4..(index + 1) * 4]);
+        u32::from_ne_bytes(word)
+    };
+
+    core::iter::from_fn(move || {
+        if bytes.len() < 12 {
+            return None;
+        }
+        let (name_size, desc_size, ty) = (word(bytes, 0), word(bytes, 1), word(bytes, 2));
+        let name_start = 12;
+        let desc_start = name_start + align(name_size)?;
+        let next = desc_start.checked_add(align(desc_size)?)?;
+
+        let name = bytes.get(name_start..name_start.checked_add(name_size as usize)?)?;
+        let desc = bytes.get(desc_start..desc_start.checked_add(desc_size as usize)?)?;
+
+        bytes = bytes.get(next..).unwrap_or(&[]);
+        Some(Note { name, ty, desc })
+    })
+}
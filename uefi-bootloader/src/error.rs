@@ -0,0 +1,129 @@
+use crate::{
+    chunked::ChunkedError, context::FileSystemError, cpio::CpioError, elf::ElfError,
+    memory::MapError,
+};
+use core::fmt;
+use uefi::Status;
+use uefi_bootloader_api::Features;
+
+/// An error encountered while loading the kernel or modules.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum BootError {
+    /// The file system the kernel and modules are loaded from couldn't be
+    /// opened.
+    FileSystem(FileSystemError),
+    /// A file doesn't exist.
+    FileNotFound,
+    /// A path refers to a directory rather than a file.
+    NotAFile,
+    /// A path component other than the last refers to a file rather than a
+    /// directory.
+    NotADirectory,
+    /// A path is empty, or has a component that is too long or contains
+    /// characters that can't be represented in UCS-2.
+    InvalidPath,
+    /// A file couldn't be read.
+    ReadFailed(Status),
+    /// A file ended before all of its expected contents were read.
+    UnexpectedEof,
+    /// The kernel isn't an ELF file that the loader supports.
+    InvalidElf(&'static str),
+    /// The module archive is malformed.
+    InvalidArchive(&'static str),
+    /// The firmware ran out of memory.
+    AllocationFailed(Status),
+    /// A protocol needed to load a file isn't supported by the firmware.
+    ProtocolMissing(Status),
+    /// The `http_url` option isn't an `http://` URL, or is too long once the
+    /// path of a file is appended to it.
+    InvalidUrl,
+    /// An HTTP request failed before the server responded, or while the
+    /// response was being received.
+    NetworkFailed(Status),
+    /// The HTTP server responded with a status other than 200 or 404.
+    ///
+    /// This is zero for statuses the firmware doesn't support.
+    HttpStatus(u16),
+    /// The HTTP server's response is malformed.
+    InvalidResponse(&'static str),
+    /// The kernel requires features that the bootloader doesn't provide with
+    /// the current configuration.
+    MissingFeatures(Features),
+}
+
+impl From<FileSystemError> for BootError {
+    fn from(error: FileSystemError) -> Self {
+        Self::FileSystem(error)
+    }
+}
+
+impl From<ElfError> for BootError {
+    fn from(error: ElfError) -> Self {
+        Self::InvalidElf(error.0)
+    }
+}
+
+impl From<CpioError> for BootError {
+    fn from(error: CpioError) -> Self {
+        Self::InvalidArchive(error.0)
+    }
+}
+
+impl From<ChunkedError> for BootError {
+    fn from(error: ChunkedError) -> Self {
+        Self::InvalidResponse(error.0)
+    }
+}
+
+impl From<MapError> for BootError {
+    fn from(error: MapError) -> Self {
+        match error {
+            MapError::FrameAllocationFailed => Self::AllocationFailed(Status::OUT_OF_RESOURCES),
+            MapError::PageAlreadyMapped(_) | MapError::ParentEntryHugePage => {
+                Self::InvalidElf("segment overlaps an existing mapping")
+            }
+            MapError::PageNotMapped => Self::InvalidElf("segment is not mapped"),
+        }
+    }
+}
+
+impl<T: fmt::Debug> From<uefi::Error<T>> for BootError {
+    fn from(error: uefi::Error<T>) -> Self {
+        match error.status() {
+            Status::NOT_FOUND => Self::FileNotFound,
+            Status::OUT_OF_RESOURCES => Self::AllocationFailed(error.status()),
+            Status::UNSUPPORTED => Self::ProtocolMissing(error.status()),
+            status => Self::ReadFailed(status),
+        }
+    }
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileSystem(error) => write!(f, "{error}"),
+            Self::FileNotFound => write!(f, "file not found"),
+            Self::NotAFile => write!(f, "path is a directory, not a file"),
+            Self::NotADirectory => write!(f, "path component is a file, not a directory"),
+            Self::InvalidPath => write!(f, "invalid path"),
+            Self::ReadFailed(status) => write!(f, "failed to read file: {status:?}"),
+            Self::UnexpectedEof => write!(f, "unexpected end of file"),
+            Self::InvalidElf(reason) => write!(f, "invalid ELF file: {reason}"),
+            Self::InvalidArchive(reason) => write!(f, "invalid module archive: {reason}"),
+            Self::AllocationFailed(status) => write!(f, "out of memory: {status:?}"),
+            Self::ProtocolMissing(status) => {
+                write!(f, "required protocol is not supported: {status:?}")
+            }
+            Self::InvalidUrl => write!(f, "invalid URL, expected http://host[:port]/path"),
+            Self::NetworkFailed(status) => write!(f, "HTTP request failed: {status:?}"),
+            Self::HttpStatus(0) => write!(f, "server responded with an unsupported HTTP status"),
+            Self::HttpStatus(code) => write!(f, "server responded with HTTP status {code}"),
+            Self::InvalidResponse(reason) => write!(f, "invalid HTTP response: {reason}"),
+            Self::MissingFeatures(features) => write!(
+                f,
+                "kernel requires bootloader features {:#x}, which are not provided",
+                features.bits()
+            ),
+        }
+    }
+}
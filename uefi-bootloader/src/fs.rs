@@ -0,0 +1,38 @@
+use crate::error::BootError;
+use uefi::{
+    proto::media::file::{Directory, File, FileAttribute, FileHandle, FileMode},
+    CStr16,
+};
+
+/// The maximum length of a path component in UCS-2 characters, which is the
+/// longest file name FAT supports.
+const MAX_COMPONENT_LEN: usize = 255;
+
+/// Opens the file or directory at `path`, relative to `root`.
+///
+/// Components may be separated by `/` or `\`, and empty components are
+/// ignored, so `boot/theseus/kernel.elf`, `\boot\theseus\kernel.elf`, and
+/// `boot\theseus/kernel.elf` are all equivalent.
+pub(crate) fn open_path(root: &mut Directory, path: &str) -> Result<FileHandle, BootError> {
+    let mut components = path
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty());
+    let mut name = components.next().ok_or(BootError::InvalidPath)?;
+
+    let mut dir = None;
+    for next in components {
+        let parent: &mut Directory = dir.as_mut().unwrap_or(&mut *root);
+        let handle = open(parent, name)?;
+        dir = Some(handle.into_directory().ok_or(BootError::NotADirectory)?);
+        name = next;
+    }
+
+    open(dir.as_mut().unwrap_or(root), name)
+}
+
+/// Opens the file or directory called `name` in `dir`.
+fn open(dir: &mut Directory, name: &str) -> Result<FileHandle, BootError> {
+    let mut buffer = [0; MAX_COMPONENT_LEN + 1];
+    let name = CStr16::from_str_with_buf(name, &mut buffer).map_err(|_| BootError::InvalidPath)?;
+    Ok(dir.open(name, FileMode::Read, FileAttribute::empty())?)
+}
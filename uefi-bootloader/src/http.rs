@@ -0,0 +1,539 @@
+//! Downloading of the kernel and modules using the firmware's HTTP protocol,
+//! for which the `uefi` crate has no bindings.
+
+use crate::{chunked, error::BootError, memory::RECLAIMABLE_MEMORY, BootContext};
+use core::{ffi::c_void, ptr};
+use log::{info, warn};
+use uefi::{
+    proto::unsafe_protocol,
+    table::boot::{BootServices, EventType, Tpl},
+    CStr16, Event, Handle, Status,
+};
+
+/// The maximum length of a URL, including the null terminator.
+const MAX_URL_LEN: usize = 512;
+
+/// The maximum length of the host in a URL, including the null terminator.
+const MAX_HOST_LEN: usize = 256;
+
+/// How long the firmware waits for the server before failing a request.
+const TIMEOUT_MILLISECONDS: u32 = 10_000;
+
+/// How many times configuring the protocol is attempted while the network
+/// address isn't yet available.
+const CONFIGURE_ATTEMPTS: usize = 50;
+
+/// How long to wait between attempts to configure the protocol.
+const CONFIGURE_RETRY_MICROSECONDS: usize = 100_000;
+
+/// The size of the buffer initially allocated for a body without a content
+/// length, which is doubled whenever it fills up.
+const INITIAL_BODY_SIZE: usize = 0x10_0000;
+
+/// `EFI_HTTP_VERSION` for HTTP/1.1.
+const HTTP_VERSION_11: u32 = 1;
+
+/// `EFI_IP4_CONFIG2_DATA_TYPE` for the address policy.
+const IP4_CONFIG2_DATA_TYPE_POLICY: u32 = 2;
+
+/// `EFI_IP4_CONFIG2_POLICY` for configuring the address using DHCP.
+const IP4_CONFIG2_POLICY_DHCP: u32 = 1;
+
+/// `EFI_HTTP_METHOD` for `GET`.
+const HTTP_METHOD_GET: u32 = 0;
+
+/// The HTTP status codes in the order of the `EFI_HTTP_STATUS_CODE` values
+/// they correspond to.
+const STATUS_CODES: [u16; 43] = [
+    0, 100, 101, 200, 201, 202, 203, 204, 205, 206, 300, 301, 302, 303, 304, 305, 307, 400, 401,
+    402, 403, 404, 405, 406, 407, 408, 409, 410, 411, 412, 413, 414, 415, 416, 417, 500, 501, 502,
+    503, 504, 505, 308, 429,
+];
+
+/// `EFI_HTTP_SERVICE_BINDING_PROTOCOL`, which creates the HTTP protocol
+/// instances.
+#[repr(C)]
+#[unsafe_protocol("bdc8e6af-d9bc-4379-a72a-e0c4e75dae1c")]
+struct HttpServiceBinding {
+    create_child:
+        unsafe extern "efiapi" fn(this: *mut Self, child_handle: *mut *mut c_void) -> Status,
+    destroy_child: unsafe extern "efiapi" fn(this: *mut Self, child_handle: *mut c_void) -> Status,
+}
+
+/// `EFI_HTTP_PROTOCOL`.
+#[repr(C)]
+#[unsafe_protocol("7a59b29b-910b-4171-8242-a85a0df25b5b")]
+struct Http {
+    get_mode_data: unsafe extern "efiapi" fn(this: *mut Self, data: *mut ConfigData) -> Status,
+    configure: unsafe extern "efiapi" fn(this: *mut Self, data: *const ConfigData) -> Status,
+    request: TokenFn,
+    cancel: TokenFn,
+    response: TokenFn,
+    poll: unsafe extern "efiapi" fn(this: *mut Self) -> Status,
+}
+
+/// The type of [`Http::request`] and [`Http::response`], which start an
+/// operation that completes when the token's event is signalled.
+type TokenFn = unsafe extern "efiapi" fn(this: *mut Http, token: *mut Token) -> Status;
+
+/// `EFI_IP4_CONFIG2_PROTOCOL`, which configures a network interface's IPv4
+/// address.
+#[repr(C)]
+#[unsafe_protocol("5b446ed1-e30b-4faa-871a-3654eca36080")]
+struct Ip4Config2 {
+    set_data: unsafe extern "efiapi" fn(
+        this: *mut Self,
+        data_type: u32,
+        data_size: usize,
+        data: *const c_void,
+    ) -> Status,
+}
+
+/// `EFI_HTTP_CONFIG_DATA`, with an IPv4 access point.
+#[repr(C)]
+struct ConfigData {
+    http_version: u32,
+    timeout_milliseconds: u32,
+    local_address_is_ipv6: bool,
+    access_point: *const Ipv4AccessPoint,
+}
+
+/// `EFI_HTTPv4_ACCESS_POINT`.
+#[repr(C)]
+struct Ipv4AccessPoint {
+    use_default_address: bool,
+    local_address: [u8; 4],
+    local_subnet: [u8; 4],
+    local_port: u16,
+}
+
+/// `EFI_HTTP_TOKEN`.
+#[repr(C)]
+struct Token {
+    event: Event,
+    status: Status,
+    message: *mut Message,
+}
+
+/// `EFI_HTTP_MESSAGE`, whose data is either a [`RequestData`] or a
+/// [`ResponseData`].
+#[repr(C)]
+struct Message {
+    data: *mut c_void,
+    header_count: usize,
+    headers: *mut Header,
+    body_length: usize,
+    body: *mut c_void,
+}
+
+/// `EFI_HTTP_REQUEST_DATA`.
+#[repr(C)]
+struct RequestData {
+    method: u32,
+    url: *const u16,
+}
+
+/// `EFI_HTTP_RESPONSE_DATA`.
+#[repr(C)]
+struct ResponseData {
+    status_code: u32,
+}
+
+/// `EFI_HTTP_HEADER`, whose name and value are null-terminated ASCII strings.
+#[repr(C)]
+struct Header {
+    field_name: *const u8,
+    field_value: *const u8,
+}
+
+impl Header {
+    /// Returns whether the header is called `name`, ignoring case.
+    ///
+    /// # Safety
+    ///
+    /// The header's name must be a valid null-terminated string.
+    unsafe fn is(&self, name: &str) -> bool {
+        // SAFETY: Guaranteed by caller.
+        unsafe { c_str(self.field_name) }.eq_ignore_ascii_case(name.as_bytes())
+    }
+
+    /// Returns the header's value.
+    ///
+    /// # Safety
+    ///
+    /// The header's value must be a valid null-terminated string.
+    unsafe fn value(&self) -> &[u8] {
+        // SAFETY: Guaranteed by caller.
+        unsafe { c_str(self.field_value) }
+    }
+}
+
+impl BootContext {
+    /// Downloads `path` relative to the base URL `url`.
+    ///
+    /// A response with a 404 status is reported as
+    /// [`BootError::FileNotFound`], so that missing files are handled like
+    /// missing files on disk.
+    pub(crate) fn http_get(&self, url: &str, path: &str) -> Result<&'static [u8], BootError> {
+        let mut url_buf = [0; MAX_URL_LEN];
+        let mut host_buf = [0; MAX_HOST_LEN];
+        let (url, host) = request_url(url, path, &mut url_buf, &mut host_buf)?;
+
+        let boot_services = self.system_table.boot_services();
+        let handle = boot_services.get_handle_for_protocol::<HttpServiceBinding>()?;
+        let mut service_binding =
+            boot_services.open_protocol_exclusive::<HttpServiceBinding>(handle)?;
+        let service_binding: *mut HttpServiceBinding = &mut *service_binding;
+
+        let mut child = ptr::null_mut();
+        // SAFETY: The protocol was opened above.
+        check(unsafe { ((*service_binding).create_child)(service_binding, &mut child) })?;
+        // SAFETY: `create_child` succeeded, so `child` is a valid handle.
+        let child_handle = unsafe { Handle::from_ptr(child) }
+            .ok_or(BootError::NetworkFailed(Status::DEVICE_ERROR))?;
+
+        let result = boot_services
+            .open_protocol_exclusive::<Http>(child_handle)
+            .map_err(BootError::from)
+            .and_then(|mut http| self.get(handle, &mut http, url, host));
+        if result.is_ok() {
+            info!("downloaded {url}");
+        }
+
+        // SAFETY: The child was created above, and its protocol was closed when
+        // `http` was dropped.
+        let destroyed =
+            check(unsafe { ((*service_binding).destroy_child)(service_binding, child) });
+        result.and_then(|body| destroyed.map(|()| body))
+    }
+
+    /// Sends a `GET` request for `url` to `host` using the network interface
+    /// `controller`, returning the response body.
+    fn get(
+        &self,
+        controller: Handle,
+        http: &mut Http,
+        url: &CStr16,
+        host: &[u8],
+    ) -> Result<&'static [u8], BootError> {
+        let boot_services = self.system_table.boot_services();
+        let http: *mut Http = http;
+
+        let access_point = Ipv4AccessPoint {
+            use_default_address: true,
+            local_address: [0; 4],
+            local_subnet: [0; 4],
+            local_port: 0,
+        };
+        let config = ConfigData {
+            http_version: HTTP_VERSION_11,
+            timeout_milliseconds: TIMEOUT_MILLISECONDS,
+            local_address_is_ipv6: false,
+            access_point: &access_point,
+        };
+        // SAFETY: `http` is a valid protocol, and `config` is valid for the call.
+        let configure = || unsafe { ((*http).configure)(http, &config) };
+        let mut status = configure();
+        if status == Status::NO_MAPPING {
+            // The default address is only available once it's configured, which
+            // the firmware only does by itself when it was booted over the
+            // network.
+            info!("no network address, starting DHCP");
+            self.start_dhcp(controller);
+            for _ in 0..CONFIGURE_ATTEMPTS {
+                boot_services.stall(CONFIGURE_RETRY_MICROSECONDS);
+                status = configure();
+                if status != Status::NO_MAPPING {
+                    break;
+                }
+            }
+        }
+        check(status)?;
+
+        let request_headers = [
+            Header {
+                field_name: b"Host\0".as_ptr(),
+                field_value: host.as_ptr(),
+            },
+            Header {
+                field_name: b"Accept\0".as_ptr(),
+                field_value: b"*/*\0".as_ptr(),
+            },
+        ];
+        let mut request_data = RequestData {
+            method: HTTP_METHOD_GET,
+            url: url.as_ptr().cast(),
+        };
+        let mut request = Message {
+            data: ptr::addr_of_mut!(request_data).cast(),
+            header_count: request_headers.len(),
+            headers: request_headers.as_ptr().cast_mut(),
+            body_length: 0,
+            body: ptr::null_mut(),
+        };
+        // SAFETY: The protocol was configured above.
+        unsafe { wait(boot_services, http, (*http).request, &mut request) }?;
+
+        // The first response contains the status and headers, and any body
+        // bytes are received by later responses.
+        let mut response_data = ResponseData { status_code: 0 };
+        let mut response = Message {
+            data: ptr::addr_of_mut!(response_data).cast(),
+            header_count: 0,
+            headers: ptr::null_mut(),
+            body_length: 0,
+            body: ptr::null_mut(),
+        };
+        // SAFETY: The request was sent above.
+        unsafe { wait(boot_services, http, (*http).response, &mut response) }?;
+
+        // SAFETY: The firmware returned `header_count` headers with valid
+        // strings.
+        let headers = unsafe { response_headers(&response) };
+        let mut content_length = None;
+        let mut is_chunked = false;
+        for header in headers {
+            // SAFETY: The firmware returned the header with valid strings.
+            unsafe {
+                if header.is("Content-Length") {
+                    content_length = parse_decimal(header.value());
+                } else if header.is("Transfer-Encoding") {
+                    is_chunked = header.value().eq_ignore_ascii_case(b"chunked");
+                }
+            }
+        }
+        if !response.headers.is_null() {
+            // The header strings are owned by the firmware, and are leaked if
+            // it allocated them separately, which is harmless.
+            let _ = boot_services.free_pool(response.headers.cast());
+        }
+
+        match STATUS_CODES.get(response_data.status_code as usize) {
+            Some(&200) => {}
+            Some(&404) => return Err(BootError::FileNotFound),
+            Some(&code) => return Err(BootError::HttpStatus(code)),
+            None => return Err(BootError::HttpStatus(0)),
+        }
+
+        if is_chunked {
+            self.receive_chunked_body(http)
+        } else if let Some(len) = content_length {
+            let body = self.allocate_byte_slice(len, RECLAIMABLE_MEMORY);
+            let mut received = 0;
+            while received < len {
+                // SAFETY: The response headers were received above.
+                received += unsafe { receive(boot_services, http, &mut body[received..]) }?;
+            }
+            Ok(body)
+        } else {
+            Err(BootError::InvalidResponse(
+                "response has neither a content length nor a chunked body",
+            ))
+        }
+    }
+
+    /// Sets the IPv4 address policy of the network interface `controller` to
+    /// DHCP, which starts configuring its address.
+    fn start_dhcp(&self, controller: Handle) {
+        let boot_services = self.system_table.boot_services();
+        let mut ip4_config2 = match boot_services.open_protocol_exclusive::<Ip4Config2>(controller)
+        {
+            Ok(ip4_config2) => ip4_config2,
+            Err(error) => {
+                warn!(
+                    "failed to open IPv4 configuration protocol: {:?}",
+                    error.status()
+                );
+                return;
+            }
+        };
+        let ip4_config2: *mut Ip4Config2 = &mut *ip4_config2;
+        let policy = IP4_CONFIG2_POLICY_DHCP;
+        // SAFETY: The protocol was opened above, and `policy` is valid for the
+        // call.
+        let status = unsafe {
+            ((*ip4_config2).set_data)(
+                ip4_config2,
+                IP4_CONFIG2_DATA_TYPE_POLICY,
+                core::mem::size_of_val(&policy),
+                ptr::addr_of!(policy).cast(),
+            )
+        };
+        // The policy is already DHCP if this was aborted.
+        if !status.is_success() && status != Status::ABORTED {
+            warn!("failed to start DHCP: {status:?}");
+        }
+    }
+
+    /// Receives a chunked body, growing the buffer it's received into as
+    /// needed, and returns the decoded body.
+    fn receive_chunked_body(&self, http: *mut Http) -> Result<&'static [u8], BootError> {
+        let boot_services = self.system_table.boot_services();
+        let mut body = self.allocate_byte_slice(INITIAL_BODY_SIZE, RECLAIMABLE_MEMORY);
+        let mut received = 0;
+        loop {
+            if received == body.len() {
+                let larger = self.allocate_byte_slice(body.len() * 2, RECLAIMABLE_MEMORY);
+                larger[..received].copy_from_slice(body);
+                // SAFETY: `body` was returned by `allocate_byte_slice`, and
+                // isn't used again.
+                unsafe { self.free_byte_slice(body) };
+                body = larger;
+            }
+            // SAFETY: The response headers were received by the caller.
+            received += unsafe { receive(boot_services, http, &mut body[received..]) }?;
+
+            if chunked::decoded_len(&body[..received])?.is_some() {
+                let len = chunked::decode_in_place(&mut body[..received])?;
+                return Ok(&body[..len]);
+            }
+        }
+    }
+}
+
+/// Returns the null-terminated UCS-2 URL of `path` relative to the base URL
+/// `url`, and the null-terminated host of `url`, which are written to the
+/// buffers.
+fn request_url<'a>(
+    url: &str,
+    path: &str,
+    url_buf: &'a mut [u16; MAX_URL_LEN],
+    host_buf: &'a mut [u8; MAX_HOST_LEN],
+) -> Result<(&'a CStr16, &'a [u8]), BootError> {
+    let rest = url.strip_prefix("http://").ok_or(BootError::InvalidUrl)?;
+    let host = rest.split('/').next().unwrap_or(rest);
+    if host.is_empty() || host.len() >= host_buf.len() {
+        return Err(BootError::InvalidUrl);
+    }
+    host_buf[..host.len()].copy_from_slice(host.as_bytes());
+
+    let path = path.trim_start_matches(['/', '\\']);
+    let url = url.trim_end_matches('/');
+    let chars = url.chars().chain(['/']).chain(path.chars());
+    let mut len = 0;
+    for c in chars {
+        // Paths may use either separator, like on the ESP.
+        let c = if c == '\\' { '/' } else { c };
+        if !c.is_ascii_graphic() || len + 1 >= url_buf.len() {
+            return Err(BootError::InvalidUrl);
+        }
+        url_buf[len] = c as u16;
+        len += 1;
+    }
+
+    let url = CStr16::from_u16_with_nul(&url_buf[..=len]).map_err(|_| BootError::InvalidUrl)?;
+    Ok((url, &host_buf[..=host.len()]))
+}
+
+/// Receives the next part of a response body into `buffer`, returning the
+/// number of bytes received.
+///
+/// # Safety
+///
+/// `http` must be a valid protocol that has received the response headers.
+unsafe fn receive(
+    boot_services: &BootServices,
+    http: *mut Http,
+    buffer: &mut [u8],
+) -> Result<usize, BootError> {
+    let mut message = Message {
+        data: ptr::null_mut(),
+        header_count: 0,
+        headers: ptr::null_mut(),
+        body_length: buffer.len(),
+        body: buffer.as_mut_ptr().cast(),
+    };
+    // SAFETY: Guaranteed by caller.
+    unsafe { wait(boot_services, http, (*http).response, &mut message) }?;
+    if message.body_length == 0 {
+        return Err(BootError::InvalidResponse(
+            "connection closed before the body ended",
+        ));
+    }
+    Ok(message.body_length.min(buffer.len()))
+}
+
+/// Starts an operation for `message` using `operation`, and polls `http` until
+/// it completes.
+///
+/// # Safety
+///
+/// `http` must be a valid protocol, and `operation` one of its functions.
+unsafe fn wait(
+    boot_services: &BootServices,
+    http: *mut Http,
+    operation: TokenFn,
+    message: &mut Message,
+) -> Result<(), BootError> {
+    // SAFETY: The event has no notification function.
+    let event =
+        unsafe { boot_services.create_event(EventType::empty(), Tpl::CALLBACK, None, None) }
+            .map_err(|error| BootError::NetworkFailed(error.status()))?;
+    let mut token = Token {
+        // SAFETY: The event is only closed after the operation completes.
+        event: unsafe { event.unsafe_clone() },
+        status: Status::NOT_READY,
+        message,
+    };
+
+    // SAFETY: Guaranteed by caller, and `token` outlives the operation.
+    let mut result = check(unsafe { operation(http, &mut token) });
+    if result.is_ok() {
+        loop {
+            // SAFETY: Guaranteed by caller.
+            unsafe { ((*http).poll)(http) };
+            // SAFETY: The event is still open.
+            match boot_services.check_event(unsafe { event.unsafe_clone() }) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(error) => {
+                    result = Err(BootError::NetworkFailed(error.status()));
+                    break;
+                }
+            }
+        }
+        result = result.and_then(|()| check(token.status));
+    }
+
+    let _ = boot_services.close_event(event);
+    result
+}
+
+/// Returns the headers of `response`.
+///
+/// # Safety
+///
+/// The firmware must have returned `header_count` headers in `response`.
+unsafe fn response_headers(response: &Message) -> &[Header] {
+    if response.headers.is_null() {
+        return &[];
+    }
+    // SAFETY: Guaranteed by caller.
+    unsafe { core::slice::from_raw_parts(response.headers, response.header_count) }
+}
+
+/// Returns the bytes of the null-terminated string at `pointer`, without the
+/// null terminator.
+///
+/// # Safety
+///
+/// `pointer` must point to a valid null-terminated string that isn't freed
+/// while the bytes are used.
+unsafe fn c_str(pointer: *const u8) -> &'static [u8] {
+    // SAFETY: Guaranteed by caller.
+    unsafe { core::ffi::CStr::from_ptr(pointer.cast()) }.to_bytes()
+}
+
+/// Parses a content length.
+fn parse_decimal(value: &[u8]) -> Option<usize> {
+    core::str::from_utf8(value).ok()?.trim().parse().ok()
+}
+
+/// Converts `status` to a result, treating failures as network failures.
+fn check(status: Status) -> Result<(), BootError> {
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(BootError::NetworkFailed(status))
+    }
+}
@@ -1,157 +1,930 @@
-use crate::{memory::VirtualAddress, BootContext};
-use core::mem::MaybeUninit;
-use goblin::elf64::{
-    header::Header,
-    program_header::{ProgramHeader, SIZEOF_PHDR},
-    section_header::{SectionHeader, SIZEOF_SHDR},
+use crate::{
+    arch,
+    config::Config,
+    elf,
+    error::BootError,
+    fs::open_path,
+    logger::report_progress,
+    memory::{
+        Page, PageRange, PteFlags, VirtualAddress, HUGE_PAGE_SIZE, PAGE_SIZE, RECLAIMABLE_MEMORY,
+    },
+    util::{assume_all_written, assume_written},
+    BootContext,
 };
-use log::info;
-use plain::Plain;
-use uefi::{
-    prelude::cstr16,
-    proto::media::file::{File, FileAttribute, FileMode, FileType, RegularFile},
-    table::boot::MemoryType,
-    CStr16,
+use core::{cmp::min, iter};
+use goblin::elf64::{
+    dynamic::{Dyn, DT_NULL, DT_RELA, DT_RELAENT, DT_RELASZ, SIZEOF_DYN},
+    header::{Header, ET_DYN, SIZEOF_EHDR},
+    program_header::{
+        pt_to_str, ProgramHeader, PF_W, PF_X, PT_DYNAMIC, PT_GNU_EH_FRAME, PT_GNU_RELRO,
+        PT_GNU_STACK, PT_LOAD, PT_NOTE, SIZEOF_PHDR,
+    },
+    reloc::{r_type, Rela, SIZEOF_RELA},
+    section_header::{SectionHeader, SHF_ALLOC, SHT_NOBITS, SHT_SYMTAB, SIZEOF_SHDR},
 };
-use uefi_bootloader_api::ElfSection;
+use log::{debug, info, warn};
+use uefi::proto::media::file::RegularFile;
+use uefi_bootloader_api::{ElfSection, Features, FEATURES_NOTE_NAME, NT_REQUIRED_FEATURES};
+
+/// The maximum number of bytes read from the kernel file at once.
+///
+/// Some firmware fails or slows down considerably when reading large buffers.
+const READ_CHUNK_SIZE: usize = 0x10_0000;
+
+/// The virtual address at which relocatable kernels are loaded if no base is
+/// configured.
+const DEFAULT_KERNEL_BASE: u64 = 0xffff_ffff_8000_0000;
+
+/// The lowest virtual address at which KASLR loads relocatable kernels, which
+/// is the start of the last top-level page table entry.
+///
+/// The stack, framebuffer, and boot information are allocated after the kernel
+/// is loaded, in top-level entries the kernel doesn't occupy, and the identity
+/// mapping lies in the lower half, so none of them can collide with the kernel.
+const KASLR_MIN_BASE: u64 = 0xffff_ff80_0000_0000;
+
+/// The highest virtual address at which KASLR loads relocatable kernels, which
+/// leaves 2 GiB for the kernel image.
+const KASLR_MAX_BASE: u64 = DEFAULT_KERNEL_BASE;
+
+/// The alignment of kernel bases chosen by KASLR, which preserves the alignment
+/// of segments aligned to at most a huge page.
+const KASLR_ALIGN: u64 = HUGE_PAGE_SIZE as u64;
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        const RELATIVE_RELOCATION: u32 = goblin::elf64::reloc::R_X86_64_RELATIVE;
+        const KERNEL_MACHINE: u16 = goblin::elf64::header::EM_X86_64;
+    } else {
+        const RELATIVE_RELOCATION: u32 = goblin::elf64::reloc::R_AARCH64_RELATIVE;
+        const KERNEL_MACHINE: u16 = goblin::elf64::header::EM_AARCH64;
+    }
+}
+
+/// A kernel loaded into memory.
+pub(crate) struct Kernel {
+    pub(crate) entry_point: VirtualAddress,
+    pub(crate) elf_sections: &'static mut [ElfSection],
+    pub(crate) symbols: Option<Symbols>,
+    pub(crate) section_contents: &'static [SectionContents],
+    /// Whether the kernel's stack must be executable, as requested by its
+    /// `PT_GNU_STACK` program header.
+    pub(crate) executable_stack: bool,
+    /// The kernel's GNU build ID, if it has a 20 byte one.
+    pub(crate) build_id: Option<[u8; 20]>,
+    /// The bootloader features the kernel requires, as declared by its
+    /// `NT_REQUIRED_FEATURES` note.
+    pub(crate) required_features: Features,
+    /// The offset added to the kernel's link addresses.
+    pub(crate) load_bias: u64,
+    /// The kernel's loadable segments.
+    pub(crate) segments: &'static [LoadedSegment],
+    /// The pages made read-only by the kernel's `PT_GNU_RELRO` program
+    /// header.
+    pub(crate) relro_pages: Option<PageRange>,
+}
+
+/// The contents of a kernel section named by the `sections` option.
+///
+/// The contents are copied into the boot info, so they can be overwritten by
+/// the kernel.
+pub(crate) struct SectionContents {
+    pub(crate) name: [u8; 64],
+    pub(crate) bytes: &'static [u8],
+}
 
-const KERNEL_NAME: &CStr16 = cstr16!("kernel.elf");
+/// The kernel's symbol table and its associated string table.
+///
+/// The slices are copied into the boot info, so they can be overwritten by the
+/// kernel.
+pub(crate) struct Symbols {
+    pub(crate) symtab: &'static [u8],
+    pub(crate) strtab: &'static [u8],
+}
 
 impl BootContext {
-    pub(crate) fn load_kernel(&mut self) -> (VirtualAddress, &'static mut [ElfSection]) {
-        let mut root = self
-            .open_file_system_root()
-            .expect("failed to open file system root");
-
-        let file = match root
-            .open(KERNEL_NAME, FileMode::Read, FileAttribute::empty())
-            .expect("failed to open kernel file")
-            .into_type()
-            .expect("kernel file was closed or deleted")
-        {
-            FileType::Regular(file) => file,
-            FileType::Dir(_) => panic!(),
-        };
+    /// Loads the kernel from the configured path.
+    ///
+    /// If that kernel is missing, can't be opened, or is invalid, the
+    /// `kernel_fallback` path is tried instead. Only if neither file exists is
+    /// the kernel downloaded over TFTP, unless the kernel is downloaded over
+    /// HTTP. Otherwise, the last error other than a missing file is returned.
+    pub(crate) fn load_kernel(&mut self, config: &Config) -> Result<Kernel, BootError> {
+        let mut invalid = None;
+        for path in iter::once(config.kernel_path).chain(config.kernel_fallback) {
+            let file = match self.open_elf(path, config) {
+                Ok(file) => file,
+                Err(error) => {
+                    warn!("failed to open kernel {path} ({error})");
+                    if !matches!(error, BootError::FileNotFound) || config.http_url.is_some() {
+                        invalid = Some(error);
+                    }
+                    continue;
+                }
+            };
+            let mut loader = self.loader(file, config);
+            // Nothing has been mapped yet, so another kernel can still be
+            // loaded if this one is rejected.
+            match loader.validate() {
+                Ok(()) => {
+                    info!("booting kernel {path}");
+                    return loader.load();
+                }
+                Err(error) => {
+                    warn!("kernel {path} is invalid ({error})");
+                    invalid = Some(error);
+                }
+            }
+        }
+        if let Some(error) = invalid {
+            return Err(error);
+        }
+
+        warn!("no kernel file found, trying TFTP");
+        let bytes = self.download_kernel()?;
+        self.load_from(Source::Memory { bytes, position: 0 }, config)
+    }
 
+    /// Loads the ELF image at `path` and maps its segments.
+    ///
+    /// The image is loaded from the partition configured for the kernel, or
+    /// downloaded if the `http_url` option is set.
+    pub(crate) fn load_elf(&mut self, path: &str, config: &Config) -> Result<Kernel, BootError> {
+        let file = self.open_elf(path, config)?;
+        self.load_from(file, config)
+    }
+
+    /// Opens the ELF image at `path`, downloading it if the `http_url` option
+    /// is set.
+    fn open_elf(&self, path: &str, config: &Config) -> Result<Source, BootError> {
+        if let Some(url) = config.http_url {
+            let bytes = self.http_get(url, path)?;
+            return Ok(Source::Memory { bytes, position: 0 });
+        }
+
+        let mut root = self.open_kernel_root(config)?;
+        open_path(&mut root, path)?
+            .into_regular_file()
+            .map(Source::File)
+            .ok_or(BootError::NotAFile)
+    }
+
+    fn load_from(&mut self, file: Source, config: &Config) -> Result<Kernel, BootError> {
+        self.loader(file, config).load()
+    }
+
+    fn loader(&mut self, file: Source, config: &Config) -> Loader<'_> {
         Loader {
             file,
             context: self,
+            load_symbols: config.symbols,
+            section_names: config.sections,
+            huge_pages: config.huge_pages,
+            kernel_base: config.kernel_base,
+            kaslr: config.kaslr,
+            file_size: 0,
+            link_base: 0,
+            base: 0,
+            symtab_header: None,
+        }
+    }
+}
+
+/// The source the kernel is loaded from.
+enum Source {
+    File(RegularFile),
+    Memory {
+        bytes: &'static [u8],
+        position: usize,
+    },
+}
+
+impl Source {
+    fn set_position(&mut self, position: u64) -> uefi::Result {
+        match self {
+            Self::File(file) => file.set_position(position),
+            Self::Memory {
+                position: current, ..
+            } => {
+                *current = position as usize;
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the size of the source in bytes.
+    ///
+    /// This moves the file position.
+    fn len(&mut self) -> uefi::Result<u64> {
+        match self {
+            Self::File(file) => {
+                file.set_position(RegularFile::END_OF_FILE)?;
+                file.get_position()
+            }
+            Self::Memory { bytes, .. } => Ok(bytes.len() as u64),
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> uefi::Result<usize, Option<usize>> {
+        match self {
+            Self::File(file) => file.read(buffer),
+            Self::Memory { bytes, position } => {
+                let remaining = bytes.get(*position..).unwrap_or(&[]);
+                let len = min(remaining.len(), buffer.len());
+                buffer[..len].copy_from_slice(&remaining[..len]);
+                *position += len;
+                Ok(len)
+            }
         }
-        .load()
     }
 }
 
 struct Loader<'a> {
-    file: RegularFile,
+    file: Source,
     context: &'a mut BootContext,
+    load_symbols: bool,
+    section_names: &'static str,
+    huge_pages: bool,
+    kernel_base: Option<u64>,
+    kaslr: bool,
+    /// The size of the kernel file, which the offsets in its headers are
+    /// checked against.
+    file_size: u64,
+    /// The link address that is loaded at [`base`](Self::base), which is the
+    /// lowest address of the kernel's loadable segments rounded down to a huge
+    /// page.
+    ///
+    /// This is zero unless the kernel is relocatable.
+    link_base: u64,
+    /// The address the kernel's [`link_base`](Self::link_base) is loaded at.
+    ///
+    /// This is zero unless the kernel is relocatable.
+    base: u64,
+    symtab_header: Option<SectionHeader>,
+}
+
+/// A loaded segment of the kernel.
+pub(crate) struct LoadedSegment {
+    /// The virtual address of the start of the segment.
+    pub(crate) start: u64,
+    /// The segment's contents, starting at `start`.
+    pub(crate) bytes: &'static mut [u8],
+    pub(crate) is_executable: bool,
+    pub(crate) is_writable: bool,
+}
+
+impl LoadedSegment {
+    pub(crate) fn end(&self) -> u64 {
+        self.start + self.bytes.len() as u64
+    }
 }
 
 impl Loader<'_> {
-    fn load(mut self) -> (VirtualAddress, &'static mut [ElfSection]) {
-        let mut buffer = [0; core::mem::size_of::<Header>()];
-        self.file
-            .read(&mut buffer)
-            .expect("failed to read kernel header");
+    /// Checks the ELF header and program headers without mapping anything.
+    ///
+    /// This catches everything `load` would reject based on the program
+    /// headers alone, so that another kernel can be tried instead.
+    fn validate(&mut self) -> Result<(), BootError> {
+        let header = self.read_header()?;
+
+        let program_header_bytes =
+            self.read_bytes(header.e_phoff, usize::from(header.e_phnum) * SIZEOF_PHDR)?;
+        let result = check_program_headers(program_header_bytes, self.file_size, header.e_entry);
+        // SAFETY: The bytes were returned by `read_bytes`, and aren't used
+        // again.
+        unsafe { self.context.free_byte_slice(program_header_bytes) };
+        result
+    }
+
+    /// Reads and checks the ELF header, and records the size of the file.
+    fn read_header(&mut self) -> Result<Header, BootError> {
+        let mut buffer = [0; SIZEOF_EHDR];
+        self.file.set_position(0)?;
+        self.read_exact(&mut buffer)?;
+
+        self.file_size = self.file.len()?;
+        let header = elf::parse_header(&buffer, self.file_size)?;
+        if header.e_machine != KERNEL_MACHINE {
+            return Err(BootError::InvalidElf("built for a different architecture"));
+        }
+        Ok(header)
+    }
 
-        let kernel_header = Header::from_bytes(&buffer);
+    fn load(mut self) -> Result<Kernel, BootError> {
+        let kernel_header = &self.read_header()?;
 
         let program_header_offset = kernel_header.e_phoff;
         let program_header_count = kernel_header.e_phnum;
 
+        // Like with `KASLR_ALIGN`, rounding the link base down to a huge page
+        // preserves the alignment of segments aligned to at most a huge page.
+        if kernel_header.e_type == ET_DYN {
+            self.link_base = self.lowest_load_address(kernel_header)? / KASLR_ALIGN * KASLR_ALIGN;
+        }
+        self.base = self.base(kernel_header)?;
+        let entry_point = self.load_address(kernel_header.e_entry)?;
+        let mut is_entry_point_loaded = false;
+
+        let segments = self
+            .context
+            .allocate_slice(program_header_count.into(), RECLAIMABLE_MEMORY);
+        let mut num_segments = 0;
+        let mut dynamic_header = None;
+        let mut relro_header = None;
+        // Without a PT_GNU_STACK program header, the stack is not executable.
+        let mut executable_stack = false;
+        let mut build_id = None;
+        let mut required_features = Features::empty();
+
         let mut buffer = [0; SIZEOF_PHDR];
 
         for i in 0..program_header_count.into() {
             // Loading segments modifies the file position.
             self.file
-                .set_position(program_header_offset + (i * SIZEOF_PHDR as u64))
-                .expect("failed to set kernel file position to program header");
-            self.file
-                .read(&mut buffer)
-                .expect("failed to read kernel program header");
+                .set_position(program_header_offset + (i * SIZEOF_PHDR as u64))?;
+            self.read_exact(&mut buffer)?;
+
+            let program_header = &elf::parse_program_header(&buffer, self.file_size)?;
+
+            match program_header.p_type {
+                // .got section
+                PT_LOAD if program_header.p_memsz == 0 => {}
+                PT_LOAD => {
+                    let mut segment = *program_header;
+                    segment.p_vaddr = self.load_address(segment.p_vaddr)?;
+
+                    let segment_range = segment.p_vaddr
+                        ..self.load_address(program_header.p_vaddr + program_header.p_memsz)?;
+                    // SAFETY: The first `num_segments` segments were initialised.
+                    let loaded = unsafe { assume_written(segments, num_segments) };
+                    if loaded.iter().any(|other| {
+                        other.start < segment_range.end && segment_range.start < other.end()
+                    }) {
+                        return Err(BootError::InvalidElf("segments overlap"));
+                    }
+
+                    let bytes = self.handle_load_segment(&segment)?;
+                    let is_executable = segment.p_flags & PF_X != 0;
+                    segments[num_segments].write(LoadedSegment {
+                        start: segment.p_vaddr,
+                        bytes,
+                        is_executable,
+                        is_writable: segment.p_flags & PF_W != 0,
+                    });
+                    num_segments += 1;
+
+                    if is_executable && segment_range.contains(&entry_point) {
+                        is_entry_point_loaded = true;
+                    }
+                }
+                PT_DYNAMIC => dynamic_header = Some(*program_header),
+                PT_GNU_RELRO => relro_header = Some(*program_header),
+                PT_GNU_STACK => {
+                    executable_stack = program_header.p_flags & PF_X != 0;
+                    if executable_stack {
+                        warn!("kernel requires an executable stack");
+                    }
+                }
+                PT_NOTE => {
+                    self.read_notes(program_header, &mut build_id, &mut required_features)?;
+                }
+                // The kernel finds its unwinding information using its section headers.
+                PT_GNU_EH_FRAME => debug!("ignoring PT_GNU_EH_FRAME program header"),
+                p_type => debug!("ignoring {} program header", pt_to_str(p_type)),
+            }
+
+            report_progress(
+                "loading kernel",
+                i as usize + 1,
+                program_header_count.into(),
+            );
+        }
+
+        // Jumping to an unmapped entry point would triple fault.
+        if !is_entry_point_loaded {
+            return Err(BootError::InvalidElf(
+                "entry point is not in an executable segment",
+            ));
+        }
+
+        // SAFETY: The first `num_segments` segments were initialised.
+        let segments = unsafe { assume_written(segments, num_segments) };
+        if self.load_bias() != 0 {
+            if let Some(dynamic_header) = dynamic_header {
+                self.relocate(&dynamic_header, segments)?;
+            }
+        }
+        let relro_pages = match relro_header {
+            Some(relro_header) => self.protect_relro(&relro_header)?,
+            None => None,
+        };
+
+        for segment in segments.iter().filter(|segment| segment.is_executable) {
+            arch::clean_and_invalidate(segment.bytes);
+        }
+
+        let (elf_sections, section_contents) = self.elf_sections(kernel_header)?;
+        let symbols = if self.load_symbols {
+            self.symbols(kernel_header)?
+        } else {
+            None
+        };
+
+        Ok(Kernel {
+            entry_point: VirtualAddress::new_canonical(entry_point as usize),
+            elf_sections,
+            symbols,
+            section_contents,
+            executable_stack,
+            build_id,
+            required_features,
+            load_bias: self.load_bias(),
+            segments,
+            relro_pages,
+        })
+    }
+
+    /// Returns the lowest address of the kernel's loadable segments.
+    fn lowest_load_address(&mut self, header: &Header) -> Result<u64, BootError> {
+        let mut buffer = [0; SIZEOF_PHDR];
+        let mut lowest = None;
+        self.file.set_position(header.e_phoff)?;
+        for _ in 0..header.e_phnum {
+            self.read_exact(&mut buffer)?;
+            let program_header = elf::parse_program_header(&buffer, self.file_size)?;
+            if program_header.p_type == PT_LOAD && program_header.p_memsz != 0 {
+                let vaddr = program_header.p_vaddr;
+                lowest = Some(lowest.map_or(vaddr, |lowest: u64| lowest.min(vaddr)));
+            }
+        }
+        lowest.ok_or(BootError::InvalidElf("no loadable segments"))
+    }
+
+    /// Returns the address to load the kernel's [`link_base`](Self::link_base)
+    /// at.
+    fn base(&self, header: &Header) -> Result<u64, BootError> {
+        if header.e_type != ET_DYN {
+            if self.kernel_base.is_some() {
+                warn!("kernel is not relocatable, ignoring configured kernel base");
+            }
+            if self.kaslr {
+                warn!("kernel is not relocatable, ignoring KASLR");
+            }
+            return Ok(0);
+        }
+
+        let base = if self.kaslr {
+            if self.kernel_base.is_some() {
+                warn!("KASLR is enabled, ignoring configured kernel base");
+            }
+            match self.context.random_u64() {
+                Ok(random) => kaslr_base(random),
+                Err(error) => {
+                    warn!("failed to get random number ({error:?}), not randomising kernel base");
+                    self.kernel_base.unwrap_or(DEFAULT_KERNEL_BASE)
+                }
+            }
+        } else {
+            self.kernel_base.unwrap_or(DEFAULT_KERNEL_BASE)
+        };
+        if base % PAGE_SIZE as u64 != 0 {
+            return Err(BootError::InvalidElf(
+                "configured kernel base is not page aligned",
+            ));
+        }
+        info!("loading relocatable kernel at {base:#x}");
+        Ok(base)
+    }
+
+    /// Returns the address that the kernel's link address `address` is loaded
+    /// at.
+    fn load_address(&self, address: u64) -> Result<u64, BootError> {
+        address
+            .checked_sub(self.link_base)
+            .and_then(|offset| offset.checked_add(self.base))
+            .ok_or(BootError::InvalidElf(
+                "address is outside the relocated kernel",
+            ))
+    }
 
-            let program_header = ProgramHeader::from_bytes(&buffer)
-                .expect("failed to create program header from bytes");
+    /// Returns the offset added to the kernel's link addresses, modulo 2^64.
+    fn load_bias(&self) -> u64 {
+        self.base.wrapping_sub(self.link_base)
+    }
+
+    /// Applies the kernel's dynamic relocations.
+    ///
+    /// Only relative relocations are supported, as the kernel has no symbols to
+    /// resolve against.
+    fn relocate(
+        &self,
+        dynamic_header: &ProgramHeader,
+        segments: &mut [LoadedSegment],
+    ) -> Result<(), BootError> {
+        let mut rela_address = None;
+        let mut rela_size = 0;
+        let mut rela_entry_size = SIZEOF_RELA as u64;
 
-            // .got section
-            if program_header.p_memsz == 0 {
-                continue;
+        let dynamic = segment_bytes(
+            segments,
+            self.load_address(dynamic_header.p_vaddr)?,
+            dynamic_header.p_filesz,
+        )?;
+        for bytes in dynamic.chunks_exact(SIZEOF_DYN) {
+            let mut entry = Dyn::default();
+            plain::copy_from_bytes(&mut entry, bytes)
+                .map_err(|_| BootError::InvalidElf("truncated dynamic entry"))?;
+            match entry.d_tag {
+                DT_NULL => break,
+                DT_RELA => rela_address = Some(self.load_address(entry.d_val)?),
+                DT_RELASZ => rela_size = entry.d_val,
+                DT_RELAENT => rela_entry_size = entry.d_val,
+                _ => {}
             }
+        }
+
+        let rela_address = match rela_address {
+            Some(address) => address,
+            None => return Ok(()),
+        };
+        if rela_entry_size != SIZEOF_RELA as u64 {
+            return Err(BootError::InvalidElf("unsupported relocation entry size"));
+        }
 
-            if program_header.p_type == 1 {
-                self.handle_load_segment(program_header);
+        for i in 0..(rela_size / rela_entry_size) {
+            let mut rela = Rela::default();
+            // A saturated address is rejected by `segment_bytes`.
+            let address = rela_address.saturating_add(i * rela_entry_size);
+            let bytes = segment_bytes(segments, address, rela_entry_size)?;
+            plain::copy_from_bytes(&mut rela, bytes)
+                .map_err(|_| BootError::InvalidElf("truncated relocation"))?;
+
+            if r_type(rela.r_info) != RELATIVE_RELOCATION {
+                return Err(BootError::InvalidElf(
+                    "unsupported relocation type, only relative relocations are supported",
+                ));
             }
+
+            let value = self.load_bias().wrapping_add_signed(rela.r_addend);
+            segment_bytes(segments, self.load_address(rela.r_offset)?, 8)?
+                .copy_from_slice(&value.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    /// Makes the pages covered by the kernel's relocation read-only segment
+    /// read-only, returning them.
+    fn protect_relro(
+        &mut self,
+        relro_header: &ProgramHeader,
+    ) -> Result<Option<PageRange>, BootError> {
+        let start = self.load_address(relro_header.p_vaddr)?;
+        let end = start
+            .checked_add(relro_header.p_memsz)
+            .ok_or(BootError::InvalidElf(
+                "relocation read-only segment extends beyond the end of the address space",
+            ))?;
+        let start = VirtualAddress::new_canonical(start as usize);
+        let end = VirtualAddress::new_canonical(end as usize);
+
+        // Like the dynamic linker, round both ends down, as the page containing the
+        // end of the segment may also contain writable data.
+        let start_page = Page::containing_address(start);
+        let end_page = Page::containing_address(end);
+        if start_page == end_page {
+            return Ok(None);
         }
 
-        (
-            VirtualAddress::new_canonical(kernel_header.e_entry as usize),
-            self.elf_sections(kernel_header),
-        )
+        info!("making {start:?}..{end:?} read-only");
+        let pages = PageRange::new(start_page, end_page - 1);
+        for page in pages.clone() {
+            self.context
+                .mapper
+                .update_flags(page, PteFlags::new().present(true).no_execute(true))
+                .map_err(|_| {
+                    BootError::InvalidElf(
+                        "relocation read-only segment is not mapped with 4 KiB pages",
+                    )
+                })?;
+        }
+        Ok(Some(pages))
     }
 
-    fn elf_sections(&mut self, header: &Header) -> &'static mut [ElfSection] {
-        let program_header_count = header.e_shnum;
+    /// Returns the kernel's sections, and the contents of those named by the
+    /// `sections` option.
+    fn elf_sections(
+        &mut self,
+        header: &Header,
+    ) -> Result<(&'static mut [ElfSection], &'static [SectionContents]), BootError> {
+        let section_count = usize::from(header.e_shnum);
 
-        // This slice is copied into another slice in the bootloader, so this slice can
+        // These slices are copied into other slices in the bootloader, so they can
         // be overwritten by the kernel.
         let sections = self
             .context
-            .allocate_slice(program_header_count as usize, MemoryType::LOADER_DATA);
-        let mut buffer = [0; SIZEOF_SHDR];
+            .allocate_slice(section_count, RECLAIMABLE_MEMORY);
+        let contents = self
+            .context
+            .allocate_slice(section_count, RECLAIMABLE_MEMORY);
+        let mut contents_written = 0;
 
-        let shstrtab_header = header.e_shoff + (u64::from(header.e_shstrndx) * SIZEOF_SHDR as u64);
-        self.file
-            .set_position(shstrtab_header)
-            .expect("failed to set kernel file position to shstrtab header");
-        self.file
-            .read(&mut buffer)
-            .expect("failed to read kernel shstrtab header");
-        let shstrtab_section_header =
-            SectionHeader::from_bytes(&buffer).expect("failed to create section header from bytes");
-        let shstrtab_base = shstrtab_section_header.sh_offset;
-
-        for (i, uninit_section) in sections.iter_mut().enumerate() {
-            self.file
-                .set_position(header.e_shoff + (i * SIZEOF_SHDR) as u64)
-                .expect("failed to set kernel file position to section header");
-            self.file
-                .read(&mut buffer)
-                .expect("failed to read kernel section header");
-            let section_header = SectionHeader::from_bytes(&buffer)
-                .expect("failed to create section header from bytes");
+        // Every file operation goes through the firmware, so the section headers and
+        // names are read in bulk rather than seeking to each one.
+        let section_header_bytes = self.read_bytes(header.e_shoff, section_count * SIZEOF_SHDR)?;
+        let file_size = self.file_size;
+        let section_headers = section_header_bytes
+            .chunks_exact(SIZEOF_SHDR)
+            .map(|bytes| elf::parse_section_header(bytes, file_size));
+        let shstrtab = match section_headers
+            .clone()
+            .nth(usize::from(header.e_shstrndx))
+            .transpose()?
+        {
+            Some(shstrtab_header) => {
+                self.read_bytes(shstrtab_header.sh_offset, shstrtab_header.sh_size as usize)?
+            }
+            None => &mut [][..],
+        };
+
+        let mut sections_written = 0;
+        for (section_header, uninit_section) in section_headers.zip(sections.iter_mut()) {
+            let section_header = &section_header?;
+            if section_header.sh_type == SHT_SYMTAB {
+                self.symtab_header = Some(*section_header);
+            }
 
             let mut name = [0; 64];
-            let name_position = shstrtab_base + u64::from(section_header.sh_name);
-            self.file
-                .set_position(name_position)
-                .expect("failed to set kernel file position to shstrab name position");
-            self.file
-                .read(&mut name)
-                .expect("failed to read kernel section name");
+            let name_bytes = shstrtab
+                .get(section_header.sh_name as usize..)
+                .unwrap_or(&[]);
+            let name_bytes = &name_bytes[..name_bytes
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(name_bytes.len())];
+            let name_len = name_bytes.len().min(name.len());
+            name[..name_len].copy_from_slice(&name_bytes[..name_len]);
+
+            if self.is_section_requested(name_bytes) {
+                let bytes = if section_header.sh_type == SHT_NOBITS {
+                    self.context
+                        .allocate_byte_slice(section_header.sh_size as usize, RECLAIMABLE_MEMORY)
+                } else {
+                    self.read_bytes(section_header.sh_offset, section_header.sh_size as usize)?
+                };
+                contents[contents_written].write(SectionContents { name, bytes });
+                contents_written += 1;
+            }
+
+            let start = if section_header.sh_flags & u64::from(SHF_ALLOC) == 0 {
+                section_header.sh_addr
+            } else {
+                section_header.sh_addr.wrapping_add(self.load_bias())
+            };
 
             uninit_section.write(ElfSection {
                 name,
-                start: section_header.sh_addr as usize,
+                start: start as usize,
                 size: section_header.sh_size as usize,
                 flags: section_header.sh_flags,
             });
+            sections_written += 1;
         }
 
-        // SAFETY: We initialised the sections.
-        unsafe { MaybeUninit::slice_assume_init_mut(sections) }
+        // SAFETY: Both slices were returned by `read_bytes`, which uses
+        // `allocate_byte_slice`.
+        unsafe {
+            self.context.free_byte_slice(shstrtab);
+            self.context.free_byte_slice(section_header_bytes);
+        }
+
+        // SAFETY: We initialised the first `sections_written` sections and the
+        // first `contents_written` contents. Only the requested sections have
+        // contents, so `contents` is usually only partly written.
+        unsafe {
+            Ok((
+                assume_all_written(sections, sections_written),
+                assume_written(contents, contents_written),
+            ))
+        }
     }
 
-    fn handle_load_segment(&mut self, segment: &ProgramHeader) {
+    /// Returns whether the contents of the section called `name` were requested
+    /// by the `sections` option.
+    fn is_section_requested(&self, name: &[u8]) -> bool {
+        !name.is_empty()
+            && self
+                .section_names
+                .split(',')
+                .any(|requested| requested.trim().as_bytes() == name)
+    }
+
+    fn symbols(&mut self, header: &Header) -> Result<Option<Symbols>, BootError> {
+        let Some(symtab_header) = self.symtab_header else {
+            return Ok(None);
+        };
+
+        if symtab_header.sh_link >= u32::from(header.e_shnum) {
+            return Err(BootError::InvalidElf(
+                "symbol table links to a missing string table",
+            ));
+        }
+        let mut buffer = [0; SIZEOF_SHDR];
+        let strtab_header =
+            header.e_shoff + (u64::from(symtab_header.sh_link) * SIZEOF_SHDR as u64);
+        self.file.set_position(strtab_header)?;
+        self.read_exact(&mut buffer)?;
+        let strtab_header = elf::parse_section_header(&buffer, self.file_size)?;
+
+        Ok(Some(Symbols {
+            symtab: self.read_section(&symtab_header)?,
+            strtab: self.read_section(&strtab_header)?,
+        }))
+    }
+
+    /// Reads the build ID and feature requirements from the notes described by
+    /// `note_header`.
+    ///
+    /// Build IDs that aren't 20 bytes long are ignored, but a feature note
+    /// whose description isn't a `u64` is an error.
+    fn read_notes(
+        &mut self,
+        note_header: &ProgramHeader,
+        build_id: &mut Option<[u8; 20]>,
+        required_features: &mut Features,
+    ) -> Result<(), BootError> {
+        /// The type of a GNU build ID note.
+        const NT_GNU_BUILD_ID: u32 = 3;
+        const GNU_NAME: &[u8] = b"GNU\0";
+
+        let bytes = self.read_bytes(note_header.p_offset, note_header.p_filesz as usize)?;
+        let mut result = Ok(());
+        for note in elf::notes(bytes) {
+            match (note.name, note.ty) {
+                (GNU_NAME, NT_GNU_BUILD_ID) if build_id.is_none() => {
+                    *build_id = note.desc.try_into().ok();
+                    if build_id.is_none() {
+                        warn!("ignoring {} byte build ID", note.desc.len());
+                    }
+                }
+                (FEATURES_NOTE_NAME, NT_REQUIRED_FEATURES) => {
+                    let Ok(bits) = note.desc.try_into() else {
+                        result = Err(BootError::InvalidElf("malformed required-features note"));
+                        break;
+                    };
+                    *required_features = required_features
+                        .union(Features::from_bits_retain(u64::from_ne_bytes(bits)));
+                }
+                _ => {}
+            }
+        }
+        // SAFETY: The slice was returned by `read_bytes`, which uses
+        // `allocate_byte_slice`.
+        unsafe { self.context.free_byte_slice(bytes) };
+        result
+    }
+
+    fn read_section(&mut self, section_header: &SectionHeader) -> Result<&'static [u8], BootError> {
+        self.read_bytes(section_header.sh_offset, section_header.sh_size as usize)
+            .map(|bytes| &*bytes)
+    }
+
+    /// Reads `len` bytes at `offset` in the kernel file into a newly allocated
+    /// buffer.
+    fn read_bytes(&mut self, offset: u64, len: usize) -> Result<&'static mut [u8], BootError> {
+        if len == 0 {
+            return Ok(&mut []);
+        }
+
+        let bytes = self.context.allocate_byte_slice(len, RECLAIMABLE_MEMORY);
+        self.file.set_position(offset)?;
+        self.read_exact(bytes)?;
+        Ok(bytes)
+    }
+
+    fn handle_load_segment(
+        &mut self,
+        segment: &ProgramHeader,
+    ) -> Result<&'static mut [u8], BootError> {
         info!("loading segment: {segment:?}");
-        let slice = self.context.map_segment(segment);
-        info!("at paddr: {:x?}", slice.as_ptr());
+        // Parts of writable segments may later be made read-only by
+        // `protect_relro`, which can't split huge pages.
+        let huge_pages = self.huge_pages && segment.p_flags & PF_W == 0;
+        let slice = self.context.map_segment(segment, huge_pages)?;
 
-        self.file
-            .set_position(segment.p_offset)
-            .expect("failed to set kernel file position to segment offset");
-        self.file
-            .read(&mut slice[..segment.p_filesz as usize])
-            .expect("failed to read kernel segment");
+        self.file.set_position(segment.p_offset)?;
+        self.read_exact(&mut slice[..segment.p_filesz as usize])?;
 
         // The BSS section was already zeroed by `map_segment`.
+        Ok(slice)
+    }
+
+    /// Fills `buffer` from the current file position, reading at most
+    /// [`READ_CHUNK_SIZE`] bytes at a time.
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), BootError> {
+        let mut bytes_read = 0;
+
+        while bytes_read < buffer.len() {
+            let chunk_end = min(bytes_read + READ_CHUNK_SIZE, buffer.len());
+            let len = self.file.read(&mut buffer[bytes_read..chunk_end])?;
+            if len == 0 {
+                return Err(BootError::UnexpectedEof);
+            }
+            bytes_read += len;
+
+            if buffer.len() > READ_CHUNK_SIZE {
+                info!("read {bytes_read:#x}/{:#x} bytes", buffer.len());
+            }
+        }
+        Ok(())
     }
 }
+
+/// Checks that the program headers in `bytes` are valid, that no two
+/// loadable segments overlap, that `entry_point` is in an executable segment,
+/// and that the relocation read-only segment, if any, is in a writable one.
+///
+/// Writable segments are always mapped with 4 KiB pages, so the pages of the
+/// relocation read-only segment can then be made read-only individually.
+fn check_program_headers(bytes: &[u8], file_size: u64, entry_point: u64) -> Result<(), BootError> {
+    let mut is_entry_point_loaded = false;
+    let mut relro_header = None;
+    for (index, header_bytes) in bytes.chunks_exact(SIZEOF_PHDR).enumerate() {
+        let program_header = elf::parse_program_header(header_bytes, file_size)?;
+        if program_header.p_type == PT_GNU_RELRO && program_header.p_memsz != 0 {
+            relro_header = Some(program_header);
+        }
+        if program_header.p_type != PT_LOAD || program_header.p_memsz == 0 {
+            continue;
+        }
+        // The parser checks that the segment's end doesn't overflow.
+        let range = program_header.p_vaddr..program_header.p_vaddr + program_header.p_memsz;
+        if program_header.p_flags & PF_X != 0 && range.contains(&entry_point) {
+            is_entry_point_loaded = true;
+        }
+        for other_bytes in bytes.chunks_exact(SIZEOF_PHDR).take(index) {
+            let other = elf::parse_program_header(other_bytes, file_size)?;
+            if other.p_type == PT_LOAD
+                && other.p_memsz != 0
+                && other.p_vaddr < range.end
+                && range.start < other.p_vaddr + other.p_memsz
+            {
+                return Err(BootError::InvalidElf("segments overlap"));
+            }
+        }
+    }
+
+    if !is_entry_point_loaded {
+        return Err(BootError::InvalidElf(
+            "entry point is not in an executable segment",
+        ));
+    }
+
+    if let Some(relro_header) = relro_header {
+        let relro_end = relro_header.p_vaddr + relro_header.p_memsz;
+        let is_relro_writable = bytes.chunks_exact(SIZEOF_PHDR).any(|header_bytes| {
+            elf::parse_program_header(header_bytes, file_size).is_ok_and(|program_header| {
+                program_header.p_type == PT_LOAD
+                    && program_header.p_flags & PF_W != 0
+                    && program_header.p_vaddr <= relro_header.p_vaddr
+                    && relro_end <= program_header.p_vaddr + program_header.p_memsz
+            })
+        });
+        if !is_relro_writable {
+            return Err(BootError::InvalidElf(
+                "relocation read-only segment is not in a writable segment",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the `len` bytes at the virtual address `start` in the loaded
+/// segments, or an error if they aren't contained in a single segment.
+fn segment_bytes(
+    segments: &mut [LoadedSegment],
+    start: u64,
+    len: u64,
+) -> Result<&mut [u8], BootError> {
+    let error = BootError::InvalidElf("dynamic section or relocation is not in a loaded segment");
+    let end = start.checked_add(len).ok_or(error)?;
+    let segment = segments
+        .iter_mut()
+        .find(|segment| segment.start <= start && end <= segment.end())
+        .ok_or(error)?;
+    let offset = (start - segment.start) as usize;
+    Ok(&mut segment.bytes[offset..offset + len as usize])
+}
+
+/// Returns the kernel base corresponding to `random`, which is aligned to
+/// [`KASLR_ALIGN`] and lies between [`KASLR_MIN_BASE`] and [`KASLR_MAX_BASE`].
+const fn kaslr_base(random: u64) -> u64 {
+    let num_bases = (KASLR_MAX_BASE - KASLR_MIN_BASE) / KASLR_ALIGN + 1;
+    KASLR_MIN_BASE + random % num_bases * KASLR_ALIGN
+}
+
+const _: () = {
+    assert!(kaslr_base(0) == KASLR_MIN_BASE);
+    assert!(kaslr_base((KASLR_MAX_BASE - KASLR_MIN_BASE) / KASLR_ALIGN) == KASLR_MAX_BASE);
+    assert!(kaslr_base(u64::MAX) % KASLR_ALIGN == 0);
+    assert!(kaslr_base(u64::MAX) <= KASLR_MAX_BASE);
+};
@@ -1,17 +1,35 @@
+use crate::arch::serial::SerialPort;
 use core::{
     fmt::{self, Write},
-    ptr,
+    ptr::{self, NonNull},
+    sync::atomic::AtomicBool,
 };
 use font_constants::BACKUP_CHAR;
 use noto_sans_mono_bitmap::{
     get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
 };
 use spin::{Mutex, Once};
+use uefi::table::{Boot, SystemTable};
 use uefi_bootloader_api::{FrameBufferInfo, PixelFormat};
 
 /// The global logger instance used for the `log` crate.
 pub(crate) static LOGGER: Once<LockedLogger> = Once::new();
 
+/// The logger used for the `log` crate if there is no framebuffer.
+pub(crate) static CONSOLE_LOGGER: ConsoleLogger = ConsoleLogger(Mutex::new(None));
+
+/// The logger used for the `log` crate if the framebuffer is disabled.
+pub(crate) static SERIAL_LOGGER: SerialLogger = SerialLogger(Mutex::new(None));
+
+/// Recent log output, kept so that it can be dumped over serial on panic.
+pub(crate) static LOG_HISTORY: Mutex<LogHistory> = Mutex::new(LogHistory::new());
+
+/// Whether the panic handler dumps [`LOG_HISTORY`] over serial.
+pub(crate) static DUMP_ON_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// The number of bytes of log output kept in [`LOG_HISTORY`].
+const LOG_HISTORY_SIZE: usize = 4096;
+
 /// A [`Logger`] instance protected by a spinlock.
 pub(crate) struct LockedLogger(Mutex<Logger>);
 
@@ -23,18 +41,12 @@ const LETTER_SPACING: usize = 0;
 /// Padding from the border. Prevent that font is too close to border.
 const BORDER_PADDING: usize = 1;
 
+/// Height of the progress bar drawn along the bottom of the screen.
+const PROGRESS_BAR_HEIGHT: usize = 4;
+
 /// Constants for the usage of the [`noto_sans_mono_bitmap`] crate.
 mod font_constants {
-    use super::{get_raster_width, FontWeight, RasterHeight};
-
-    /// Height of each char raster. The font size is ~0.84% of this. Thus, this
-    /// is the line height that enables multiple characters to be
-    /// side-by-side and appear optically in one line in a natural way.
-    pub(crate) const CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size16;
-
-    /// The width of each single symbol of the mono space font.
-    pub(crate) const CHAR_RASTER_WIDTH: usize =
-        get_raster_width(FontWeight::Regular, CHAR_RASTER_HEIGHT);
+    use super::FontWeight;
 
     /// Backup character if a desired symbol is not available by the font.
     /// The '�' character requires the feature "unicode-specials".
@@ -45,21 +57,19 @@ mod font_constants {
 
 /// Returns the raster of the given char or the raster of
 /// [`font_constants::BACKUP_CHAR`].
-fn get_char_raster(c: char) -> RasterizedChar {
-    fn get(c: char) -> Option<RasterizedChar> {
-        get_raster(
-            c,
-            font_constants::FONT_WEIGHT,
-            font_constants::CHAR_RASTER_HEIGHT,
-        )
-    }
+fn get_char_raster(c: char, raster_height: RasterHeight) -> RasterizedChar {
+    let get = |c| get_raster(c, font_constants::FONT_WEIGHT, raster_height);
     get(c).unwrap_or_else(|| get(BACKUP_CHAR).expect("Should get raster of backup char."))
 }
 
 impl LockedLogger {
     /// Create a new instance that logs to the given framebuffer.
-    pub(crate) fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
-        LockedLogger(Mutex::new(Logger::new(framebuffer, info)))
+    pub(crate) fn new(
+        framebuffer: &'static mut [u8],
+        info: FrameBufferInfo,
+        raster_height: RasterHeight,
+    ) -> Self {
+        LockedLogger(Mutex::new(Logger::new(framebuffer, info, raster_height)))
     }
 
     /// Force-unlocks the logger to prevent a deadlock.
@@ -72,6 +82,39 @@ impl LockedLogger {
         // SAFETY: Guaranteed by caller.
         unsafe { self.0.force_unlock() };
     }
+
+    /// Draws a progress bar along the bottom of the screen.
+    pub(crate) fn draw_progress_bar(&self, done: usize, total: usize) {
+        self.0.lock().draw_progress_bar(done, total);
+    }
+
+    /// Moves the logger to `framebuffer`, which must map the same memory as
+    /// the framebuffer it was created with.
+    pub(crate) fn set_frame_buffer(&self, framebuffer: &'static mut [u8]) {
+        let mut logger = self.0.lock();
+        assert_eq!(
+            framebuffer.len(),
+            logger.framebuffer.len(),
+            "framebuffer size changed"
+        );
+        logger.framebuffer = framebuffer;
+    }
+}
+
+/// Reports the progress of a long-running operation.
+///
+/// If the framebuffer logger is initialised, this draws a progress bar.
+/// Otherwise, the progress is logged every 10%.
+pub(crate) fn report_progress(operation: &str, done: usize, total: usize) {
+    if total == 0 {
+        return;
+    }
+
+    if let Some(logger) = LOGGER.get() {
+        logger.draw_progress_bar(done, total);
+    } else if done == total || done * 10 / total != done.saturating_sub(1) * 10 / total {
+        log::info!("{operation}: {done}/{total}");
+    }
 }
 
 impl log::Log for LockedLogger {
@@ -82,25 +125,271 @@ impl log::Log for LockedLogger {
     fn log(&self, record: &log::Record<'_>) {
         let mut logger = self.0.lock();
         writeln!(logger, "{:5}: {}", record.level(), record.args()).unwrap();
+        write_history(record);
     }
 
     fn flush(&self) {}
 }
 
+/// Logs to the UEFI console while boot services are available.
+///
+/// Records logged after [`disable`](Self::disable) are only kept in
+/// [`LOG_HISTORY`].
+pub(crate) struct ConsoleLogger(Mutex<Option<NonNull<SystemTable<Boot>>>>);
+
+impl ConsoleLogger {
+    /// Starts logging to the console of `system_table`.
+    ///
+    /// # Safety
+    ///
+    /// `system_table` must remain valid until [`disable`](Self::disable) is
+    /// called.
+    pub(crate) unsafe fn enable(&self, system_table: NonNull<SystemTable<Boot>>) {
+        *self.0.lock() = Some(system_table);
+    }
+
+    /// Stops logging to the console.
+    pub(crate) fn disable(&self) {
+        *self.0.lock() = None;
+    }
+
+    /// Returns whether the logger is logging to the console.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.0.lock().is_some()
+    }
+
+    /// Force-unlocks the logger to prevent a deadlock.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure no other thread could simultaneously access the
+    /// underlying logger.
+    pub(crate) unsafe fn force_unlock(&self) {
+        // SAFETY: Guaranteed by caller.
+        unsafe { self.0.force_unlock() };
+    }
+}
+
+// SAFETY: The bootloader is single-threaded.
+unsafe impl Sync for ConsoleLogger {}
+
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if let Some(mut system_table) = *self.0.lock() {
+            // SAFETY: Guaranteed by the caller of `enable`.
+            let system_table = unsafe { system_table.as_mut() };
+            // There's nowhere to report a failure to write to the console.
+            let _ = writeln!(
+                system_table.stdout(),
+                "{:5}: {}",
+                record.level(),
+                record.args()
+            );
+        }
+        write_history(record);
+    }
+
+    fn flush(&self) {}
+}
+
+/// The format of the records written by [`SerialLogger`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    /// The same human-readable lines as the other loggers.
+    Text,
+    /// One JSON object per line, with `level`, `target`, and `message` string
+    /// fields, for test harnesses parsing the serial output.
+    Json,
+}
+
+/// Logs to the serial port.
+///
+/// Unlike [`ConsoleLogger`], this keeps working after boot services are
+/// exited.
+pub(crate) struct SerialLogger(Mutex<Option<(SerialPort, LogFormat)>>);
+
+impl SerialLogger {
+    /// Initialises the serial port and starts logging to it in `format`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the serial port isn't simultaneously accessed
+    /// elsewhere.
+    pub(crate) unsafe fn enable(&self, format: LogFormat) {
+        // SAFETY: Guaranteed by caller.
+        *self.0.lock() = Some((unsafe { SerialPort::new() }, format));
+    }
+
+    /// Returns whether the logger is logging to the serial port.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.0.lock().is_some()
+    }
+
+    /// Force-unlocks the logger to prevent a deadlock.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure no other thread could simultaneously access the
+    /// underlying logger.
+    pub(crate) unsafe fn force_unlock(&self) {
+        // SAFETY: Guaranteed by caller.
+        unsafe { self.0.force_unlock() };
+    }
+}
+
+impl log::Log for SerialLogger {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if let Some((serial, format)) = self.0.lock().as_mut() {
+            // There's nowhere to report a failure to write to the serial port.
+            let _ = match format {
+                LogFormat::Text => writeln!(serial, "{:5}: {}", record.level(), record.args()),
+                LogFormat::Json => write_json_record(serial, record),
+            };
+        }
+        write_history(record);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Writes `record` to `writer` as a single line JSON object.
+fn write_json_record(writer: &mut impl Write, record: &log::Record<'_>) -> fmt::Result {
+    write!(writer, "{{\"level\":\"{}\",\"target\":\"", record.level())?;
+    JsonEscaper(writer).write_str(record.target())?;
+    writer.write_str("\",\"message\":\"")?;
+    write!(JsonEscaper(writer), "{}", record.args())?;
+    writer.write_str("\"}\n")
+}
+
+/// Escapes everything written through it for use in a JSON string.
+struct JsonEscaper<'a, W: Write>(&'a mut W);
+
+impl<W: Write> Write for JsonEscaper<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c if c.is_control() => write!(self.0, "\\u{:04x}", u32::from(c))?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `record` to [`LOG_HISTORY`].
+fn write_history(record: &log::Record<'_>) {
+    writeln!(
+        LOG_HISTORY.lock(),
+        "{:5}: {}",
+        record.level(),
+        record.args()
+    )
+    .unwrap();
+}
+
+/// A ring buffer of the most recent log output.
+pub(crate) struct LogHistory {
+    buffer: [u8; LOG_HISTORY_SIZE],
+    /// The total number of bytes ever written.
+    written: usize,
+}
+
+impl LogHistory {
+    const fn new() -> Self {
+        Self {
+            buffer: [0; LOG_HISTORY_SIZE],
+            written: 0,
+        }
+    }
+
+    /// Writes the complete lines in the history to `writer`, oldest first.
+    pub(crate) fn dump(&self, writer: &mut impl Write) -> fmt::Result {
+        let split = self.written % LOG_HISTORY_SIZE;
+        let (older, newer) = if self.written > LOG_HISTORY_SIZE {
+            // The oldest line was partially overwritten, so skip it.
+            let older = &self.buffer[split..];
+            let line_start = older
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .map_or(older.len(), |index| index + 1);
+            (&older[line_start..], &self.buffer[..split])
+        } else {
+            (&[][..], &self.buffer[..split])
+        };
+
+        write_lossy(writer, older)?;
+        write_lossy(writer, newer)
+    }
+}
+
+/// Writes `bytes` to `writer`, replacing invalid UTF-8 with
+/// [`char::REPLACEMENT_CHARACTER`].
+///
+/// A multi-byte character may have been split when the history wrapped.
+fn write_lossy(writer: &mut impl Write, mut bytes: &[u8]) -> fmt::Result {
+    loop {
+        match core::str::from_utf8(bytes) {
+            Ok(text) => return writer.write_str(text),
+            Err(error) => {
+                let (valid, rest) = bytes.split_at(error.valid_up_to());
+                // SAFETY: `from_utf8` checked that these bytes are valid.
+                writer.write_str(unsafe { core::str::from_utf8_unchecked(valid) })?;
+                writer.write_char(char::REPLACEMENT_CHARACTER)?;
+                bytes = &rest[error.error_len().unwrap_or(rest.len())..];
+            }
+        }
+    }
+}
+
+impl Write for LogHistory {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buffer[self.written % LOG_HISTORY_SIZE] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
 /// Allows logging text to a pixel-based framebuffer.
 pub(crate) struct Logger {
     framebuffer: &'static mut [u8],
     info: FrameBufferInfo,
+    /// Height of each char raster. The font size is ~0.84% of this. Thus, this
+    /// is the line height that enables multiple characters to be
+    /// side-by-side and appear optically in one line in a natural way.
+    raster_height: RasterHeight,
+    /// The width of each single symbol of the mono space font.
+    raster_width: usize,
     x_pos: usize,
     y_pos: usize,
 }
 
 impl Logger {
-    /// Creates a new logger that uses the given framebuffer.
-    pub(crate) fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
+    /// Creates a new logger that uses the given framebuffer and font size.
+    pub(crate) fn new(
+        framebuffer: &'static mut [u8],
+        info: FrameBufferInfo,
+        raster_height: RasterHeight,
+    ) -> Self {
         let mut logger = Self {
             framebuffer,
             info,
+            raster_height,
+            raster_width: get_raster_width(font_constants::FONT_WEIGHT, raster_height),
             x_pos: 0,
             y_pos: 0,
         };
@@ -109,7 +398,7 @@ impl Logger {
     }
 
     fn newline(&mut self) {
-        self.y_pos += font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
+        self.y_pos += self.raster_height.val() + LINE_SPACING;
         self.carriage_return();
     }
 
@@ -128,8 +417,19 @@ impl Logger {
         self.info.width
     }
 
+    /// The height of the text area, which excludes the progress bar.
     fn height(&self) -> usize {
-        self.info.height
+        self.info.height.saturating_sub(PROGRESS_BAR_HEIGHT)
+    }
+
+    fn draw_progress_bar(&mut self, done: usize, total: usize) {
+        let filled_width = self.info.width * done.min(total) / total.max(1);
+        for y in self.height()..self.info.height {
+            for x in 0..self.info.width {
+                let intensity = if x < filled_width { 0xff } else { 0x40 };
+                self.write_pixel(x, y, intensity);
+            }
+        }
     }
 
     /// Writes a single char to the framebuffer. Takes care of special control
@@ -140,16 +440,15 @@ impl Logger {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
             c => {
-                let new_xpos = self.x_pos + font_constants::CHAR_RASTER_WIDTH;
+                let new_xpos = self.x_pos + self.raster_width;
                 if new_xpos >= self.width() {
                     self.newline();
                 }
-                let new_ypos =
-                    self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() + BORDER_PADDING;
+                let new_ypos = self.y_pos + self.raster_height.val() + BORDER_PADDING;
                 if new_ypos >= self.height() {
                     self.clear();
                 }
-                self.write_rendered_char(&get_char_raster(c));
+                self.write_rendered_char(&get_char_raster(c, self.raster_height));
             }
         }
     }
@@ -165,18 +464,35 @@ impl Logger {
         self.x_pos += rendered_char.width() + LETTER_SPACING;
     }
 
+    /// Writes a single pixel to the framebuffer.
+    ///
+    /// Pixels outside the framebuffer are ignored, so that incorrect geometry
+    /// garbles the output rather than corrupting adjacent memory.
     fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
-        let pixel_offset = y * self.info.stride + x;
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
         let color = match self.info.pixel_format {
             PixelFormat::Rgb => [intensity, intensity, intensity / 2, 0],
             PixelFormat::Bgr => [intensity / 2, intensity, intensity, 0],
         };
-        let bytes_per_pixel = self.info.bytes_per_pixel;
-        let byte_offset = pixel_offset * bytes_per_pixel;
-        self.framebuffer[byte_offset..(byte_offset + bytes_per_pixel)]
-            .copy_from_slice(&color[..bytes_per_pixel]);
-        // SAFETY: The frame buffer is valid.
-        let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
+        let bytes_per_pixel = self.info.bytes_per_pixel.min(color.len());
+        let Some(pixel) = y
+            .checked_mul(self.info.stride)
+            .and_then(|offset| offset.checked_add(x))
+            .and_then(|offset| offset.checked_mul(self.info.bytes_per_pixel))
+            .and_then(|start| {
+                self.framebuffer
+                    .get_mut(start..start.checked_add(bytes_per_pixel)?)
+            })
+        else {
+            return;
+        };
+        pixel.copy_from_slice(&color[..bytes_per_pixel]);
+        if let Some(byte) = pixel.first() {
+            // SAFETY: The frame buffer is valid.
+            let _ = unsafe { ptr::read_volatile(byte) };
+        }
     }
 }
 
@@ -7,71 +7,139 @@
 #![no_std]
 #![no_main]
 
+mod acpi;
 mod arch;
 mod boot_info;
+mod chunked;
+mod config;
 mod context;
+mod cpio;
+mod elf;
+mod error;
+mod fs;
+mod http;
 mod kernel;
 mod logger;
 mod mappings;
 mod memory;
+mod memtest;
 mod modules;
+mod mp;
+mod network;
+mod rng;
+mod selftest;
 mod util;
 
 use crate::{
     arch::jump_to_kernel,
-    memory::{Frame, VirtualAddress},
+    config::Config,
+    error::BootError,
+    memory::{Frame, Mapper, VirtualAddress, RECLAIMABLE_MEMORY},
+};
+use core::{
+    fmt::Write,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
 };
-use core::{fmt::Write, ptr::NonNull};
 use log::{error, info};
 use uefi::{
     prelude::entry,
     proto::console::gop::{self, GraphicsOutput},
-    table::{
-        cfg::{ACPI2_GUID, ACPI_GUID},
-        Boot, SystemTable,
-    },
+    table::{boot::SearchType, Boot, SystemTable},
     Handle, Status,
 };
-use uefi_bootloader_api::{BootInformation, FrameBuffer, FrameBufferInfo, PixelFormat};
+use uefi_bootloader_api::{
+    BootInformation, FrameBuffer, FrameBufferInfo, GopMode, PixelFormat, MAX_GOP_MODES,
+    NO_PHYSICAL_MEMORY_OFFSET,
+};
 
 pub(crate) use context::{BootContext, RuntimeContext};
 
-static mut SYSTEM_TABLE: Option<NonNull<SystemTable<Boot>>> = None;
+/// The system table, which the panic handler writes to if no logger is set
+/// up yet.
+///
+/// This is null once boot services are exited, as the system table is moved
+/// and its console can no longer be used.
+static SYSTEM_TABLE: AtomicPtr<SystemTable<Boot>> = AtomicPtr::new(ptr::null_mut());
 
 #[entry]
-fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
-    let system_table_pointer = NonNull::from(&mut system_table);
-    // SAFETY: We are the sole thread.
-    unsafe { SYSTEM_TABLE = Some(system_table_pointer) };
+fn main(handle: Handle, system_table: SystemTable<Boot>) -> Status {
+    let mut context = BootContext::new(handle, system_table);
 
-    system_table
+    let system_table_pointer = NonNull::from(&mut context.system_table);
+    SYSTEM_TABLE.store(system_table_pointer.as_ptr(), Ordering::Relaxed);
+
+    context
+        .system_table
         .stdout()
         .clear()
         .expect("failed to clear stdout");
 
-    let mut frame_buffer = get_frame_buffer(&system_table);
+    let config = context.load_config();
+    if let Some(serial) = config.serial {
+        arch::serial::configure(serial);
+    }
+
+    let display = if config.frame_buffer {
+        get_display(context.system_table(), &config)
+    } else {
+        None
+    };
+    let mut frame_buffer = display.map(|(_, frame_buffer)| frame_buffer);
+
     if let Some(frame_buffer) = frame_buffer {
-        init_logger(&frame_buffer);
+        init_logger(&frame_buffer, &config);
         info!("using framebuffer at {:#x}", frame_buffer.physical);
+    } else if !config.frame_buffer {
+        // SAFETY: Nothing else uses the serial port unless the bootloader
+        // panics.
+        unsafe { logger::SERIAL_LOGGER.enable(config.log_format) };
+        set_logger(&logger::SERIAL_LOGGER, &config);
+        info!("framebuffer disabled, logging to serial");
+    } else {
+        // SAFETY: The context isn't moved until it exits boot services, which
+        // disables the console logger.
+        unsafe { init_console_logger(system_table_pointer, &config) };
+        info!("no framebuffer found, logging to the UEFI console");
     }
 
-    // SAFETY: We are the sole thread.
-    unsafe { SYSTEM_TABLE = None };
-
-    let rsdp_address = get_rsdp_address(&system_table);
+    let rsdp = acpi::get_rsdp(context.system_table());
+    let cpu_count = context.cpu_count();
+    let gop_modes = get_gop_modes(&context, display.map(|(handle, _)| handle));
 
-    let mut context = BootContext::new(handle, system_table);
-    let (entry_point, elf_sections) = context.load_kernel();
+    let kernel = context
+        .load_kernel(&config)
+        .and_then(|kernel| {
+            let missing_features = kernel
+                .required_features
+                .difference(config.features(frame_buffer.is_some()));
+            if missing_features.is_empty() {
+                Ok(kernel)
+            } else {
+                Err(BootError::MissingFeatures(missing_features))
+            }
+        })
+        .unwrap_or_else(|error| panic!("failed to load kernel: {error}"));
     info!("loaded kernel");
     // This may take a sec.
     info!("loading modules...");
-    let modules = context.load_modules();
-    info!("loaded modules");
+    let modules = context
+        .load_modules(&config)
+        .unwrap_or_else(|error| panic!("failed to load modules: {error}"));
+    info!("loaded {} modules", modules.modules.len());
+
+    // Allocation must be checked while boot services are still available.
+    let zeroed_allocation = config.self_test && selftest::check_zeroed_allocation(&context);
 
-    let mut context = context.exit_boot_services();
+    SYSTEM_TABLE.store(ptr::null_mut(), Ordering::Relaxed);
+    let mut context = context.exit_boot_services(&config);
 
-    let stack_top = context.set_up_mappings(frame_buffer.as_mut());
+    let stack = context.set_up_mappings(frame_buffer.as_mut(), &config, &kernel);
     info!("created memory mappings");
+    info!(
+        "{:#x} bytes of usable memory remaining",
+        context.frame_allocator.remaining_usable_bytes()
+    );
 
     let page_table_frame = context.page_table();
     info!(
@@ -79,17 +147,49 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         page_table_frame.start_address()
     );
 
-    let boot_info = context.create_boot_info(frame_buffer, rsdp_address, modules, elf_sections);
+    let entry_point = kernel.entry_point;
+    let executable_stack = kernel.executable_stack;
+    let segments = kernel.segments;
+    let relro_pages = kernel.relro_pages.clone();
+    let boot_info = context.create_boot_info(
+        frame_buffer,
+        rsdp,
+        cpu_count,
+        modules,
+        gop_modes,
+        kernel,
+        stack,
+        &config,
+    );
     info!("created boot info: {boot_info:x?}");
 
+    if config.self_test {
+        // SAFETY: The runtime context, whose mapper owned the page table, was
+        // consumed when creating the boot info.
+        let mapper = unsafe { Mapper::from_frame(page_table_frame) };
+        selftest::run(
+            &mapper,
+            segments,
+            relro_pages,
+            boot_info,
+            executable_stack,
+            zeroed_allocation,
+        );
+    }
+
     info!("about to jump to kernel: {:x?}", entry_point.value());
     // SAFETY: Everything is correctly mapped.
     unsafe {
         jump_to_kernel(KernelContext {
             page_table_frame,
-            stack_top,
+            stack_top: VirtualAddress::new_canonical(stack.top),
             entry_point,
             boot_info,
+            physical_memory_offset: if boot_info.identity_mapped {
+                0
+            } else {
+                NO_PHYSICAL_MEMORY_OFFSET
+            },
         })
     }
 }
@@ -101,35 +201,76 @@ struct KernelContext {
     stack_top: VirtualAddress,
     entry_point: VirtualAddress,
     boot_info: &'static BootInformation,
+    /// The virtual address at which physical memory is mapped, or
+    /// [`NO_PHYSICAL_MEMORY_OFFSET`].
+    physical_memory_offset: usize,
 }
 
-fn get_frame_buffer(system_table: &SystemTable<Boot>) -> Option<FrameBuffer> {
-    let handle = system_table
+/// Returns the handle of the display to use and its framebuffer.
+///
+/// The display selected by the `display` option is used if it has a usable
+/// framebuffer. Otherwise, the display with the highest resolution is used,
+/// which skips headless displays. This runs before the logger is initialised,
+/// so an unusable `display` option silently falls back to the default.
+fn get_display(system_table: &SystemTable<Boot>, config: &Config) -> Option<(Handle, FrameBuffer)> {
+    let handles = system_table
         .boot_services()
-        .get_handle_for_protocol::<GraphicsOutput>()
+        .locate_handle_buffer(SearchType::from_proto::<GraphicsOutput>())
         .ok()?;
+    let handles = handles.handles();
+
+    if let Some(index) = config.display {
+        let display = handles.get(index).and_then(|handle| {
+            get_frame_buffer(system_table, *handle, config)
+                .map(|frame_buffer| (*handle, frame_buffer))
+        });
+        if display.is_some() {
+            return display;
+        }
+    }
+
+    handles
+        .iter()
+        .filter_map(|handle| {
+            get_frame_buffer(system_table, *handle, config)
+                .map(|frame_buffer| (*handle, frame_buffer))
+        })
+        .max_by_key(|(_, frame_buffer)| frame_buffer.info.width * frame_buffer.info.height)
+}
+
+fn get_frame_buffer(
+    system_table: &SystemTable<Boot>,
+    handle: Handle,
+    config: &Config,
+) -> Option<FrameBuffer> {
     let mut gop = system_table
         .boot_services()
         .open_protocol_exclusive::<GraphicsOutput>(handle)
         .ok()?;
 
     let mode_info = gop.current_mode_info();
+    // Bitmask and BltOnly framebuffers are not supported.
+    let pixel_format = convert_pixel_format(mode_info.pixel_format())?;
     let mut frame_buffer = gop.frame_buffer();
     let info = FrameBufferInfo {
         size: frame_buffer.size(),
         width: mode_info.resolution().0,
         height: mode_info.resolution().1,
-        pixel_format: match mode_info.pixel_format() {
-            gop::PixelFormat::Rgb => PixelFormat::Rgb,
-            gop::PixelFormat::Bgr => PixelFormat::Bgr,
-            gop::PixelFormat::Bitmask | gop::PixelFormat::BltOnly => {
-                panic!("Bitmask and BltOnly framebuffers are not supported")
-            }
-        },
+        pixel_format: config.pixel_format.unwrap_or(pixel_format),
         bytes_per_pixel: 4,
         stride: mode_info.stride(),
     };
 
+    // Some firmware reports a framebuffer size that is inconsistent with the
+    // mode's geometry, in which case we can't safely draw to it.
+    let required_size = info
+        .height
+        .checked_mul(info.stride)?
+        .checked_mul(info.bytes_per_pixel)?;
+    if required_size == 0 || info.size < required_size {
+        return None;
+    }
+
     Some(FrameBuffer {
         physical: frame_buffer.as_mut_ptr() as usize,
         virt: 0,
@@ -137,40 +278,126 @@ fn get_frame_buffer(system_table: &SystemTable<Boot>) -> Option<FrameBuffer> {
     })
 }
 
-fn init_logger(frame_buffer: &FrameBuffer) {
+fn get_gop_modes(context: &BootContext, display: Option<Handle>) -> &'static mut [GopMode] {
+    let boot_services = context.system_table().boot_services();
+    let gop = match display
+        .map(|handle| boot_services.open_protocol_exclusive::<GraphicsOutput>(handle))
+    {
+        Some(Ok(gop)) => gop,
+        _ => return &mut [],
+    };
+
+    let num_modes = gop.modes().len().min(MAX_GOP_MODES);
+    if num_modes == 0 {
+        return &mut [];
+    }
+
+    // This slice is copied into another slice in the bootloader, so this slice can
+    // be overwritten by the kernel.
+    let modes = context.allocate_slice(num_modes, RECLAIMABLE_MEMORY);
+    let mut len = 0;
+
+    for (index, mode) in gop.modes().enumerate().take(num_modes) {
+        let mode_info = mode.info();
+        if let Some(pixel_format) = convert_pixel_format(mode_info.pixel_format()) {
+            modes[len].write(GopMode {
+                index: index as u32,
+                width: mode_info.resolution().0,
+                height: mode_info.resolution().1,
+                pixel_format,
+                stride: mode_info.stride(),
+            });
+            len += 1;
+        }
+    }
+
+    // SAFETY: We initialised the first `len` modes.
+    unsafe { util::assume_written(modes, len) }
+}
+
+fn convert_pixel_format(pixel_format: gop::PixelFormat) -> Option<PixelFormat> {
+    match pixel_format {
+        gop::PixelFormat::Rgb => Some(PixelFormat::Rgb),
+        gop::PixelFormat::Bgr => Some(PixelFormat::Bgr),
+        gop::PixelFormat::Bitmask | gop::PixelFormat::BltOnly => None,
+    }
+}
+
+fn init_logger(frame_buffer: &FrameBuffer, config: &Config) {
     // SAFETY: The hardware initialised the frame buffer.
     let slice = unsafe {
         core::slice::from_raw_parts_mut(frame_buffer.physical as *mut _, frame_buffer.info.size)
     };
-    let logger =
-        logger::LOGGER.call_once(move || logger::LockedLogger::new(slice, frame_buffer.info));
-    log::set_logger(logger).expect("logger already set");
-    log::set_max_level(log::LevelFilter::Trace);
+    let logger = logger::LOGGER
+        .call_once(move || logger::LockedLogger::new(slice, frame_buffer.info, config.font_size));
+    set_logger(logger, config);
 }
 
-fn get_rsdp_address(system_table: &SystemTable<Boot>) -> Option<usize> {
-    let mut config_entries = system_table.config_table().iter();
-    // look for an ACPI2 RSDP first
-    let acpi2_rsdp = config_entries.find(|entry| matches!(entry.guid, ACPI2_GUID));
-    // if no ACPI2 RSDP is found, look for a ACPI1 RSDP
-    let rsdp = acpi2_rsdp.or_else(|| config_entries.find(|entry| matches!(entry.guid, ACPI_GUID)));
-    rsdp.map(|entry| entry.address as usize)
+/// Initialises the logger to write to the UEFI console.
+///
+/// # Safety
+///
+/// `system_table` must remain valid until the console logger is disabled.
+unsafe fn init_console_logger(system_table: NonNull<SystemTable<Boot>>, config: &Config) {
+    // SAFETY: Guaranteed by caller.
+    unsafe { logger::CONSOLE_LOGGER.enable(system_table) };
+    set_logger(&logger::CONSOLE_LOGGER, config);
+}
+
+fn set_logger(logger: &'static dyn log::Log, config: &Config) {
+    logger::DUMP_ON_PANIC.store(config.panic_dump, Ordering::Relaxed);
+    log::set_logger(logger).expect("logger already set");
+    log::set_max_level(if config.debug {
+        log::LevelFilter::Trace
+    } else {
+        log::LevelFilter::Info
+    });
 }
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo<'_>) -> ! {
-    // SAFETY: We are the sole thread.
-    if let Some(mut system_table_pointer) = unsafe { SYSTEM_TABLE } {
-        // SAFETY: We are the sole thread.
-        let system_table = unsafe { system_table_pointer.as_mut() };
-        let _ = writeln!(system_table.stdout(), "{info}");
+    static PANICKING: AtomicBool = AtomicBool::new(false);
+
+    // Don't try to report a panic that occurred while reporting a panic.
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        arch::halt();
     }
 
     if let Some(logger) = logger::LOGGER.get() {
         // SAFETY: We are the sole thread.
         unsafe { logger.force_unlock() };
     }
+    // SAFETY: We are the sole thread.
+    unsafe { logger::CONSOLE_LOGGER.force_unlock() };
+    // SAFETY: We are the sole thread.
+    unsafe { logger::SERIAL_LOGGER.force_unlock() };
+    // SAFETY: We are the sole thread.
+    unsafe { logger::LOG_HISTORY.force_unlock() };
     error!("{info}");
 
+    // The framebuffer and serial loggers work throughout, but the console
+    // logger stops working when boot services are exited.
+    let is_logged = logger::LOGGER.get().is_some()
+        || logger::SERIAL_LOGGER.is_enabled()
+        || logger::CONSOLE_LOGGER.is_enabled();
+    if !is_logged {
+        if let Some(mut system_table) = NonNull::new(SYSTEM_TABLE.load(Ordering::Relaxed)) {
+            // SAFETY: The pointer is cleared before the system table is moved,
+            // and we are the sole thread.
+            let system_table = unsafe { system_table.as_mut() };
+            let _ = writeln!(system_table.stdout(), "{info}");
+        } else if !logger::DUMP_ON_PANIC.load(Ordering::Relaxed) {
+            // SAFETY: We are the sole thread.
+            let mut serial = unsafe { arch::serial::SerialPort::new() };
+            let _ = writeln!(serial, "{info}");
+        }
+    }
+
+    if logger::DUMP_ON_PANIC.load(Ordering::Relaxed) {
+        // SAFETY: We are the sole thread.
+        let mut serial = unsafe { arch::serial::SerialPort::new() };
+        let _ = logger::LOG_HISTORY.lock().dump(&mut serial);
+    }
+
     arch::halt();
 }
@@ -1,47 +1,79 @@
 use crate::{
+    config::Config,
     jump_to_kernel,
-    memory::{Frame, FrameAllocator, Page, PhysicalAddress, PteFlags, VirtualAddress},
+    kernel::{Kernel, LoadedSegment},
+    logger,
+    memory::{
+        identity_page, is_ram, Frame, FrameAllocator, FrameRange, Mapper, Page, PageRange,
+        PhysicalAddress, PteFlags, HUGE_PAGE_SIZE, KERNEL_MEMORY, PAGE_SIZE, RECLAIMABLE_MEMORY,
+    },
     FrameBuffer, RuntimeContext,
 };
+use core::{mem::size_of, slice};
+use log::info;
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use uefi_bootloader_api::{EarlyHeap, MappingFlags, PreMappedRegion, Stack, GUARD_PAGE_POISON};
+
+/// The end of the lower half of the address space, which is where identity
+/// mappings lie.
+const IDENTITY_MAP_LIMIT: usize = 1 << 47;
 
 impl RuntimeContext {
     pub(crate) fn set_up_mappings(
         &mut self,
         frame_buffer: Option<&mut FrameBuffer>,
-    ) -> VirtualAddress {
+        config: &Config,
+        kernel: &Kernel,
+    ) -> Stack {
         // TODO: Enable nxe and write protect bits on x86_64.
 
         // TODO: Depend on kernel_config?
-        const STACK_SIZE: usize = 18 * 4096;
+        const STACK_SIZE: usize = 17 * 4096;
 
-        let stack_start_address = self.page_allocator.get_free_address(STACK_SIZE);
+        // This must happen before any virtual memory is allocated.
+        if config.identity_map {
+            self.identity_map_ram(config.identity_map_max_tables);
+        }
+        if config.identity_map_kernel {
+            self.identity_map_kernel(kernel.segments);
+        }
 
-        let stack_start = Page::containing_address(stack_start_address);
-        let stack_end = {
-            let end_address = stack_start_address + STACK_SIZE;
-            Page::containing_address(end_address - 1)
-        };
+        // The upper guard page is never mapped, and the lower one only if it's
+        // poisoned.
+        let (lower_guard_page, stack_pages, upper_guard_page) = self
+            .page_allocator
+            .get_free_pages_with_guards(STACK_SIZE, config.stack_address);
+        if config.poison_guard_page {
+            self.poison_guard_page(lower_guard_page);
+        }
 
-        // The +1 means the guard page isn't mapped to a frame.
-        for page in (stack_start + 1)..=stack_end {
+        for page in stack_pages.clone() {
             let frame = self
                 .frame_allocator
                 .allocate_frame()
                 .expect("failed to allocate stack frame");
-            self.mapper.map(
-                page,
-                frame,
-                PteFlags::new()
-                    .present(true)
-                    .writable(true)
-                    .no_execute(true),
-                &mut self.frame_allocator,
-            );
+            self.mapper
+                .map(
+                    page,
+                    frame,
+                    PteFlags::new()
+                        .present(true)
+                        .writable(true)
+                        .no_execute(!kernel.executable_stack),
+                    &mut self.frame_allocator,
+                )
+                .unwrap_or_else(|error| {
+                    panic!("failed to map stack {page:?} to {frame:?}: {error}")
+                });
         }
 
         if let Some(frame_buffer) = frame_buffer {
-            let frame_buffer_start_address =
-                self.page_allocator.get_free_address(frame_buffer.info.size);
+            let frame_buffer_start_address = match config.frame_buffer_address {
+                Some(hint) => self
+                    .page_allocator
+                    .get_free_address_at(hint, frame_buffer.info.size),
+                None => self.page_allocator.get_free_address(frame_buffer.info.size),
+            };
             let frame_buffer_virtual_start = Page::containing_address(frame_buffer_start_address);
             let frame_buffer_virtual_end = {
                 let end_address =
@@ -57,34 +89,321 @@ impl RuntimeContext {
                 Frame::containing_address(end_address - 1)
             };
 
-            for (page, frame) in (frame_buffer_virtual_start..=frame_buffer_virtual_end)
-                .zip(frame_buffer_physical_start..frame_buffer_physical_end)
-            {
-                self.mapper.map(
-                    page,
-                    frame,
-                    PteFlags::new()
-                        .present(true)
-                        .writable(true)
-                        .no_execute(true),
+            let pages = PageRange::new(frame_buffer_virtual_start, frame_buffer_virtual_end);
+            let frames = FrameRange::new(frame_buffer_physical_start, frame_buffer_physical_end);
+            let flags = PteFlags::new()
+                .present(true)
+                .writable(true)
+                .no_execute(true);
+            self.mapper
+                .map_range(
+                    pages.clone(),
+                    frames.clone(),
+                    flags,
                     &mut self.frame_allocator,
-                );
-            }
+                )
+                .unwrap_or_else(|error| {
+                    panic!("failed to map framebuffer {pages:?} to {frames:?}: {error}")
+                });
 
             frame_buffer.virt = frame_buffer_start_address.value();
+            self.remap_logger(pages, frames, flags, frame_buffer.info.size);
+        }
+
+        self.map_pre_mapped_regions(config);
+        if let Some(size) = config.early_heap_size {
+            self.map_early_heap(size);
         }
 
         // Identity-map the context switch function so that when it switches to the new
-        // page table, it continues executing.
-        self.mapper.map(
-            Page::containing_address(VirtualAddress::new_canonical(jump_to_kernel as usize)),
-            Frame::containing_address(PhysicalAddress::new_canonical(jump_to_kernel as usize)),
-            PteFlags::new().present(true),
-            &mut self.frame_allocator,
-        );
+        // page table, it continues executing. Only its frame is mapped, and it's reported
+        // to the kernel so that it can be unmapped.
+        // If all RAM is identity-mapped, this is already the case.
+        if !self.identity_mapped {
+            let trampoline =
+                Frame::containing_address(PhysicalAddress::new_canonical(jump_to_kernel as usize));
+            self.identity_map(
+                FrameRange::new(trampoline, trampoline),
+                PteFlags::new().present(true),
+            );
+            self.trampoline = Some(trampoline);
+        }
 
         crate::memory::set_up_arch_specific_mappings(self);
 
-        (stack_end + 1).start_address()
+        Stack {
+            bottom: stack_pages.start_address().value(),
+            top: upper_guard_page.start_address().value(),
+            lower_guard_page: lower_guard_page.start_address().value(),
+            upper_guard_page: upper_guard_page.start_address().value(),
+            lower_guard_page_poisoned: config.poison_guard_page,
+        }
+    }
+
+    /// Reserves `size` bytes of physically contiguous memory for the kernel's
+    /// early allocator and maps it at a free virtual address.
+    fn map_early_heap(&mut self, size: usize) {
+        let frames = self
+            .frame_allocator
+            .allocate_frames(size / PAGE_SIZE)
+            .unwrap_or_else(|| panic!("failed to allocate {size:#x} bytes for the early heap"));
+        let address = self.page_allocator.get_free_address(size);
+        let pages = PageRange::from_virt_addr(address, size);
+        self.mapper
+            .map_range(
+                pages.clone(),
+                frames.clone(),
+                PteFlags::new()
+                    .present(true)
+                    .writable(true)
+                    .no_execute(true),
+                &mut self.frame_allocator,
+            )
+            .unwrap_or_else(|error| {
+                panic!("failed to map early heap {pages:?} to {frames:?}: {error}")
+            });
+
+        info!(
+            "reserved {size:#x} bytes at {:#x} for the early heap, mapped at {address:#x}",
+            frames.start_address()
+        );
+        self.early_heap = Some(EarlyHeap {
+            physical: frames.start_address().value(),
+            virt: address.value(),
+            len: size,
+        });
     }
+
+    /// Maps `page` to a frame filled with [`GUARD_PAGE_POISON`].
+    ///
+    /// The page is writable, so a stack overflow into it doesn't fault, but
+    /// the kernel can detect it by checking the pattern.
+    fn poison_guard_page(&mut self, page: Page) {
+        let frame = self
+            .frame_allocator
+            .allocate_frame()
+            .expect("failed to allocate guard page frame");
+
+        let words = frame.start_address().value() as *mut u64;
+        for index in 0..PAGE_SIZE / size_of::<u64>() {
+            // SAFETY: The frame was just allocated, so it isn't in use, and memory
+            // is identity-mapped.
+            unsafe { words.add(index).write(GUARD_PAGE_POISON) };
+        }
+
+        self.mapper
+            .map(
+                page,
+                frame,
+                PteFlags::new()
+                    .present(true)
+                    .writable(true)
+                    .no_execute(true),
+                &mut self.frame_allocator,
+            )
+            .unwrap_or_else(|error| {
+                panic!("failed to map guard page {page:?} to {frame:?}: {error}")
+            });
+        info!("poisoned guard page at {:#x}", page.start_address().value());
+    }
+
+    /// Maps the framebuffer at the same address in the bootloader's page table
+    /// as in the kernel's, and moves the framebuffer logger to that address.
+    ///
+    /// The bootloader's page table, which is active from here until it jumps
+    /// to the kernel, only keeps the firmware's identity mappings of the first
+    /// 512 GiB, so a framebuffer above that would otherwise be unmapped.
+    fn remap_logger(&mut self, pages: PageRange, frames: FrameRange, flags: PteFlags, len: usize) {
+        let Some(logger) = logger::LOGGER.get() else {
+            return;
+        };
+
+        let address = pages.start_address().value();
+        let bootloader_mapper = self
+            .bootloader_mapper
+            .get_or_insert_with(|| Mapper::current(&mut self.frame_allocator));
+        for (page, frame) in pages.into_iter().zip(frames) {
+            bootloader_mapper
+                .map(page, frame, flags, &mut self.frame_allocator)
+                .unwrap_or_else(|error| {
+                    panic!("failed to map framebuffer {page:?} to {frame:?}: {error}")
+                });
+        }
+
+        // SAFETY: The framebuffer was just mapped at `address` in the current page
+        // table, and the logger is the only user of the framebuffer.
+        let buffer = unsafe { slice::from_raw_parts_mut(address as *mut u8, len) };
+        logger.set_frame_buffer(buffer);
+    }
+
+    /// Maps the physical memory regions requested by `map_phys` options at
+    /// free virtual addresses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a region overlaps memory that the bootloader, kernel, or
+    /// modules may use.
+    fn map_pre_mapped_regions(&mut self, config: &Config) {
+        for region in config.pre_mapped_regions() {
+            let end = region.phys + region.len;
+            if let Some(descriptor) = self.frame_allocator.descriptors().find(|descriptor| {
+                let start = descriptor.phys_start as usize;
+                let descriptor_end = start + descriptor.page_count as usize * PAGE_SIZE;
+                is_in_use(descriptor, config) && start < end && region.phys < descriptor_end
+            }) {
+                panic!(
+                    "map_phys region {:#x}..{end:#x} overlaps {:?} memory at {:#x}",
+                    region.phys, descriptor.ty, descriptor.phys_start
+                );
+            }
+
+            let frames = FrameRange::new(
+                Frame::containing_address(PhysicalAddress::new_canonical(region.phys)),
+                Frame::containing_address(PhysicalAddress::new_canonical(end - 1)),
+            );
+            let start = self.page_allocator.get_free_address(frames.size_in_bytes());
+            let pages = PageRange::from_virt_addr(start, frames.size_in_bytes());
+            let flags = PteFlags::new()
+                .present(true)
+                .writable(region.flags.contains(MappingFlags::WRITABLE))
+                .no_execute(!region.flags.contains(MappingFlags::EXECUTABLE));
+            self.mapper
+                .map_range(
+                    pages.clone(),
+                    frames.clone(),
+                    flags,
+                    &mut self.frame_allocator,
+                )
+                .unwrap_or_else(|error| {
+                    panic!("failed to map map_phys region {pages:?} to {frames:?}: {error}")
+                });
+
+            let virt = start.value() + region.phys % PAGE_SIZE;
+            info!("mapped {:#x}..{end:#x} at {virt:#x}", region.phys);
+            self.pre_mapped_regions[self.num_pre_mapped_regions] =
+                PreMappedRegion { virt, ..*region };
+            self.num_pre_mapped_regions += 1;
+        }
+    }
+
+    /// Maps each of the kernel's `segments` at its physical address, with the
+    /// same flags as at its link address.
+    fn identity_map_kernel(&mut self, segments: &[LoadedSegment]) {
+        for segment in segments {
+            let physical = segment.bytes.as_ptr() as usize;
+            // The segment is already mapped here.
+            if physical as u64 == segment.start {
+                continue;
+            }
+
+            let start = Frame::containing_address(PhysicalAddress::new_canonical(physical));
+            let end = Frame::containing_address(PhysicalAddress::new_canonical(
+                physical + segment.bytes.len() - 1,
+            ));
+            self.page_allocator
+                .mark_pages_as_used(&PageRange::new(identity_page(start), identity_page(end)));
+            info!(
+                "identity-mapping kernel segment {:#x} at {physical:#x}",
+                segment.start
+            );
+            self.identity_map(
+                FrameRange::new(start, end),
+                PteFlags::new()
+                    .present(true)
+                    .writable(segment.is_writable)
+                    .no_execute(!segment.is_executable),
+            );
+        }
+    }
+
+    /// Identity-maps all RAM, except for the kernel's memory, which is mapped at
+    /// the kernel's link addresses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if RAM extends beyond the lower half of the address space, or if
+    /// the mapping could need more than `max_tables` page table frames.
+    fn identity_map_ram(&mut self, max_tables: usize) {
+        let (ram_size, max_phys_addr) = self
+            .frame_allocator
+            .descriptors()
+            .filter(|descriptor| is_ram(descriptor))
+            .fold((0, 0), |(size, max_phys_addr), descriptor| {
+                let len = descriptor.page_count as usize * PAGE_SIZE;
+                let end = descriptor.phys_start as usize + len;
+                (size + len, max_phys_addr.max(end))
+            });
+        assert!(
+            max_phys_addr <= IDENTITY_MAP_LIMIT,
+            "RAM ends at {max_phys_addr:#x}, which is beyond the identity-mappable limit of \
+             {IDENTITY_MAP_LIMIT:#x}"
+        );
+
+        let num_tables = page_table_frames(max_phys_addr);
+        info!(
+            "identity-mapping {ram_size:#x} bytes of RAM ending at {max_phys_addr:#x} using at \
+             most {num_tables} page table frames"
+        );
+        assert!(
+            num_tables <= max_tables,
+            "identity-mapping RAM could need {num_tables} page table frames, which exceeds the \
+             identity_map_max_tables budget of {max_tables}"
+        );
+
+        for descriptor in self.frame_allocator.descriptors() {
+            if !is_ram(descriptor) || descriptor.ty == KERNEL_MEMORY || descriptor.page_count == 0 {
+                continue;
+            }
+
+            let start = Frame::containing_address(PhysicalAddress::new_canonical(
+                descriptor.phys_start as usize,
+            ));
+            let frames = FrameRange::new(start, start + (descriptor.page_count as usize - 1));
+            self.page_allocator.mark_pages_as_used(&PageRange::new(
+                identity_page(*frames.start()),
+                identity_page(*frames.end()),
+            ));
+            self.identity_map(frames, PteFlags::new().present(true).writable(true));
+        }
+        self.identity_mapped = true;
+    }
+}
+
+/// Returns whether the memory described by `descriptor` may be used by the
+/// bootloader, kernel, or modules, and so can't be mapped for a `map_phys`
+/// option.
+fn is_in_use(descriptor: &MemoryDescriptor, config: &Config) -> bool {
+    // The frame allocator allocates from conventional and boot services memory.
+    matches!(
+        descriptor.ty,
+        MemoryType::CONVENTIONAL
+            | MemoryType::LOADER_CODE
+            | MemoryType::LOADER_DATA
+            | MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA
+    ) || descriptor.ty == KERNEL_MEMORY
+        || descriptor.ty == RECLAIMABLE_MEMORY
+        || descriptor.ty == config.module_memory
 }
+
+/// Returns an upper bound on the number of page table frames, excluding the
+/// top-level table, needed to map the first `len` bytes of the address space
+/// with 4 KiB pages.
+const fn page_table_frames(len: usize) -> usize {
+    // Each table in a level maps 512 times as much memory as a table in the
+    // level below.
+    let mut frames = 0;
+    let mut table_span = HUGE_PAGE_SIZE;
+    while table_span <= IDENTITY_MAP_LIMIT {
+        frames += (len + table_span - 1) / table_span;
+        table_span *= 512;
+    }
+    frames
+}
+
+const _: () = {
+    assert!(page_table_frames(0) == 0);
+    assert!(page_table_frames(PAGE_SIZE) == 3);
+    assert!(page_table_frames(HUGE_PAGE_SIZE + 1) == 4);
+    assert!(page_table_frames(IDENTITY_MAP_LIMIT) == 256 * 512 * 512 + 256 * 512 + 256);
+};
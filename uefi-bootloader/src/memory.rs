@@ -1,383 +1,554 @@
 // TODO: Depend on memory_structs
 
-use crate::arch::memory as imp;
-use core::{
-    cmp::{max, min},
-    fmt,
-    iter::Step,
-    mem::MaybeUninit,
-    ops::{Add, AddAssign, Deref, DerefMut, RangeInclusive, Sub, SubAssign},
+use crate::{
+    arch::memory as imp,
+    memtest::MAX_BAD_FRAMES,
+    util::{assume_written, calculate_pages},
 };
-use derive_more::{
-    Add, AddAssign, Binary, BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign,
-    LowerHex, Octal, Sub, SubAssign, UpperHex,
-};
-use paste::paste;
+use core::{fmt, mem::MaybeUninit, ops::Range};
+use log::warn;
 use uefi::table::{
-    boot::{AllocateType, MemoryDescriptor, MemoryMapIter, MemoryType},
+    boot::{AllocateType, MemoryAttribute, MemoryDescriptor, MemoryMapIter, MemoryType},
     Boot, SystemTable,
 };
-use uefi_bootloader_api::{MemoryRegion, MemoryRegionKind};
-use zerocopy::FromBytes;
+use uefi_bootloader_api::{
+    MemoryRegion, MemoryRegionKind, DEFAULT_MODULE_MEMORY_TYPE, KERNEL_MEMORY_TYPE,
+    RECLAIMABLE_MEMORY_TYPE,
+};
+
+mod structs;
 
 pub(crate) use imp::{set_up_arch_specific_mappings, Mapper, PageAllocator, PteFlags};
+pub(crate) use structs::{Frame, FrameRange, Page, PageRange, PhysicalAddress, VirtualAddress};
+pub(crate) use uefi_bootloader_api::PAGE_SIZE;
+
+/// The size of a huge page, which is mapped by a single second-to-last level
+/// page table entry.
+pub(crate) const HUGE_PAGE_SIZE: usize = 512 * PAGE_SIZE;
+const PAGES_PER_HUGE_PAGE: usize = HUGE_PAGE_SIZE / PAGE_SIZE;
+
+/// The memory type of the kernel image and the page tables.
+pub(crate) const KERNEL_MEMORY: MemoryType = MemoryType::custom(KERNEL_MEMORY_TYPE);
+/// The default memory type of the module contents.
+///
+/// This can be changed with the `module_memory_type` option, for kernels that
+/// expect a specific type.
+pub(crate) const MODULE_MEMORY: MemoryType = MemoryType::custom(DEFAULT_MODULE_MEMORY_TYPE);
+/// The memory type of temporary buffers whose contents are copied into the
+/// boot information.
+///
+/// The frame allocator doesn't allocate from this memory, as the buffers are
+/// still read after boot services are exited.
+pub(crate) const RECLAIMABLE_MEMORY: MemoryType = MemoryType::custom(RECLAIMABLE_MEMORY_TYPE);
+
+/// An error encountered while mapping a page.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum MapError {
+    /// The page is already mapped to the given frame.
+    PageAlreadyMapped(Frame),
+    /// A frame for a page table couldn't be allocated.
+    FrameAllocationFailed,
+    /// The page is part of an existing huge page mapping.
+    ParentEntryHugePage,
+    /// The page isn't mapped.
+    PageNotMapped,
+}
 
-pub(crate) const PAGE_SIZE: usize = 4096;
-const MAX_PAGE_NUMBER: usize = usize::MAX / PAGE_SIZE;
-
-pub(crate) const KERNEL_MEMORY: MemoryType = MemoryType::custom(0xffff_ffff);
-
-/// A macro for defining `VirtualAddress` and `PhysicalAddress` structs
-/// and implementing their common traits, which are generally identical.
-macro_rules! implement_address {
-    ($TypeName:ident, $desc:literal, $prefix:literal, $is_canonical:path, $canonicalize:path, $chunk:ident) => {
-        paste! { // using the paste crate's macro for easy concatenation
-
-            #[doc = "A " $desc " memory address, which is a `usize` under the hood."]
-            #[derive(
-                Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
-                Binary, Octal, LowerHex, UpperHex,
-                BitAnd, BitOr, BitXor, BitAndAssign, BitOrAssign, BitXorAssign,
-                Add, Sub, AddAssign, SubAssign,
-                FromBytes,
-            )]
-            #[repr(transparent)]
-            pub(crate) struct $TypeName(usize);
-
-            impl $TypeName {
-                #[doc = "Creates a new `" $TypeName "`, returning an error if the address is not canonical.\n\n \
-                    This is useful for checking whether an address is valid before using it. 
-                    For example, on x86_64, virtual addresses are canonical
-                    if their upper bits `(64:48]` are sign-extended from bit 47,
-                    and physical addresses are canonical if their upper bits `(64:52]` are 0."]
-                pub(crate) fn new(addr: usize) -> Option<$TypeName> {
-                    if $is_canonical(addr) { Some($TypeName(addr)) } else { None }
-                }
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PageAlreadyMapped(frame) => write!(f, "page is already mapped to {frame:?}"),
+            Self::FrameAllocationFailed => write!(f, "failed to allocate frame for page table"),
+            Self::ParentEntryHugePage => write!(f, "page is part of a huge page mapping"),
+            Self::PageNotMapped => write!(f, "page is not mapped"),
+        }
+    }
+}
 
-                #[doc = "Creates a new `" $TypeName "` that is guaranteed to be canonical."]
-                pub(crate) const fn new_canonical(addr: usize) -> $TypeName {
-                    $TypeName($canonicalize(addr))
-                }
+/// Formats the flags as the access they allow, e.g. `R|W|NX`.
+///
+/// Pages are always readable if they're mapped at all.
+impl fmt::Display for PteFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "R")?;
+        if self.is_writable() {
+            write!(f, "|W")?;
+        }
+        if self.is_executable() {
+            write!(f, "|X")
+        } else {
+            write!(f, "|NX")
+        }
+    }
+}
 
-                #[doc = "Creates a new `" $TypeName "` with a value 0."]
-                pub(crate) const fn zero() -> $TypeName {
-                    $TypeName(0)
-                }
+impl PageRange {
+    /// Returns an iterator over the largest aligned chunks of this range, as
+    /// `(start, page_size)` tuples.
+    ///
+    /// Each chunk is a huge page if it's aligned to [`HUGE_PAGE_SIZE`] and
+    /// enough of the range remains, and a single 4 KiB page otherwise.
+    pub(crate) fn aligned_chunks(&self) -> AlignedChunks {
+        AlignedChunks {
+            next: *self.start(),
+            remaining: self.size_in_pages(),
+        }
+    }
+}
 
-                #[doc = "Returns the underlying `usize` value for this `" $TypeName "`."]
-                #[inline]
-                pub(crate) const fn value(&self) -> usize {
-                    self.0
-                }
+/// An iterator over the aligned chunks of a [`PageRange`], created by
+/// [`PageRange::aligned_chunks`].
+pub(crate) struct AlignedChunks {
+    next: Page,
+    remaining: usize,
+}
 
-                #[doc = "Returns the offset from the " $chunk " boundary specified by this `"
-                    $TypeName ".\n\n \
-                    For example, if the [`PAGE_SIZE`] is 4096 (4KiB), then this will return
-                    the least significant 12 bits `(12:0]` of this `" $TypeName "`."]
-                pub(crate) const fn [<$chunk _offset>](&self) -> usize {
-                    self.0 & (PAGE_SIZE - 1)
-                }
-            }
-            impl fmt::Debug for $TypeName {
-                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                    write!(f, concat!($prefix, "{:#X}"), self.0)
-                }
-            }
-            impl fmt::Display for $TypeName {
-                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                    write!(f, "{:?}", self)
-                }
-            }
-            impl fmt::Pointer for $TypeName {
-                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                    write!(f, "{:?}", self)
-                }
-            }
-            impl Add<usize> for $TypeName {
-                type Output = $TypeName;
-                fn add(self, rhs: usize) -> $TypeName {
-                    $TypeName::new_canonical(self.0.saturating_add(rhs))
-                }
-            }
-            impl AddAssign<usize> for $TypeName {
-                fn add_assign(&mut self, rhs: usize) {
-                    *self = $TypeName::new_canonical(self.0.saturating_add(rhs));
-                }
-            }
-            impl Sub<usize> for $TypeName {
-                type Output = $TypeName;
-                fn sub(self, rhs: usize) -> $TypeName {
-                    $TypeName::new_canonical(self.0.saturating_sub(rhs))
-                }
-            }
-            impl SubAssign<usize> for $TypeName {
-                fn sub_assign(&mut self, rhs: usize) {
-                    *self = $TypeName::new_canonical(self.0.saturating_sub(rhs));
-                }
-            }
-            impl From<$TypeName> for usize {
-                #[inline]
-                fn from(value: $TypeName) -> Self {
-                    value.0
-                }
-            }
+impl Iterator for AlignedChunks {
+    type Item = (Page, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
         }
-    };
+        let start = self.next;
+        let num_pages = chunk_pages(start.number(), self.remaining);
+        self.next += num_pages;
+        self.remaining -= num_pages;
+        Some((start, num_pages * PAGE_SIZE))
+    }
 }
 
-implement_address!(
-    VirtualAddress,
-    "virtual",
-    "v",
-    imp::is_canonical_virtual_address,
-    imp::canonicalize_virtual_address,
-    page
-);
-
-implement_address!(
-    PhysicalAddress,
-    "physical",
-    "p",
-    imp::is_canonical_physical_address,
-    imp::canonicalize_physical_address,
-    frame
-);
-
-/// A macro for defining `Page` and `Frame` structs
-/// and implementing their common traits, which are generally identical.
-macro_rules! implement_page_frame {
-    ($TypeName:ident, $desc:literal, $prefix:literal, $address:ident) => {
-        paste! { // using the paste crate's macro for easy concatenation
-
-            #[doc = "A `" $TypeName "` is a chunk of **" $desc "** memory aligned to a [`PAGE_SIZE`] boundary."]
-            #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-            pub(crate) struct $TypeName {
-                pub(crate) number: usize,
-            }
+/// Returns the number of pages in the largest aligned chunk starting at the
+/// page with the given number, if `remaining` pages are left in the range.
+const fn chunk_pages(number: usize, remaining: usize) -> usize {
+    if remaining >= PAGES_PER_HUGE_PAGE && number % PAGES_PER_HUGE_PAGE == 0 {
+        PAGES_PER_HUGE_PAGE
+    } else {
+        1
+    }
+}
 
-            impl $TypeName {
-                #[doc = "Returns the `" $address "` at the start of this `" $TypeName "`."]
-                pub(crate) const fn start_address(&self) -> $address {
-                    $address::new_canonical(self.number * PAGE_SIZE)
-                }
+const _: () = {
+    /// Returns the number of huge and 4 KiB chunks in the range of `len` pages
+    /// starting at page `start`.
+    const fn count_chunks(mut start: usize, mut len: usize) -> (usize, usize) {
+        let (mut huge, mut small) = (0, 0);
+        while len > 0 {
+            let num_pages = chunk_pages(start, len);
+            if num_pages == PAGES_PER_HUGE_PAGE {
+                huge += 1;
+            } else {
+                small += 1;
+            }
+            start += num_pages;
+            len -= num_pages;
+        }
+        (huge, small)
+    }
 
-                #[doc = "Returns the number of this `" $TypeName "`."]
-                #[inline(always)]
-                pub(crate) const fn number(&self) -> usize {
-                    self.number
-                }
+    // A fully aligned range.
+    let (huge, small) = count_chunks(512, 1024);
+    assert!(huge == 2 && small == 0);
+    // A partially aligned range, with 4 KiB edges.
+    let (huge, small) = count_chunks(511, 514);
+    assert!(huge == 1 && small == 2);
+    // An aligned range that is smaller than a huge page.
+    let (huge, small) = count_chunks(512, 511);
+    assert!(huge == 0 && small == 511);
+    // An empty range.
+    let (huge, small) = count_chunks(512, 0);
+    assert!(huge == 0 && small == 0);
+};
 
-                #[doc = "Returns the `" $TypeName "` containing the given `" $address "`."]
-                pub(crate) const fn containing_address(addr: $address) -> $TypeName {
-                    $TypeName {
-                        number: addr.value() / PAGE_SIZE,
-                    }
-                }
+impl Mapper {
+    /// Maps `pages` to `frames`, using huge pages where the alignment of both
+    /// ranges permits.
+    ///
+    /// Pages mapped before an error are left mapped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pages` and `frames` don't have the same length.
+    pub(crate) fn map_range<T>(
+        &mut self,
+        pages: PageRange,
+        frames: FrameRange,
+        flags: PteFlags,
+        frame_allocator: &mut T,
+    ) -> Result<(), MapError>
+    where
+        T: FrameAllocator,
+    {
+        assert_eq!(
+            pages.size_in_pages(),
+            frames.size_in_frames(),
+            "page range {pages:?} and frame range {frames:?} have different lengths"
+        );
+
+        // Huge pages can only be used if the pages and frames are aligned at the
+        // same points.
+        let offset = frames.start().number().wrapping_sub(pages.start().number());
+        if offset % PAGES_PER_HUGE_PAGE != 0 {
+            for (page, frame) in pages.into_iter().zip(frames) {
+                self.map(page, frame, flags, frame_allocator)?;
             }
-            impl fmt::Debug for $TypeName {
-                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                    write!(f, concat!(stringify!($TypeName), "(", $prefix, "{:#X})"), self.start_address())
-                }
+            return Ok(());
+        }
+
+        for (page, size) in pages.aligned_chunks() {
+            let frame = *frames.start() + (page.number() - pages.start().number());
+            if size == HUGE_PAGE_SIZE {
+                self.map_huge(page, frame, flags, frame_allocator)?;
+            } else {
+                self.map(page, frame, flags, frame_allocator)?;
             }
-            impl Add<usize> for $TypeName {
-                type Output = $TypeName;
-                fn add(self, rhs: usize) -> $TypeName {
-                    // cannot exceed max page number (which is also max frame number)
-                    $TypeName {
-                        number: min(MAX_PAGE_NUMBER, self.number.saturating_add(rhs)),
-                    }
-                }
+        }
+        Ok(())
+    }
+
+    /// Maps each frame in `frames` to the page with the same number.
+    pub(crate) fn identity_map<T>(
+        &mut self,
+        frames: FrameRange,
+        flags: PteFlags,
+        frame_allocator: &mut T,
+    ) -> Result<(), MapError>
+    where
+        T: FrameAllocator,
+    {
+        let pages = PageRange::new(identity_page(*frames.start()), identity_page(*frames.end()));
+        self.map_range(pages, frames, flags, frame_allocator)
+    }
+}
+
+impl PageAllocator {
+    /// Allocates `len` bytes of virtual memory starting at `hint`, or wherever
+    /// [`get_free_address`](Self::get_free_address) would allocate them if
+    /// `hint` isn't page aligned or the memory there isn't free.
+    ///
+    /// Like `get_free_address`, this reserves whole top-level page table
+    /// entries. Allocations that don't use a hint are deterministic, as they
+    /// always take the lowest free entries, so they only depend on the
+    /// kernel's segments and the preceding allocations.
+    pub(crate) fn get_free_address_at(
+        &mut self,
+        hint: VirtualAddress,
+        len: usize,
+    ) -> VirtualAddress {
+        let pages = hint
+            .value()
+            .checked_add(len.max(1) - 1)
+            .and_then(VirtualAddress::new)
+            .filter(|_| hint.value() % PAGE_SIZE == 0)
+            .map(|end| {
+                PageRange::new(
+                    Page::containing_address(hint),
+                    Page::containing_address(end),
+                )
+            });
+        match pages {
+            Some(pages) if self.are_pages_free(&pages) => {
+                self.mark_pages_as_used(&pages);
+                hint
             }
-            impl AddAssign<usize> for $TypeName {
-                fn add_assign(&mut self, rhs: usize) {
-                    *self = $TypeName {
-                        number: min(MAX_PAGE_NUMBER, self.number.saturating_add(rhs)),
-                    };
-                }
+            _ => {
+                warn!("can't allocate {len:#x} bytes at {hint:?}, allocating elsewhere");
+                self.get_free_address(len)
             }
-            impl Sub<usize> for $TypeName {
-                type Output = $TypeName;
-                fn sub(self, rhs: usize) -> $TypeName {
-                    $TypeName {
-                        number: self.number.saturating_sub(rhs),
+        }
+    }
+
+    /// Allocates `len` bytes of virtual memory with a guard page on either
+    /// side, returning the lower guard page, the allocated pages, and the upper
+    /// guard page.
+    ///
+    /// The guard pages lie within the same allocation, so they are never
+    /// returned by subsequent allocations.
+    ///
+    /// If `hint` is set, the allocated pages start at `hint` if possible.
+    pub(crate) fn get_free_pages_with_guards(
+        &mut self,
+        len: usize,
+        hint: Option<VirtualAddress>,
+    ) -> (Page, PageRange, Page) {
+        let num_pages = calculate_pages(len);
+        let len = (num_pages + 2) * PAGE_SIZE;
+        let start = match hint {
+            Some(hint) => {
+                let lower_guard_page = hint
+                    .value()
+                    .checked_sub(PAGE_SIZE)
+                    .and_then(VirtualAddress::new);
+                match lower_guard_page {
+                    Some(lower_guard_page) => self.get_free_address_at(lower_guard_page, len),
+                    None => {
+                        warn!("no room for a guard page below {hint:?}");
+                        self.get_free_address(len)
                     }
                 }
             }
-            impl SubAssign<usize> for $TypeName {
-                fn sub_assign(&mut self, rhs: usize) {
-                    *self = $TypeName {
-                        number: self.number.saturating_sub(rhs),
-                    };
-                }
-            }
-            #[doc = "Implementing `Step` allows `" $TypeName "` to be used in an [`Iterator`]."]
-            impl Step for $TypeName {
-                #[inline]
-                fn steps_between(start: &$TypeName, end: &$TypeName) -> Option<usize> {
-                    Step::steps_between(&start.number, &end.number)
-                }
-                #[inline]
-                fn forward_checked(start: $TypeName, count: usize) -> Option<$TypeName> {
-                    Step::forward_checked(start.number, count).map(|n| $TypeName { number: n })
-                }
-                #[inline]
-                fn backward_checked(start: $TypeName, count: usize) -> Option<$TypeName> {
-                    Step::backward_checked(start.number, count).map(|n| $TypeName { number: n })
-                }
-            }
-        }
-    };
-}
-
-implement_page_frame!(Page, "virtual", "v", VirtualAddress);
-implement_page_frame!(Frame, "physical", "p", PhysicalAddress);
-
-/// A macro for defining `PageRange` and `FrameRange` structs
-/// and implementing their common traits, which are generally identical.
-macro_rules! implement_page_frame_range {
-    ($TypeName:ident, $desc:literal, $short:ident, $chunk:ident, $address:ident) => {
-        paste! { // using the paste crate's macro for easy concatenation
+            None => self.get_free_address(len),
+        };
 
-            #[doc = "A range of [`" $chunk "`]s that are contiguous in " $desc " memory."]
-            #[derive(Clone, PartialEq, Eq)]
-            pub(crate) struct $TypeName(RangeInclusive<$chunk>);
+        let lower_guard_page = Page::containing_address(start);
+        let upper_guard_page = lower_guard_page + num_pages + 1;
+        let pages = PageRange::new(lower_guard_page + 1, upper_guard_page - 1);
 
-            impl $TypeName {
-                #[doc = "Creates a new range of [`" $chunk "`]s that spans from `start` to `end`, both inclusive bounds."]
-                pub(crate) const fn new(start: $chunk, end: $chunk) -> $TypeName {
-                    $TypeName(RangeInclusive::new(start, end))
-                }
-
-                #[doc = "Creates a `" $TypeName "` that will always yield `None` when iterated."]
-                pub(crate) const fn empty() -> $TypeName {
-                    $TypeName::new($chunk { number: 1 }, $chunk { number: 0 })
-                }
+        (lower_guard_page, pages, upper_guard_page)
+    }
+}
 
-                #[doc = "A convenience method for creating a new `" $TypeName "` that spans \
-                    all [`" $chunk "`]s from the given [`" $address "`] to an end bound based on the given size."]
-                pub(crate) fn [<from_ $short _addr>](starting_addr: $address, size_in_bytes: usize) -> $TypeName {
-                    assert!(size_in_bytes > 0);
-                    let start = $chunk::containing_address(starting_addr);
-                    // The end bound is inclusive, hence the -1. Parentheses are needed to avoid overflow.
-                    let end = $chunk::containing_address(starting_addr + (size_in_bytes - 1));
-                    $TypeName::new(start, end)
-                }
+/// The number of pages spanned by a top-level page table entry.
+const PAGES_PER_TOP_LEVEL_ENTRY: usize = 512 * 512 * 512;
+
+/// An iterator over the pages spanned by each run of allocated top-level page
+/// table entries, created by [`PageAllocator::used_ranges`].
+pub(crate) struct UsedRanges<'a> {
+    entries: &'a [bool],
+    index: usize,
+    /// Returns the address of the first page spanned by an entry.
+    entry_address: fn(usize) -> VirtualAddress,
+    /// The first entry of the upper half of the address space, which runs
+    /// don't extend across because the halves aren't contiguous.
+    upper_half: usize,
+}
 
-                #[doc = "Returns the [`" $address "`] of the starting [`" $chunk "`] in this `" $TypeName "`."]
-                pub(crate) const fn start_address(&self) -> $address {
-                    self.0.start().start_address()
-                }
+impl<'a> UsedRanges<'a> {
+    pub(crate) fn new(
+        entries: &'a [bool],
+        entry_address: fn(usize) -> VirtualAddress,
+        upper_half: usize,
+    ) -> Self {
+        Self {
+            entries,
+            index: 0,
+            entry_address,
+            upper_half,
+        }
+    }
+}
 
-                #[doc = "Returns the number of [`" $chunk "`]s covered by this iterator.\n\n \
-                    Use this instead of [`Iterator::count()`] method. \
-                    This is instant, because it doesn't need to iterate over each entry, unlike normal iterators."]
-                pub(crate) const fn [<size_in_ $chunk:lower s>](&self) -> usize {
-                    // add 1 because it's an inclusive range
-                    (self.0.end().number + 1).saturating_sub(self.0.start().number)
-                }
+impl Iterator for UsedRanges<'_> {
+    type Item = PageRange;
 
-                /// Returns the size of this range in number of bytes.
-                pub(crate) const fn size_in_bytes(&self) -> usize {
-                    self.[<size_in_ $chunk:lower s>]() * PAGE_SIZE
-                }
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.index + self.entries[self.index..].iter().position(|used| *used)?;
+        let end = if start < self.upper_half {
+            self.upper_half
+        } else {
+            self.entries.len()
+        };
+        let len = self.entries[start..end]
+            .iter()
+            .take_while(|used| **used)
+            .count();
+        self.index = start + len;
+
+        let first = Page::containing_address((self.entry_address)(start));
+        Some(PageRange::new(
+            first,
+            first + (len * PAGES_PER_TOP_LEVEL_ENTRY - 1),
+        ))
+    }
+}
 
-                #[doc = "Returns `true` if this `" $TypeName "` contains the given [`" $address "`]."]
-                pub(crate) fn contains_address(&self, addr: $address) -> bool {
-                    self.0.contains(&$chunk::containing_address(addr))
-                }
+/// Returns the page with the same number as `frame`.
+pub(crate) const fn identity_page(frame: Frame) -> Page {
+    Page::containing_address(VirtualAddress::new_canonical(frame.start_address().value()))
+}
 
-                #[doc = "Returns the offset of the given [`" $address "`] within this `" $TypeName "`, \
-                    i.e., `addr - self.start_address()`.\n\n \
-                    If the given `addr` is not covered by this range of [`" $chunk "`]s, this returns `None`.\n\n \
-                    # Examples\n \
-                    If the range covers addresses `0x2000` to `0x4000`, then `offset_of_address(0x3500)` would return `Some(0x1500)`."]
-                pub(crate) fn offset_of_address(&self, addr: $address) -> Option<usize> {
-                    if self.contains_address(addr) {
-                        Some(addr.value() - self.start_address().value())
-                    } else {
-                        None
-                    }
-                }
+const _: () = {
+    let frame = Frame::containing_address(PhysicalAddress::new_canonical(0x1234_5000));
+    assert!(identity_page(frame).number() == frame.number());
+    let frame = Frame::containing_address(PhysicalAddress::zero());
+    assert!(identity_page(frame).number() == frame.number());
+};
 
-                #[doc = "Returns the [`" $address "`] at the given `offset` into this `" $TypeName "`within this `" $TypeName "`, \
-                    i.e., `addr - self.start_address()`.\n\n \
-                    If the given `offset` is not within this range of [`" $chunk "`]s, this returns `None`.\n\n \
-                    # Examples\n \
-                    If the range covers addresses `0x2000` to `0x4000`, then `address_at_offset(0x1500)` would return `Some(0x3500)`."]
-                pub(crate) fn address_at_offset(&self, offset: usize) -> Option<$address> {
-                    if offset <= self.size_in_bytes() {
-                        Some(self.start_address() + offset)
-                    }
-                    else {
-                        None
-                    }
-                }
+/// Returns the pages spanned by the `len` bytes at `virtual_start`, and the
+/// frames spanned by the `len` bytes at `physical_start`.
+///
+/// `len` must be non-zero. The ranges only have the same length if the bytes
+/// cross the same number of page boundaries at both addresses, which is
+/// guaranteed if the addresses have the same offset within their page.
+pub(crate) const fn spanned_ranges(
+    virtual_start: VirtualAddress,
+    physical_start: PhysicalAddress,
+    len: usize,
+) -> (PageRange, FrameRange) {
+    let virtual_end_inclusive = VirtualAddress::new_canonical(virtual_start.value() + len - 1);
+    let physical_end_inclusive = PhysicalAddress::new_canonical(physical_start.value() + len - 1);
+    (
+        PageRange::new(
+            Page::containing_address(virtual_start),
+            Page::containing_address(virtual_end_inclusive),
+        ),
+        FrameRange::new(
+            Frame::containing_address(physical_start),
+            Frame::containing_address(physical_end_inclusive),
+        ),
+    )
+}
 
-                #[doc = "Returns a new separate `" $TypeName "` that is extended to include the given [`" $chunk "`]."]
-                pub(crate) fn to_extended(&self, to_include: $chunk) -> $TypeName {
-                    // if the current range was empty, return a new range containing only the given page/frame
-                    if self.is_empty() {
-                        return $TypeName::new(to_include.clone(), to_include);
-                    }
-                    let start = min(self.0.start(), &to_include);
-                    let end = max(self.0.end(), &to_include);
-                    $TypeName::new(start.clone(), end.clone())
-                }
+const _: () = {
+    // The same page offset, crossing a page boundary.
+    let (pages, frames) = spanned_ranges(
+        VirtualAddress::new_canonical(0x1ff0),
+        PhysicalAddress::new_canonical(0x5ff0),
+        0x20,
+    );
+    assert!(pages.size_in_pages() == 2 && frames.size_in_frames() == 2);
+    // Different page offsets, so only the virtual range crosses a page
+    // boundary. `map_segment` rejects this rather than under-mapping.
+    let (pages, frames) = spanned_ranges(
+        VirtualAddress::new_canonical(0x1ff0),
+        PhysicalAddress::new_canonical(0x5010),
+        0x20,
+    );
+    assert!(pages.size_in_pages() == 2 && frames.size_in_frames() == 1);
+    // A single byte.
+    let (pages, frames) = spanned_ranges(
+        VirtualAddress::new_canonical(0x1fff),
+        PhysicalAddress::new_canonical(0x5000),
+        1,
+    );
+    assert!(pages.size_in_pages() == 1 && frames.size_in_frames() == 1);
+};
 
-                #[doc = "Returns an inclusive `" $TypeName "` representing the [`" $chunk "`]s that overlap \
-                    across this `" $TypeName "` and the given other `" $TypeName "`.\n\n \
-                    If there is no overlap between the two ranges, `None` is returned."]
-                pub(crate) fn overlap(&self, other: &$TypeName) -> Option<$TypeName> {
-                    let starts = max(*self.start(), *other.start());
-                    let ends   = min(*self.end(),   *other.end());
-                    if starts <= ends {
-                        Some($TypeName::new(starts, ends))
-                    } else {
-                        None
-                    }
-                }
-            }
-            impl fmt::Debug for $TypeName {
-                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                    write!(f, "{:?}", self.0)
-                }
-            }
-            impl Deref for $TypeName {
-                type Target = RangeInclusive<$chunk>;
-                fn deref(&self) -> &RangeInclusive<$chunk> {
-                    &self.0
-                }
-            }
-            impl DerefMut for $TypeName {
-                fn deref_mut(&mut self) -> &mut RangeInclusive<$chunk> {
-                    &mut self.0
-                }
-            }
-            impl IntoIterator for $TypeName {
-                type Item = $chunk;
-                type IntoIter = RangeInclusive<$chunk>;
-                fn into_iter(self) -> Self::IntoIter {
-                    self.0
-                }
-            }
-        }
-    };
+const fn descriptor_kind(memory_descriptor: &MemoryDescriptor) -> MemoryRegionKind {
+    region_kind(memory_descriptor.ty)
 }
 
-implement_page_frame_range!(PageRange, "virtual", virt, Page, VirtualAddress);
-implement_page_frame_range!(FrameRange, "physical", phys, Frame, PhysicalAddress);
-
-fn descriptor_kind(memory_descriptor: &MemoryDescriptor) -> MemoryRegionKind {
-    match memory_descriptor.ty {
+/// Returns the kind of region reported to the kernel for memory of type `ty`.
+const fn region_kind(ty: MemoryType) -> MemoryRegionKind {
+    match ty {
         MemoryType::CONVENTIONAL
         | MemoryType::LOADER_CODE
         | MemoryType::LOADER_DATA
         | MemoryType::BOOT_SERVICES_CODE
         | MemoryType::BOOT_SERVICES_DATA => MemoryRegionKind::Usable,
+        MemoryType::ACPI_RECLAIM => MemoryRegionKind::AcpiReclaimable,
+        MemoryType::ACPI_NON_VOLATILE => MemoryRegionKind::AcpiNvs,
+        RECLAIMABLE_MEMORY => MemoryRegionKind::BootloaderReclaimable,
         tag => MemoryRegionKind::UnknownUefi(tag.0),
     }
 }
 
+// ACPI and temporary bootloader memory must be reported distinctly, so the
+// kernel knows which memory it can reclaim once it has parsed the ACPI tables
+// and boot information.
+const _: () = {
+    assert!(matches!(
+        region_kind(MemoryType::ACPI_RECLAIM),
+        MemoryRegionKind::AcpiReclaimable
+    ));
+    assert!(matches!(
+        region_kind(MemoryType::ACPI_NON_VOLATILE),
+        MemoryRegionKind::AcpiNvs
+    ));
+    assert!(matches!(
+        region_kind(RECLAIMABLE_MEMORY),
+        MemoryRegionKind::BootloaderReclaimable
+    ));
+};
+
+/// Returns whether the memory described by `memory_descriptor` is RAM.
+pub(crate) fn is_ram(memory_descriptor: &MemoryDescriptor) -> bool {
+    !matches!(
+        memory_descriptor.ty,
+        MemoryType::RESERVED
+            | MemoryType::UNUSABLE
+            | MemoryType::MMIO
+            | MemoryType::MMIO_PORT_SPACE
+            | MemoryType::PAL_CODE
+    )
+}
+
+/// Calls `push` with each part of `region` that lies outside all of the
+/// `excluded` ranges.
+fn exclude_all(
+    region: MemoryRegion,
+    excluded: &[Range<usize>],
+    push: &mut impl FnMut(MemoryRegion),
+) {
+    match excluded.split_first() {
+        Some((first, rest)) => {
+            for part in exclude(region, first).into_iter().flatten() {
+                exclude_all(part, rest, push);
+            }
+        }
+        None => push(region),
+    }
+}
+
+/// Returns the parts of `region` that lie outside of `excluded`.
+fn exclude(region: MemoryRegion, excluded: &Range<usize>) -> [Option<MemoryRegion>; 2] {
+    let end = region.start + region.len;
+    if excluded.is_empty() || excluded.end <= region.start || end <= excluded.start {
+        return [Some(region), None];
+    }
+
+    let before = (region.start < excluded.start).then(|| MemoryRegion {
+        start: region.start,
+        len: excluded.start - region.start,
+        kind: region.kind,
+    });
+    let after = (excluded.end < end).then(|| MemoryRegion {
+        start: excluded.end,
+        len: end - excluded.end,
+        kind: region.kind,
+    });
+    [before, after]
+}
+
+/// Returns whether [`LegacyFrameAllocator`] allocates frames from the memory
+/// described by `memory_descriptor`.
+const fn is_allocatable(memory_descriptor: &MemoryDescriptor) -> bool {
+    // Allocating frames below 1MiB causes problems during AP boot.
+    matches!(descriptor_kind(memory_descriptor), MemoryRegionKind::Usable)
+        && memory_descriptor.phys_start >= 0x1_0000
+}
+
+/// Returns the number of bytes that [`LegacyFrameAllocator`] can allocate from
+/// the memory described by `memory_descriptor`, if none of it has been
+/// allocated yet.
+const fn allocatable_bytes(memory_descriptor: &MemoryDescriptor) -> usize {
+    if is_allocatable(memory_descriptor) {
+        memory_descriptor.page_count as usize * PAGE_SIZE
+    } else {
+        0
+    }
+}
+
+const _: () = {
+    const fn descriptor(ty: MemoryType, phys_start: u64, page_count: u64) -> MemoryDescriptor {
+        MemoryDescriptor {
+            ty,
+            phys_start,
+            virt_start: 0,
+            page_count,
+            att: MemoryAttribute::empty(),
+        }
+    }
+
+    let descriptors = [
+        descriptor(MemoryType::CONVENTIONAL, 0x10_0000, 16),
+        descriptor(MemoryType::BOOT_SERVICES_DATA, 0x20_0000, 2),
+        // Usable memory that is too low to allocate from.
+        descriptor(MemoryType::CONVENTIONAL, 0x1000, 4),
+        // Memory that must not be allocated from.
+        descriptor(RECLAIMABLE_MEMORY, 0x30_0000, 8),
+        descriptor(MemoryType::ACPI_RECLAIM, 0x40_0000, 8),
+        descriptor(MemoryType::CONVENTIONAL, 0x50_0000, 0),
+    ];
+    let mut total = 0;
+    let mut i = 0;
+    while i < descriptors.len() {
+        total += allocatable_bytes(&descriptors[i]);
+        i += 1;
+    }
+    assert!(total == 18 * PAGE_SIZE);
+};
+
 pub(crate) trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
 }
@@ -404,9 +575,37 @@ pub(crate) struct LegacyFrameAllocator {
     current_descriptor: Option<CurrentDescriptor>,
 }
 
+#[derive(Clone)]
 struct CurrentDescriptor {
     descriptor: &'static MemoryDescriptor,
     next_frame: Frame,
+    /// The frame after the last frame in the descriptor.
+    ///
+    /// This is computed once, so that allocating from the current descriptor,
+    /// which is how nearly all frames are allocated, is a comparison and an
+    /// increment.
+    end_frame: Frame,
+}
+
+impl CurrentDescriptor {
+    fn new(descriptor: &'static MemoryDescriptor) -> Self {
+        let next_frame = Frame::containing_address(PhysicalAddress::new_canonical(
+            descriptor.phys_start as usize,
+        ));
+        Self {
+            descriptor,
+            next_frame,
+            end_frame: next_frame + descriptor.page_count as usize,
+        }
+    }
+}
+
+/// A point in a [`LegacyFrameAllocator`]'s allocations, to which it can be
+/// rewound using [`LegacyFrameAllocator::reset_to`].
+#[derive(Clone)]
+pub(crate) struct AllocatorMark {
+    memory_map: MemoryMapIter<'static>,
+    current_descriptor: Option<CurrentDescriptor>,
 }
 
 impl LegacyFrameAllocator {
@@ -418,22 +617,104 @@ impl LegacyFrameAllocator {
         }
     }
 
+    /// Returns an iterator over the descriptors in the memory map.
+    pub(crate) fn descriptors(&self) -> MemoryMapIter<'static> {
+        self.original.clone()
+    }
+
+    /// Returns the maximum number of regions in the memory map written by
+    /// [`construct_memory_map`](Self::construct_memory_map).
+    ///
+    /// This only depends on the original memory map, so it stays the same
+    /// regardless of how many frames are allocated, or whether the allocator is
+    /// rewound with [`reset_to`](Self::reset_to). Memory for the memory map can
+    /// therefore be allocated from this allocator before the map is written.
     pub(crate) fn len(&self) -> usize {
-        // At most, one descriptor can be split.
-        self.original.clone().count() + 2
+        // At most, one descriptor can be split by the allocator, and one
+        // descriptor can be split around the framebuffer, which gets its own
+        // region. The same goes for each bad frame found by the memory test.
+        self.original.clone().count() + 4 + 2 * MAX_BAD_FRAMES
     }
 
-    fn allocate_frame_from_current(&mut self) -> Option<Frame> {
-        let current_descriptor = self.current_descriptor.as_mut()?;
+    /// Returns the ranges of frames that the allocator hasn't allocated yet.
+    pub(crate) fn unallocated_frames(&self) -> impl Iterator<Item = FrameRange> + '_ {
+        let current = self
+            .current_descriptor
+            .as_ref()
+            .filter(|current| current.next_frame < current.end_frame)
+            .map(|current| FrameRange::new(current.next_frame, current.end_frame - 1));
+        let later = self
+            .memory_map
+            .clone()
+            .filter(|descriptor| is_allocatable(descriptor) && descriptor.page_count > 0)
+            .map(|descriptor| {
+                let start = CurrentDescriptor::new(descriptor);
+                FrameRange::new(start.next_frame, start.end_frame - 1)
+            });
+        current.into_iter().chain(later)
+    }
 
-        let start_address =
-            PhysicalAddress::new_canonical(current_descriptor.descriptor.phys_start as usize);
-        let end_address =
-            start_address + (current_descriptor.descriptor.page_count as usize * PAGE_SIZE);
+    /// Returns the number of bytes that the allocator can still allocate.
+    pub(crate) fn remaining_usable_bytes(&self) -> usize {
+        let current_remaining = self.current_descriptor.as_ref().map_or(0, |current| {
+            current.end_frame.start_address().value() - current.next_frame.start_address().value()
+        });
+        let later_remaining: usize = self.memory_map.clone().map(allocatable_bytes).sum();
+        current_remaining + later_remaining
+    }
+
+    /// Returns the current point in the allocator's allocations.
+    ///
+    /// This can be used to perform allocations in a dry run, for example to
+    /// compute sizes, and then rewind the allocator using
+    /// [`reset_to`](Self::reset_to).
+    pub(crate) fn mark(&self) -> AllocatorMark {
+        AllocatorMark {
+            memory_map: self.memory_map.clone(),
+            current_descriptor: self.current_descriptor.clone(),
+        }
+    }
 
-        let end_frame = Frame::containing_address(end_address - 1);
+    /// Rewinds the allocator to `mark`, so that the frames allocated since the
+    /// mark was created are allocated again.
+    ///
+    /// # Safety
+    ///
+    /// `mark` must have been created by this allocator, and the frames
+    /// allocated since it was created must no longer be used.
+    pub(crate) unsafe fn reset_to(&mut self, mark: AllocatorMark) {
+        self.memory_map = mark.memory_map;
+        self.current_descriptor = mark.current_descriptor;
+    }
+
+    /// Allocates `count` physically contiguous frames.
+    ///
+    /// If the current descriptor doesn't have enough frames left, the
+    /// allocator moves on to the next descriptor that does. The frames skipped
+    /// over are reported as bootloader memory.
+    pub(crate) fn allocate_frames(&mut self, count: usize) -> Option<FrameRange> {
+        assert!(count > 0, "can't allocate zero frames");
+        loop {
+            if let Some(current_descriptor) = self.current_descriptor.as_mut() {
+                let remaining =
+                    current_descriptor.end_frame.number() - current_descriptor.next_frame.number();
+                if remaining >= count {
+                    let start = current_descriptor.next_frame;
+                    current_descriptor.next_frame += count;
+                    return Some(FrameRange::new(start, start + (count - 1)));
+                }
+            }
+            let descriptor = self
+                .memory_map
+                .find(|descriptor| is_allocatable(descriptor))?;
+            self.current_descriptor = Some(CurrentDescriptor::new(descriptor));
+        }
+    }
 
-        if current_descriptor.next_frame <= end_frame {
+    #[inline]
+    fn allocate_frame_from_current(&mut self) -> Option<Frame> {
+        let current_descriptor = self.current_descriptor.as_mut()?;
+        if current_descriptor.next_frame < current_descriptor.end_frame {
             let frame = current_descriptor.next_frame;
             current_descriptor.next_frame += 1;
             Some(frame)
@@ -442,15 +723,58 @@ impl LegacyFrameAllocator {
         }
     }
 
+    /// Moves to the next allocatable descriptor and allocates a frame from it.
+    #[cold]
+    fn allocate_frame_from_next(&mut self) -> Option<Frame> {
+        while let Some(descriptor) = self.memory_map.next() {
+            if !is_allocatable(descriptor) {
+                continue;
+            }
+
+            self.current_descriptor = Some(CurrentDescriptor::new(descriptor));
+            if let Some(frame) = self.allocate_frame_from_current() {
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+
+    /// Writes the memory map for the kernel to `memory_map`.
+    ///
+    /// Regions aren't merged, so each ACPI reclaimable and ACPI NVS
+    /// descriptor is reported as its own region.
+    ///
+    /// The physical memory in `frame_buffer` is reported as a separate
+    /// [`MemoryRegionKind::FrameBuffer`] region, even if it overlaps other
+    /// regions. Each of the `bad_frames` found by the memory test is reported
+    /// as a separate region of unusable memory.
     pub(crate) fn construct_memory_map(
         self,
         memory_map: &mut [MaybeUninit<MemoryRegion>],
+        frame_buffer: Option<Range<usize>>,
+        bad_frames: &[Frame],
     ) -> &mut [MemoryRegion] {
+        const EMPTY: Range<usize> = 0..0;
+
+        assert!(bad_frames.len() <= MAX_BAD_FRAMES, "too many bad frames");
         // We definetly allocated at least one frame, right?
         let current_descriptor = self
             .current_descriptor
             .expect("failed to get current descriptor");
+        let mut excluded = [EMPTY; MAX_BAD_FRAMES + 1];
+        excluded[0] = frame_buffer.clone().unwrap_or(0..0);
+        for (range, frame) in excluded[1..].iter_mut().zip(bad_frames) {
+            let start = frame.start_address().value();
+            *range = start..start + PAGE_SIZE;
+        }
         let mut index = 0;
+        let mut push = |region| {
+            exclude_all(region, &excluded, &mut |region| {
+                memory_map[index].write(region);
+                index += 1;
+            });
+        };
         let mut iterated_through_used_descriptors = false;
 
         for descriptor in self.original {
@@ -458,76 +782,65 @@ impl LegacyFrameAllocator {
                 || descriptor.phys_start < 0x1_0000
                 || descriptor_kind(descriptor) != MemoryRegionKind::Usable
             {
-                memory_map[index].write(MemoryRegion {
+                push(MemoryRegion {
                     start: descriptor.phys_start as usize,
                     len: descriptor.page_count as usize * PAGE_SIZE,
                     kind: descriptor_kind(descriptor),
                 });
-                index += 1;
             } else if descriptor.phys_start == current_descriptor.descriptor.phys_start {
                 let used_len = current_descriptor.next_frame.start_address().value()
                     - descriptor.phys_start as usize;
-                memory_map[index].write(MemoryRegion {
+                push(MemoryRegion {
                     start: descriptor.phys_start as usize,
                     len: used_len,
                     kind: MemoryRegionKind::Bootloader,
                 });
 
-                index += 1;
-
                 let remaining_len = (descriptor.page_count as usize * PAGE_SIZE) - used_len;
                 if remaining_len > 0 {
-                    memory_map[index].write(MemoryRegion {
+                    push(MemoryRegion {
                         start: descriptor.phys_start as usize + used_len,
                         len: remaining_len,
                         kind: MemoryRegionKind::Usable,
                     });
-                    index += 1;
                 }
 
                 iterated_through_used_descriptors = true;
             } else {
-                memory_map[index].write(MemoryRegion {
+                push(MemoryRegion {
                     start: descriptor.phys_start as usize,
                     len: descriptor.page_count as usize * PAGE_SIZE,
                     kind: MemoryRegionKind::Bootloader,
                 });
-                index += 1;
             }
         }
 
+        if let Some(frame_buffer) = frame_buffer.filter(|range| !range.is_empty()) {
+            memory_map[index].write(MemoryRegion {
+                start: frame_buffer.start,
+                len: frame_buffer.len(),
+                kind: MemoryRegionKind::FrameBuffer,
+            });
+            index += 1;
+        }
+        for frame in bad_frames {
+            memory_map[index].write(MemoryRegion {
+                start: frame.start_address().value(),
+                len: PAGE_SIZE,
+                kind: MemoryRegionKind::UnknownUefi(MemoryType::UNUSABLE.0),
+            });
+            index += 1;
+        }
+
         // SAFETY: We initialised all the items up to `index`.
-        unsafe { MaybeUninit::slice_assume_init_mut(&mut memory_map[..index]) }
+        unsafe { assume_written(memory_map, index) }
     }
 }
 
 impl FrameAllocator for LegacyFrameAllocator {
+    #[inline]
     fn allocate_frame(&mut self) -> Option<Frame> {
-        if let Some(frame) = self.allocate_frame_from_current() {
-            return Some(frame);
-        }
-
-        while let Some(descriptor) = self.memory_map.next() {
-            // Allocating frames below 1MiB causes problems during AP boot.
-            if descriptor_kind(descriptor) != MemoryRegionKind::Usable
-                || descriptor.phys_start < 0x1_0000
-            {
-                continue;
-            }
-
-            let descriptor = CurrentDescriptor {
-                descriptor,
-                next_frame: Frame::containing_address(PhysicalAddress::new_canonical(
-                    descriptor.phys_start as usize,
-                )),
-            };
-            self.current_descriptor = Some(descriptor);
-
-            if let Some(frame) = self.allocate_frame_from_current() {
-                return Some(frame);
-            }
-        }
-
-        None
+        self.allocate_frame_from_current()
+            .or_else(|| self.allocate_frame_from_next())
     }
 }
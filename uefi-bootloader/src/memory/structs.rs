@@ -0,0 +1,394 @@
+//! Addresses, pages, and frames, and ranges of them.
+//!
+//! This only depends on `core`, the derive crates, [`PAGE_SIZE`], and the
+//! address canonicalisation functions in [`imp`], so that it can also be built
+//! for the host by the fuzz targets in `fuzz/`.
+
+use super::{imp, PAGE_SIZE};
+use core::{
+    cmp::{max, min},
+    fmt,
+    iter::Step,
+    ops::{Add, AddAssign, Deref, DerefMut, RangeInclusive, Sub, SubAssign},
+};
+use derive_more::{
+    Add, AddAssign, Binary, BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign,
+    LowerHex, Octal, Sub, SubAssign, UpperHex,
+};
+use paste::paste;
+use zerocopy::FromBytes;
+
+const MAX_PAGE_NUMBER: usize = usize::MAX / PAGE_SIZE;
+
+/// A macro for defining `VirtualAddress` and `PhysicalAddress` structs
+/// and implementing their common traits, which are generally identical.
+macro_rules! implement_address {
+    ($TypeName:ident, $desc:literal, $prefix:literal, $is_canonical:path, $canonicalize:path, $chunk:ident) => {
+        paste! { // using the paste crate's macro for easy concatenation
+
+            #[doc = "A " $desc " memory address, which is a `usize` under the hood."]
+            #[derive(
+                Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+                Binary, Octal, LowerHex, UpperHex,
+                BitAnd, BitOr, BitXor, BitAndAssign, BitOrAssign, BitXorAssign,
+                Add, Sub, AddAssign, SubAssign,
+                FromBytes,
+            )]
+            #[repr(transparent)]
+            pub(crate) struct $TypeName(usize);
+
+            impl $TypeName {
+                #[doc = "Creates a new `" $TypeName "`, returning an error if the address is not canonical.\n\n \
+                    This is useful for checking whether an address is valid before using it. 
+                    For example, on x86_64, virtual addresses are canonical
+                    if their upper bits `(64:48]` are sign-extended from bit 47,
+                    and physical addresses are canonical if their upper bits `(64:52]` are 0."]
+                pub(crate) fn new(addr: usize) -> Option<$TypeName> {
+                    if $is_canonical(addr) { Some($TypeName(addr)) } else { None }
+                }
+
+                #[doc = "Creates a new `" $TypeName "` that is guaranteed to be canonical."]
+                pub(crate) const fn new_canonical(addr: usize) -> $TypeName {
+                    $TypeName($canonicalize(addr))
+                }
+
+                #[doc = "Creates a new `" $TypeName "` with a value 0."]
+                pub(crate) const fn zero() -> $TypeName {
+                    $TypeName(0)
+                }
+
+                #[doc = "Returns the underlying `usize` value for this `" $TypeName "`."]
+                #[inline]
+                pub(crate) const fn value(&self) -> usize {
+                    self.0
+                }
+
+                #[doc = "Returns the offset from the " $chunk " boundary specified by this `"
+                    $TypeName ".\n\n \
+                    For example, if the [`PAGE_SIZE`] is 4096 (4KiB), then this will return
+                    the least significant 12 bits `(12:0]` of this `" $TypeName "`."]
+                pub(crate) const fn [<$chunk _offset>](&self) -> usize {
+                    self.0 & (PAGE_SIZE - 1)
+                }
+            }
+            impl fmt::Debug for $TypeName {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, concat!($prefix, "{:#X}"), self.0)
+                }
+            }
+            impl fmt::Display for $TypeName {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, "{:?}", self)
+                }
+            }
+            impl fmt::Pointer for $TypeName {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, "{:?}", self)
+                }
+            }
+            impl Add<usize> for $TypeName {
+                type Output = $TypeName;
+                fn add(self, rhs: usize) -> $TypeName {
+                    $TypeName::new_canonical(self.0.saturating_add(rhs))
+                }
+            }
+            impl AddAssign<usize> for $TypeName {
+                fn add_assign(&mut self, rhs: usize) {
+                    *self = $TypeName::new_canonical(self.0.saturating_add(rhs));
+                }
+            }
+            impl Sub<usize> for $TypeName {
+                type Output = $TypeName;
+                fn sub(self, rhs: usize) -> $TypeName {
+                    $TypeName::new_canonical(self.0.saturating_sub(rhs))
+                }
+            }
+            impl SubAssign<usize> for $TypeName {
+                fn sub_assign(&mut self, rhs: usize) {
+                    *self = $TypeName::new_canonical(self.0.saturating_sub(rhs));
+                }
+            }
+            impl From<$TypeName> for usize {
+                #[inline]
+                fn from(value: $TypeName) -> Self {
+                    value.0
+                }
+            }
+        }
+    };
+}
+
+implement_address!(
+    VirtualAddress,
+    "virtual",
+    "v",
+    imp::is_canonical_virtual_address,
+    imp::canonicalize_virtual_address,
+    page
+);
+
+implement_address!(
+    PhysicalAddress,
+    "physical",
+    "p",
+    imp::is_canonical_physical_address,
+    imp::canonicalize_physical_address,
+    frame
+);
+
+/// A macro for defining `Page` and `Frame` structs
+/// and implementing their common traits, which are generally identical.
+macro_rules! implement_page_frame {
+    ($TypeName:ident, $desc:literal, $prefix:literal, $address:ident) => {
+        paste! { // using the paste crate's macro for easy concatenation
+
+            #[doc = "A `" $TypeName "` is a chunk of **" $desc "** memory aligned to a [`PAGE_SIZE`] boundary."]
+            #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+            pub(crate) struct $TypeName {
+                pub(crate) number: usize,
+            }
+
+            impl $TypeName {
+                #[doc = "Returns the `" $address "` at the start of this `" $TypeName "`."]
+                pub(crate) const fn start_address(&self) -> $address {
+                    $address::new_canonical(self.number * PAGE_SIZE)
+                }
+
+                #[doc = "Returns the number of this `" $TypeName "`."]
+                #[inline(always)]
+                pub(crate) const fn number(&self) -> usize {
+                    self.number
+                }
+
+                #[doc = "Returns the `" $TypeName "` containing the given `" $address "`."]
+                pub(crate) const fn containing_address(addr: $address) -> $TypeName {
+                    $TypeName {
+                        number: addr.value() / PAGE_SIZE,
+                    }
+                }
+            }
+            impl fmt::Debug for $TypeName {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, concat!(stringify!($TypeName), "(", $prefix, "{:#X})"), self.start_address())
+                }
+            }
+            impl Add<usize> for $TypeName {
+                type Output = $TypeName;
+                fn add(self, rhs: usize) -> $TypeName {
+                    // cannot exceed max page number (which is also max frame number)
+                    $TypeName {
+                        number: min(MAX_PAGE_NUMBER, self.number.saturating_add(rhs)),
+                    }
+                }
+            }
+            impl AddAssign<usize> for $TypeName {
+                fn add_assign(&mut self, rhs: usize) {
+                    *self = $TypeName {
+                        number: min(MAX_PAGE_NUMBER, self.number.saturating_add(rhs)),
+                    };
+                }
+            }
+            impl Sub<usize> for $TypeName {
+                type Output = $TypeName;
+                fn sub(self, rhs: usize) -> $TypeName {
+                    $TypeName {
+                        number: self.number.saturating_sub(rhs),
+                    }
+                }
+            }
+            impl SubAssign<usize> for $TypeName {
+                fn sub_assign(&mut self, rhs: usize) {
+                    *self = $TypeName {
+                        number: self.number.saturating_sub(rhs),
+                    };
+                }
+            }
+            #[doc = "Implementing `Step` allows `" $TypeName "` to be used in an [`Iterator`]."]
+            impl Step for $TypeName {
+                #[inline]
+                fn steps_between(start: &$TypeName, end: &$TypeName) -> Option<usize> {
+                    Step::steps_between(&start.number, &end.number)
+                }
+                #[inline]
+                fn forward_checked(start: $TypeName, count: usize) -> Option<$TypeName> {
+                    Step::forward_checked(start.number, count).map(|n| $TypeName { number: n })
+                }
+                #[inline]
+                fn backward_checked(start: $TypeName, count: usize) -> Option<$TypeName> {
+                    Step::backward_checked(start.number, count).map(|n| $TypeName { number: n })
+                }
+            }
+        }
+    };
+}
+
+implement_page_frame!(Page, "virtual", "v", VirtualAddress);
+implement_page_frame!(Frame, "physical", "p", PhysicalAddress);
+
+/// A macro for defining `PageRange` and `FrameRange` structs
+/// and implementing their common traits, which are generally identical.
+macro_rules! implement_page_frame_range {
+    ($TypeName:ident, $desc:literal, $short:ident, $chunk:ident, $address:ident) => {
+        paste! { // using the paste crate's macro for easy concatenation
+
+            #[doc = "A range of [`" $chunk "`]s that are contiguous in " $desc " memory."]
+            #[derive(Clone, PartialEq, Eq)]
+            pub(crate) struct $TypeName(RangeInclusive<$chunk>);
+
+            impl $TypeName {
+                #[doc = "Creates a new range of [`" $chunk "`]s that spans from `start` to `end`, both inclusive bounds."]
+                pub(crate) const fn new(start: $chunk, end: $chunk) -> $TypeName {
+                    $TypeName(RangeInclusive::new(start, end))
+                }
+
+                #[doc = "Creates a `" $TypeName "` that will always yield `None` when iterated."]
+                pub(crate) const fn empty() -> $TypeName {
+                    $TypeName::new($chunk { number: 1 }, $chunk { number: 0 })
+                }
+
+                #[doc = "A convenience method for creating a new `" $TypeName "` that spans \
+                    all [`" $chunk "`]s from the given [`" $address "`] to an end bound based on the given size."]
+                pub(crate) fn [<from_ $short _addr>](starting_addr: $address, size_in_bytes: usize) -> $TypeName {
+                    assert!(size_in_bytes > 0);
+                    let start = $chunk::containing_address(starting_addr);
+                    // The end bound is inclusive, hence the -1. Parentheses are needed to avoid overflow.
+                    let end = $chunk::containing_address(starting_addr + (size_in_bytes - 1));
+                    $TypeName::new(start, end)
+                }
+
+                #[doc = "Returns the [`" $address "`] of the starting [`" $chunk "`] in this `" $TypeName "`."]
+                pub(crate) const fn start_address(&self) -> $address {
+                    self.0.start().start_address()
+                }
+
+                #[doc = "Returns the number of [`" $chunk "`]s covered by this iterator.\n\n \
+                    Use this instead of [`Iterator::count()`] method. \
+                    This is instant, because it doesn't need to iterate over each entry, unlike normal iterators."]
+                pub(crate) const fn [<size_in_ $chunk:lower s>](&self) -> usize {
+                    // add 1 because it's an inclusive range
+                    (self.0.end().number + 1).saturating_sub(self.0.start().number)
+                }
+
+                /// Returns the size of this range in number of bytes, or `None` if it doesn't fit in
+                /// a `usize`, which is only the case for a range spanning the whole address space.
+                pub(crate) const fn try_size_in_bytes(&self) -> Option<usize> {
+                    self.[<size_in_ $chunk:lower s>]().checked_mul(PAGE_SIZE)
+                }
+
+                /// Returns the size of this range in number of bytes.
+                ///
+                /// # Panics
+                ///
+                /// Panics if the size doesn't fit in a `usize`. Only use this for ranges whose size
+                /// is bounded, e.g. by an allocation, and [`try_size_in_bytes`](Self::try_size_in_bytes)
+                /// otherwise.
+                #[track_caller]
+                pub(crate) const fn size_in_bytes(&self) -> usize {
+                    match self.try_size_in_bytes() {
+                        Some(size) => size,
+                        None => panic!("range size overflows usize"),
+                    }
+                }
+
+                #[doc = "Returns `true` if this `" $TypeName "` contains the given [`" $address "`]."]
+                pub(crate) fn contains_address(&self, addr: $address) -> bool {
+                    self.0.contains(&$chunk::containing_address(addr))
+                }
+
+                #[doc = "Returns the offset of the given [`" $address "`] within this `" $TypeName "`, \
+                    i.e., `addr - self.start_address()`.\n\n \
+                    If the given `addr` is not covered by this range of [`" $chunk "`]s, this returns `None`.\n\n \
+                    # Examples\n \
+                    If the range covers addresses `0x2000` to `0x4000`, then `offset_of_address(0x3500)` would return `Some(0x1500)`."]
+                pub(crate) fn offset_of_address(&self, addr: $address) -> Option<usize> {
+                    if self.contains_address(addr) {
+                        Some(addr.value() - self.start_address().value())
+                    } else {
+                        None
+                    }
+                }
+
+                #[doc = "Returns the [`" $address "`] at the given `offset` into this `" $TypeName "`within this `" $TypeName "`, \
+                    i.e., `addr - self.start_address()`.\n\n \
+                    If the given `offset` is not within this range of [`" $chunk "`]s, this returns `None`.\n\n \
+                    # Examples\n \
+                    If the range covers addresses `0x2000` to `0x4000`, then `address_at_offset(0x1500)` would return `Some(0x3500)`."]
+                pub(crate) fn address_at_offset(&self, offset: usize) -> Option<$address> {
+                    if offset <= self.size_in_bytes() {
+                        Some(self.start_address() + offset)
+                    }
+                    else {
+                        None
+                    }
+                }
+
+                #[doc = "Returns a new separate `" $TypeName "` that is extended to include the given [`" $chunk "`]."]
+                pub(crate) fn to_extended(&self, to_include: $chunk) -> $TypeName {
+                    // if the current range was empty, return a new range containing only the given page/frame
+                    if self.is_empty() {
+                        return $TypeName::new(to_include.clone(), to_include);
+                    }
+                    let start = min(self.0.start(), &to_include);
+                    let end = max(self.0.end(), &to_include);
+                    $TypeName::new(start.clone(), end.clone())
+                }
+
+                #[doc = "Returns an inclusive `" $TypeName "` representing the [`" $chunk "`]s that overlap \
+                    across this `" $TypeName "` and the given other `" $TypeName "`.\n\n \
+                    If there is no overlap between the two ranges, `None` is returned."]
+                pub(crate) fn overlap(&self, other: &$TypeName) -> Option<$TypeName> {
+                    let starts = max(*self.start(), *other.start());
+                    let ends   = min(*self.end(),   *other.end());
+                    if starts <= ends {
+                        Some($TypeName::new(starts, ends))
+                    } else {
+                        None
+                    }
+                }
+            }
+            impl fmt::Debug for $TypeName {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, "{:?}", self.0)
+                }
+            }
+            impl Deref for $TypeName {
+                type Target = RangeInclusive<$chunk>;
+                fn deref(&self) -> &RangeInclusive<$chunk> {
+                    &self.0
+                }
+            }
+            impl DerefMut for $TypeName {
+                fn deref_mut(&mut self) -> &mut RangeInclusive<$chunk> {
+                    &mut self.0
+                }
+            }
+            impl IntoIterator for $TypeName {
+                type Item = $chunk;
+                type IntoIter = RangeInclusive<$chunk>;
+                fn into_iter(self) -> Self::IntoIter {
+                    self.0
+                }
+            }
+        }
+    };
+}
+
+implement_page_frame_range!(PageRange, "virtual", virt, Page, VirtualAddress);
+implement_page_frame_range!(FrameRange, "physical", phys, Frame, PhysicalAddress);
+
+const _: () = {
+    const fn range(start: usize, end: usize) -> PageRange {
+        PageRange::new(Page { number: start }, Page { number: end })
+    }
+
+    assert!(matches!(PageRange::empty().try_size_in_bytes(), Some(0)));
+    assert!(matches!(
+        range(MAX_PAGE_NUMBER, MAX_PAGE_NUMBER).try_size_in_bytes(),
+        Some(PAGE_SIZE)
+    ));
+    assert!(matches!(
+        range(1, MAX_PAGE_NUMBER).try_size_in_bytes(),
+        Some(size) if size == usize::MAX - PAGE_SIZE + 1
+    ));
+    assert!(range(0, MAX_PAGE_NUMBER).try_size_in_bytes().is_none());
+};
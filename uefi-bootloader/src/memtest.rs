@@ -0,0 +1,93 @@
+use crate::{
+    logger::report_progress,
+    memory::{Frame, LegacyFrameAllocator, PAGE_SIZE},
+};
+use core::mem::size_of;
+use log::{info, warn};
+
+/// The maximum number of bad frames that are reported to the kernel.
+///
+/// Any further bad frames are only logged, as a machine with this many is
+/// unlikely to boot reliably anyway.
+pub(crate) const MAX_BAD_FRAMES: usize = 16;
+
+/// The frames that failed the memory test.
+pub(crate) struct BadFrames {
+    frames: [Frame; MAX_BAD_FRAMES],
+    len: usize,
+}
+
+impl BadFrames {
+    pub(crate) const fn new() -> Self {
+        Self {
+            frames: [Frame { number: 0 }; MAX_BAD_FRAMES],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn as_slice(&self) -> &[Frame] {
+        &self.frames[..self.len]
+    }
+
+    fn push(&mut self, frame: Frame) {
+        match self.frames.get_mut(self.len) {
+            Some(slot) => {
+                *slot = frame;
+                self.len += 1;
+            }
+            None => warn!("too many bad frames, not reporting {frame:?}"),
+        }
+    }
+}
+
+/// Tests `percent` percent of the frames that `frame_allocator` hasn't
+/// allocated, returning the frames that failed.
+///
+/// The frames are tested in runs of `percent` consecutive frames out of every
+/// 100, and their contents are left undefined.
+pub(crate) fn run(frame_allocator: &LegacyFrameAllocator, percent: usize) -> BadFrames {
+    let total = frame_allocator
+        .unallocated_frames()
+        .map(|frames| frames.size_in_frames())
+        .sum();
+    let mut bad_frames = BadFrames::new();
+    let mut num_tested = 0;
+    let mut num_bad = 0;
+
+    for (index, frame) in frame_allocator.unallocated_frames().flatten().enumerate() {
+        if index % 100 < percent {
+            if !test_frame(frame) {
+                warn!("memory test failed at {frame:?}");
+                bad_frames.push(frame);
+                num_bad += 1;
+            }
+            num_tested += 1;
+        }
+        if index % 1024 == 0 || index + 1 == total {
+            report_progress("testing memory", index + 1, total);
+        }
+    }
+
+    info!("memory test found {num_bad} bad frames out of {num_tested} tested");
+    bad_frames
+}
+
+/// Fills `frame` with walking-ones and then walking-zeros patterns, returning
+/// whether both patterns read back correctly.
+fn test_frame(frame: Frame) -> bool {
+    let words = frame.start_address().value() as *mut u64;
+    let len = PAGE_SIZE / size_of::<u64>();
+    let pattern = |index: usize, invert: u64| (1 << (index % 64)) ^ invert;
+
+    [0, u64::MAX].into_iter().all(|invert| {
+        for index in 0..len {
+            // SAFETY: The frame hasn't been allocated, so it isn't in use, and
+            // memory is identity-mapped.
+            unsafe { words.add(index).write_volatile(pattern(index, invert)) };
+        }
+        (0..len).all(|index| {
+            // SAFETY: See above.
+            unsafe { words.add(index).read_volatile() == pattern(index, invert) }
+        })
+    })
+}
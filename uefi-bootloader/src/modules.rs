@@ -1,92 +1,261 @@
-use crate::{memory::PAGE_SIZE, util::calculate_pages, BootContext};
-use core::mem::MaybeUninit;
+use crate::{
+    config::Config,
+    cpio,
+    error::BootError,
+    fs::open_path,
+    logger::report_progress,
+    memory::RECLAIMABLE_MEMORY,
+    util::{align_up, assume_written, ucs2_to_utf8},
+    BootContext,
+};
+use log::{info, warn};
 use uefi::{
-    prelude::cstr16,
-    proto::media::file::{File, FileAttribute, FileMode},
-    table::boot::MemoryType,
+    proto::media::file::{Directory, File, FileAttribute, FileMode, RegularFile},
+    CStr16,
 };
-use uefi_bootloader_api::Module;
+use uefi_bootloader_api::{Module, PhysicalRange};
 
-const MODULES_MEMORY: MemoryType = MemoryType::custom(0x8000_0000);
+/// The modules loaded by [`BootContext::load_modules`].
+pub(crate) struct LoadedModules {
+    pub(crate) modules: &'static [Module],
+    /// The physical memory containing the module contents.
+    pub(crate) physical_range: PhysicalRange,
+}
 
 impl BootContext {
-    pub(crate) fn load_modules(&self) -> &'static mut [Module] {
-        let mut root = self
-            .open_file_system_root()
-            .expect("failed to open file system root");
-
-        let mut dir = match root.open(cstr16!("modules"), FileMode::Read, FileAttribute::empty()) {
-            Ok(dir) => dir
-                .into_directory()
-                .expect("modules directory was closed or deleted"),
-            Err(_) => return &mut [],
+    /// Loads every file in the configured module archive, or in the configured
+    /// modules directory if there is no archive.
+    ///
+    /// If the `http_url` option is set, the archive is downloaded instead, and
+    /// there are no modules if it doesn't exist.
+    ///
+    /// Modules that fail to load from the directory are logged and skipped,
+    /// unless the `strict_modules` option is enabled, in which case the first
+    /// failure is returned.
+    pub(crate) fn load_modules(&self, config: &Config) -> Result<LoadedModules, BootError> {
+        let no_modules = LoadedModules {
+            modules: &[],
+            physical_range: physical_range(&[]),
+        };
+
+        if let Some(url) = config.http_url {
+            return match self.http_get(url, config.modules_archive) {
+                Ok(archive) => self.load_module_archive(archive, config),
+                Err(BootError::FileNotFound) => Ok(no_modules),
+                Err(error) => Err(error),
+            };
+        }
+
+        let mut root = self.open_kernel_root(config)?;
+
+        match open_path(&mut root, config.modules_archive) {
+            Ok(file) => {
+                let file = file.into_regular_file().ok_or(BootError::NotAFile)?;
+                let archive = self.read_archive(file)?;
+                return self.load_module_archive(archive, config);
+            }
+            Err(BootError::FileNotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        let mut dir = match open_path(&mut root, config.modules_path) {
+            Ok(dir) => dir.into_directory().ok_or(BootError::NotADirectory)?,
+            Err(BootError::FileNotFound) => return Ok(no_modules),
+            Err(error) => return Err(error),
         };
 
         let mut num_modules = 0;
-        let mut num_pages = 0;
+        // Each module starts at a multiple of the `module_align` option, which
+        // is the page size by default, as Theseus modules must not share pages.
+        let mut end = 0;
         let mut buf = [0; 500];
 
-        while let Some(info) = dir
-            .read_entry(&mut buf)
-            .expect("failed to read modules directory entry")
-        {
+        while let Some(info) = dir.read_entry(&mut buf)? {
             if !info.attribute().contains(FileAttribute::DIRECTORY) {
                 num_modules += 1;
-                // Theseus modules must not share pages i.e. the next module starts on a new
-                // page.
-                num_pages += calculate_pages(info.file_size() as usize);
+                end = align_up(end, config.module_align) + info.file_size() as usize;
             }
         }
 
         // This slice is copied into another slice in the bootloader, so this slice can
         // be overwritten by the kernel.
-        let modules = self.allocate_slice(num_modules, MemoryType::LOADER_DATA);
-        let raw_bytes = self.allocate_byte_slice(num_pages * PAGE_SIZE, MODULES_MEMORY);
+        let modules = self.allocate_slice(num_modules, RECLAIMABLE_MEMORY);
+        let raw_bytes = self.allocate_byte_slice(end, config.module_memory);
 
-        dir.reset_entry_readout()
-            .expect("failed to reset modules directory entry readout");
+        dir.reset_entry_readout()?;
 
         let mut idx = 0;
-        let mut num_pages = 0;
+        let mut num_read = 0;
+        let mut end = 0;
 
-        while let Some(info) = dir
-            .read_entry(&mut buf)
-            .expect("failed to read modules directory entry")
-        {
+        while let Some(info) = dir.read_entry(&mut buf)? {
             if !info.attribute().contains(FileAttribute::DIRECTORY) {
                 let name = info.file_name();
 
                 let len = info.file_size() as usize;
-                let mut file = dir
-                    .open(info.file_name(), FileMode::Read, FileAttribute::empty())
-                    .expect("failed to open module")
-                    .into_regular_file()
-                    .expect("module file was closed or deleted");
+                let offset = align_up(end, config.module_align);
+                // The directory could have changed since the modules were counted, and
+                // kernels trust the module offsets.
+                assert!(
+                    idx < modules.len() && offset + len <= raw_bytes.len(),
+                    "module {name} at {offset:#x}..{:#x} does not fit in the {:#x} bytes \
+                     allocated for {} modules",
+                    offset + len,
+                    raw_bytes.len(),
+                    modules.len()
+                );
 
-                file.read(&mut raw_bytes[(num_pages * 4096)..])
-                    .expect("failed to read module");
+                num_read += 1;
+                report_progress("loading modules", num_read, num_modules);
 
-                let mut name_buf = [0; 64];
-                let mut name_idx = 0;
-                for c16 in name.iter() {
-                    let c = char::from(*c16);
-                    let s = c.encode_utf8(&mut name_buf[name_idx..(name_idx + 4)]);
-                    name_idx += s.len();
+                let bytes = &mut raw_bytes[offset..offset + len];
+                if let Err(error) = read_module(&mut dir, name, bytes) {
+                    warn!("failed to load module {name}: {error}");
+                    if config.strict_modules {
+                        return Err(error);
+                    }
+                    // The next module is loaded in its place.
+                    bytes.fill(0);
+                    continue;
                 }
 
+                let mut name_buf = [0; 64];
+                ucs2_to_utf8(name, &mut name_buf);
+
                 modules[idx].write(Module {
                     name: name_buf,
-                    offset: num_pages * 4096,
+                    offset,
                     len,
                 });
 
                 idx += 1;
-                num_pages += calculate_pages(len);
+                end = offset + len;
             }
         }
 
-        assert_eq!(idx, modules.len());
-        // SAFETY: We just initialised the slice and checked that it's the same length.
-        unsafe { MaybeUninit::slice_assume_init_mut(modules) }
+        let physical_range = physical_range(raw_bytes);
+        Ok(LoadedModules {
+            // SAFETY: We initialised the first `idx` modules.
+            modules: unsafe { assume_written(modules, idx) },
+            physical_range,
+        })
+    }
+
+    /// Reads the whole archive `file` with a single read.
+    fn read_archive(&self, mut file: RegularFile) -> Result<&'static [u8], BootError> {
+        file.set_position(RegularFile::END_OF_FILE)?;
+        let len = file.get_position()? as usize;
+        file.set_position(0)?;
+        // The modules are copied out of the archive, so it can be overwritten by
+        // the kernel.
+        let archive = self.allocate_byte_slice(len, RECLAIMABLE_MEMORY);
+        if file.read(archive)? != len {
+            return Err(BootError::UnexpectedEof);
+        }
+        Ok(archive)
+    }
+
+    /// Loads every module in `archive`.
+    ///
+    /// The archive is only accepted if it's well formed, regardless of the
+    /// `strict_modules` option, as its entries can't be trusted otherwise.
+    fn load_module_archive(
+        &self,
+        archive: &'static [u8],
+        config: &Config,
+    ) -> Result<LoadedModules, BootError> {
+        let mut num_modules = 0;
+        let mut end = 0;
+        for entry in cpio::entries(archive) {
+            let entry = entry?;
+            if module_name(&entry).is_some() {
+                num_modules += 1;
+                end = align_up(end, config.module_align) + entry.data.len();
+            }
+        }
+
+        let modules = self.allocate_slice(num_modules, RECLAIMABLE_MEMORY);
+        let raw_bytes = self.allocate_byte_slice(end, config.module_memory);
+
+        // The archive was fully parsed above, so this yields the same modules.
+        let archive_modules = cpio::entries(archive).filter_map(|entry| {
+            let entry = entry.ok()?;
+            Some((module_name(&entry)?, entry.data))
+        });
+        let mut end = 0;
+        for (uninit_module, (name, data)) in modules.iter_mut().zip(archive_modules) {
+            let offset = align_up(end, config.module_align);
+            let len = data.len();
+            raw_bytes[offset..offset + len].copy_from_slice(data);
+
+            let mut name_buf = [0; 64];
+            // Truncate long names at a character boundary.
+            let mut name_len = name.len().min(name_buf.len());
+            while !name.is_char_boundary(name_len) {
+                name_len -= 1;
+            }
+            name_buf[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+
+            uninit_module.write(Module {
+                name: name_buf,
+                offset,
+                len,
+            });
+            end = offset + len;
+        }
+        info!(
+            "loaded {num_modules} modules from {}",
+            config.modules_archive
+        );
+
+        Ok(LoadedModules {
+            // SAFETY: We initialised every module, as the same entries were counted
+            // above.
+            modules: unsafe { assume_written(modules, num_modules) },
+            physical_range: physical_range(raw_bytes),
+        })
+    }
+}
+
+/// Returns the physical memory containing `bytes`, which must have been
+/// allocated by the firmware.
+fn physical_range(bytes: &[u8]) -> PhysicalRange {
+    // Boot services memory is identity-mapped, but an empty slice has a
+    // dangling pointer.
+    PhysicalRange {
+        start: if bytes.is_empty() {
+            0
+        } else {
+            bytes.as_ptr() as usize
+        },
+        len: bytes.len(),
+    }
+}
+
+/// Returns the name of the module in `entry`, or `None` if it isn't a regular
+/// file in the root of the archive with a UTF-8 name.
+///
+/// Like the modules directory, subdirectories are ignored. Paths start with
+/// `./` if the archive was created from a list of files printed by `find .`.
+fn module_name<'a>(entry: &cpio::Entry<'a>) -> Option<&'a str> {
+    let name = entry.name.strip_prefix(b"./").unwrap_or(entry.name);
+    if !entry.is_file() || name.is_empty() || name.contains(&b'/') {
+        return None;
+    }
+    core::str::from_utf8(name).ok()
+}
+
+/// Reads the module called `name` in `dir` into `buffer`, which must be
+/// exactly the size of the module.
+fn read_module(dir: &mut Directory, name: &CStr16, buffer: &mut [u8]) -> Result<(), BootError> {
+    let mut file = dir
+        .open(name, FileMode::Read, FileAttribute::empty())?
+        .into_regular_file()
+        .ok_or(BootError::NotAFile)?;
+
+    if file.read(buffer)? == buffer.len() {
+        Ok(())
+    } else {
+        Err(BootError::UnexpectedEof)
     }
 }
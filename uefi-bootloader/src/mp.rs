@@ -0,0 +1,30 @@
+use crate::BootContext;
+use log::warn;
+use uefi::proto::pi::mp::MpServices;
+use uefi_bootloader_api::CpuCount;
+
+impl BootContext {
+    /// Returns the number of logical processors reported by the firmware's MP
+    /// services protocol.
+    ///
+    /// Returns `None` if the firmware doesn't support the protocol, which is
+    /// common on aarch64.
+    pub(crate) fn cpu_count(&self) -> Option<CpuCount> {
+        let boot_services = self.system_table.boot_services();
+        let handle = boot_services.get_handle_for_protocol::<MpServices>().ok()?;
+        let mp_services = boot_services
+            .open_protocol_exclusive::<MpServices>(handle)
+            .ok()?;
+
+        match mp_services.get_number_of_processors() {
+            Ok(count) => Some(CpuCount {
+                total: count.total,
+                enabled: count.enabled,
+            }),
+            Err(error) => {
+                warn!("failed to get number of processors: {:?}", error.status());
+                None
+            }
+        }
+    }
+}
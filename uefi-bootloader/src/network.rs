@@ -0,0 +1,40 @@
+use crate::{memory::RECLAIMABLE_MEMORY, BootContext};
+use uefi::{
+    prelude::cstr8,
+    proto::network::{
+        pxe::{BaseCode, DhcpV4Packet},
+        IpAddress,
+    },
+    CStr8,
+};
+
+const KERNEL_NAME: &CStr8 = cstr8!("kernel.elf");
+
+impl BootContext {
+    /// Downloads the kernel from the TFTP server specified by the DHCP server.
+    pub(crate) fn download_kernel(&self) -> uefi::Result<&'static [u8]> {
+        let boot_services = self.system_table.boot_services();
+        let handle = boot_services.get_handle_for_protocol::<BaseCode>()?;
+        let mut base_code = boot_services.open_protocol_exclusive::<BaseCode>(handle)?;
+
+        // The base code protocol is already started, and has already received a DHCP
+        // lease, if the bootloader itself was loaded over PXE.
+        if !base_code.mode().started {
+            base_code.start(false)?;
+        }
+        if !base_code.mode().dhcp_ack_received {
+            base_code.dhcp(false)?;
+        }
+
+        let dhcp_ack: &DhcpV4Packet = base_code.mode().dhcp_ack.as_ref();
+        let server_ip = IpAddress::new_v4(dhcp_ack.bootp_si_addr);
+
+        let len = base_code.mtftp_get_file_size(&server_ip, KERNEL_NAME)? as usize;
+        // The kernel segments are copied out of this buffer, so it can be
+        // overwritten by the kernel.
+        let buffer = self.allocate_byte_slice(len, RECLAIMABLE_MEMORY);
+        base_code.mtftp_read_file(&server_ip, KERNEL_NAME, Some(buffer))?;
+
+        Ok(buffer)
+    }
+}
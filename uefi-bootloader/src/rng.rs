@@ -0,0 +1,15 @@
+use crate::BootContext;
+use uefi::proto::rng::Rng;
+
+impl BootContext {
+    /// Returns a random number from the firmware's random number generator.
+    pub(crate) fn random_u64(&self) -> uefi::Result<u64> {
+        let boot_services = self.system_table.boot_services();
+        let handle = boot_services.get_handle_for_protocol::<Rng>()?;
+        let mut rng = boot_services.open_protocol_exclusive::<Rng>(handle)?;
+
+        let mut bytes = [0; 8];
+        rng.get_rng(None, &mut bytes)?;
+        Ok(u64::from_ne_bytes(bytes))
+    }
+}
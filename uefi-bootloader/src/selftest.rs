@@ -0,0 +1,378 @@
+//! A self-test that checks the kernel's page table instead of jumping to the
+//! kernel.
+//!
+//! This is enabled by the `selftest` option, and is used by the smoke test to
+//! catch mapping regressions without a real kernel.
+
+use crate::{
+    arch,
+    kernel::LoadedSegment,
+    memory::{
+        Mapper, Page, PageRange, PhysicalAddress, VirtualAddress, PAGE_SIZE, RECLAIMABLE_MEMORY,
+    },
+    BootContext,
+};
+use core::{fmt::Write, mem, slice};
+use log::{error, info};
+use uefi::table::boot::AllocateType;
+use uefi_bootloader_api::{BootInformation, MappingFlags, MemoryRegion};
+
+/// The expected mapping of a page.
+struct Expected {
+    /// The physical address the start of the checked range is mapped to.
+    physical: Option<PhysicalAddress>,
+    /// Whether the page is writable, or `None` if either is acceptable.
+    writable: Option<bool>,
+    /// Whether the page is executable, or `None` if either is acceptable.
+    executable: Option<bool>,
+}
+
+struct SelfTest<'a> {
+    mapper: &'a Mapper,
+    /// The pages made read-only by the kernel's `PT_GNU_RELRO` program header,
+    /// which are expected to be read-only regardless of [`Expected::writable`].
+    relro_pages: Option<PageRange>,
+    num_checks: usize,
+    num_failures: usize,
+}
+
+impl SelfTest<'_> {
+    /// Checks that the pages spanning `start..start + len` are mapped as
+    /// expected.
+    ///
+    /// The flags of the first and last pages are only checked if they lie
+    /// entirely within the range, as they may be shared with other mappings.
+    fn check_mapped(&mut self, what: &str, start: usize, len: usize, expected: &Expected) {
+        if len == 0 {
+            return;
+        }
+        let end = start + len;
+        let pages = PageRange::new(
+            Page::containing_address(VirtualAddress::new_canonical(start)),
+            Page::containing_address(VirtualAddress::new_canonical(end - 1)),
+        );
+
+        for page in pages {
+            let address = page.start_address().value().max(start);
+            let is_whole_page = page.start_address().value() >= start
+                && page.start_address().value() + PAGE_SIZE <= end;
+            self.num_checks += 1;
+
+            let Some((physical, flags)) = self
+                .mapper
+                .translate(VirtualAddress::new_canonical(address))
+            else {
+                self.fail(format_args!("{what}: {address:#x} is not mapped"));
+                continue;
+            };
+            if let Some(expected_physical) = expected.physical {
+                let expected_physical = expected_physical + (address - start);
+                if physical != expected_physical {
+                    self.fail(format_args!(
+                        "{what}: {address:#x} is mapped to {physical:?} instead of \
+                         {expected_physical:?}"
+                    ));
+                }
+            }
+            if !is_whole_page {
+                continue;
+            }
+            let is_relro = self
+                .relro_pages
+                .as_ref()
+                .is_some_and(|relro_pages| relro_pages.contains(&page));
+            let writable = expected.writable.map(|writable| writable && !is_relro);
+            if writable.is_some_and(|writable| writable != flags.is_writable()) {
+                self.fail(format_args!(
+                    "{what}: {address:#x} is {}writable",
+                    if flags.is_writable() { "" } else { "not " }
+                ));
+            }
+            if expected
+                .executable
+                .is_some_and(|executable| executable != flags.is_executable())
+            {
+                self.fail(format_args!(
+                    "{what}: {address:#x} is {}executable",
+                    if flags.is_executable() { "" } else { "not " }
+                ));
+            }
+        }
+    }
+
+    /// Checks that the page at `address` isn't mapped.
+    fn check_unmapped(&mut self, what: &str, address: usize) {
+        self.num_checks += 1;
+        if self
+            .mapper
+            .translate(VirtualAddress::new_canonical(address))
+            .is_some()
+        {
+            self.fail(format_args!("{what}: {address:#x} is mapped"));
+        }
+    }
+
+    fn fail(&mut self, message: core::fmt::Arguments<'_>) {
+        error!("self-test failure: {message}");
+        self.num_failures += 1;
+    }
+}
+
+/// Checks that a slice of multi-byte elements is zeroed in full, by allocating
+/// it over memory that was just filled with non-zero bytes.
+///
+/// This must run before boot services are exited, and its result is reported
+/// by [`run`].
+pub(crate) fn check_zeroed_allocation(context: &BootContext) -> bool {
+    const LEN: usize = 3;
+
+    let dirty =
+        context.allocate_byte_slice(LEN * mem::size_of::<MemoryRegion>(), RECLAIMABLE_MEMORY);
+    dirty.fill(0xff);
+    let address = dirty.as_ptr() as u64;
+    // SAFETY: The slice was returned by `allocate_byte_slice`, and isn't used
+    // again.
+    unsafe { context.free_byte_slice(dirty) };
+
+    let regions = context.allocate_slice_inner::<MemoryRegion>(
+        LEN,
+        AllocateType::Address(address),
+        RECLAIMABLE_MEMORY,
+    );
+    // SAFETY: The slice was allocated with whole pages, and every byte of
+    // those pages is initialised, either by the fill or by the zeroing.
+    let bytes = unsafe {
+        slice::from_raw_parts_mut(regions.as_mut_ptr().cast::<u8>(), mem::size_of_val(regions))
+    };
+    let is_zeroed = bytes.iter().all(|byte| *byte == 0);
+    // SAFETY: The slice consists of the pages of an allocation like one made
+    // by `allocate_byte_slice`, and isn't used again.
+    unsafe { context.free_byte_slice(bytes) };
+    is_zeroed
+}
+
+/// Checks that the kernel's segments, stack, framebuffer, `map_phys` regions,
+/// early heap, section contents, and boot information are mapped with the
+/// expected flags in `mapper`, and that the boot information is in a used
+/// virtual range, then reports the result over serial and halts.
+///
+/// `relro_pages` are expected to be read-only, even though they're part of a
+/// writable segment, and to still be mapped to the segment's frames.
+/// `zeroed_allocation` is the result of [`check_zeroed_allocation`].
+pub(crate) fn run(
+    mapper: &Mapper,
+    segments: &[LoadedSegment],
+    relro_pages: Option<PageRange>,
+    boot_info: &BootInformation,
+    executable_stack: bool,
+    zeroed_allocation: bool,
+) -> ! {
+    let mut test = SelfTest {
+        mapper,
+        relro_pages,
+        num_checks: 0,
+        num_failures: 0,
+    };
+
+    test.num_checks += 1;
+    if !zeroed_allocation {
+        test.fail(format_args!(
+            "allocation: multi-byte slice was not fully zeroed"
+        ));
+    }
+
+    for segment in segments {
+        test.check_mapped(
+            "kernel segment",
+            segment.start as usize,
+            segment.bytes.len(),
+            &Expected {
+                physical: Some(PhysicalAddress::new_canonical(
+                    segment.bytes.as_ptr() as usize
+                )),
+                writable: Some(segment.is_writable),
+                executable: Some(segment.is_executable),
+            },
+        );
+    }
+
+    // `Mapper::update_flags` must only change the flags of the RELRO pages,
+    // not the frames they're mapped to.
+    if let Some(relro_pages) = test.relro_pages.clone() {
+        let start = relro_pages.start_address().value();
+        let len = relro_pages.size_in_bytes();
+        match segments.iter().find(|segment| {
+            segment.start as usize <= start && start + len <= segment.end() as usize
+        }) {
+            Some(segment) => test.check_mapped(
+                "RELRO pages",
+                start,
+                len,
+                &Expected {
+                    physical: Some(PhysicalAddress::new_canonical(
+                        segment.bytes.as_ptr() as usize + (start - segment.start as usize),
+                    )),
+                    writable: Some(false),
+                    executable: Some(false),
+                },
+            ),
+            None => test.fail(format_args!(
+                "RELRO pages: {start:#x}..{:#x} are not in a kernel segment",
+                start + len
+            )),
+        }
+    }
+
+    if boot_info.kernel_identity_mapped {
+        for segment in segments {
+            let physical = segment.bytes.as_ptr() as usize;
+            test.check_mapped(
+                "kernel segment identity mapping",
+                physical,
+                segment.bytes.len(),
+                &Expected {
+                    physical: Some(PhysicalAddress::new_canonical(physical)),
+                    writable: Some(segment.is_writable),
+                    executable: Some(segment.is_executable),
+                },
+            );
+        }
+    }
+
+    let stack = &boot_info.stack;
+    test.check_mapped(
+        "stack",
+        stack.bottom,
+        stack.top - stack.bottom,
+        &Expected {
+            physical: None,
+            writable: Some(true),
+            executable: Some(executable_stack),
+        },
+    );
+    if stack.lower_guard_page_poisoned {
+        test.check_mapped(
+            "stack lower guard page",
+            stack.lower_guard_page,
+            PAGE_SIZE,
+            &Expected {
+                physical: None,
+                writable: Some(true),
+                executable: Some(false),
+            },
+        );
+    } else {
+        test.check_unmapped("stack lower guard page", stack.lower_guard_page);
+    }
+    test.check_unmapped("stack upper guard page", stack.upper_guard_page);
+
+    if let Some(frame_buffer) = &boot_info.frame_buffer {
+        test.check_mapped(
+            "framebuffer",
+            frame_buffer.virt,
+            frame_buffer.info.size,
+            &Expected {
+                physical: Some(PhysicalAddress::new_canonical(frame_buffer.physical)),
+                writable: Some(true),
+                executable: Some(false),
+            },
+        );
+    }
+
+    for region in boot_info.pre_mapped_regions.iter() {
+        test.check_mapped(
+            "map_phys region",
+            region.virt,
+            region.len,
+            &Expected {
+                physical: Some(PhysicalAddress::new_canonical(region.phys)),
+                writable: Some(region.flags.contains(MappingFlags::WRITABLE)),
+                executable: Some(region.flags.contains(MappingFlags::EXECUTABLE)),
+            },
+        );
+    }
+
+    if let Some(early_heap) = &boot_info.early_heap {
+        test.check_mapped(
+            "early heap",
+            early_heap.virt,
+            early_heap.len,
+            &Expected {
+                physical: Some(PhysicalAddress::new_canonical(early_heap.physical)),
+                writable: Some(true),
+                executable: Some(false),
+            },
+        );
+    }
+
+    for contents in boot_info.section_contents.iter() {
+        test.num_checks += 1;
+        let section = boot_info
+            .elf_sections
+            .iter()
+            .find(|section| section.name() == contents.name());
+        match section {
+            Some(section) if section.size == contents.bytes.len() => {}
+            Some(section) => test.fail(format_args!(
+                "section contents: {} has {} bytes instead of {}",
+                contents.name(),
+                contents.bytes.len(),
+                section.size
+            )),
+            None => test.fail(format_args!(
+                "section contents: {} is not a section of the kernel",
+                contents.name()
+            )),
+        }
+        test.check_mapped(
+            "section contents",
+            contents.bytes.as_ptr() as usize,
+            contents.bytes.len(),
+            &Expected {
+                physical: None,
+                writable: Some(true),
+                executable: None,
+            },
+        );
+    }
+
+    test.check_mapped(
+        "boot info",
+        boot_info as *const _ as usize,
+        boot_info.size,
+        &Expected {
+            physical: Some(PhysicalAddress::new_canonical(boot_info.physical_address)),
+            writable: Some(true),
+            executable: None,
+        },
+    );
+
+    // The boot info is allocated after the used ranges are counted, so check
+    // that its range was still reported.
+    test.num_checks += 1;
+    let boot_info_address = boot_info as *const _ as usize;
+    if !boot_info.used_virtual_ranges.iter().any(|range| {
+        range.start <= boot_info_address && boot_info_address < range.start + range.len
+    }) {
+        test.fail(format_args!(
+            "used virtual ranges: boot info at {boot_info_address:#x} is not in a used range"
+        ));
+    }
+
+    let passed = test.num_failures == 0;
+    info!(
+        "self-test {}: {} of {} checks failed",
+        if passed { "passed" } else { "failed" },
+        test.num_failures,
+        test.num_checks
+    );
+    // SAFETY: We are the sole thread, and nothing else is logging.
+    let mut serial = unsafe { arch::serial::SerialPort::new() };
+    let _ = writeln!(
+        serial,
+        "uefi-bootloader: self-test {}",
+        if passed { "passed" } else { "failed" }
+    );
+
+    arch::halt();
+}
@@ -1,3 +1,94 @@
-pub(crate) fn calculate_pages(bytes: usize) -> usize {
-    ((bytes - 1) / 4096) + 1
+use core::mem::MaybeUninit;
+use uefi::CStr16;
+
+/// Returns the number of pages needed to hold `bytes` bytes.
+pub(crate) const fn calculate_pages(bytes: usize) -> usize {
+    let pages = bytes / 4096;
+    if bytes % 4096 == 0 {
+        pages
+    } else {
+        pages + 1
+    }
+}
+
+const _: () = {
+    assert!(calculate_pages(0) == 0);
+    assert!(calculate_pages(1) == 1);
+    assert!(calculate_pages(4096) == 1);
+    assert!(calculate_pages(4097) == 2);
+    assert!(calculate_pages(usize::MAX) == usize::MAX / 4096 + 1);
+};
+
+/// Rounds `value` up to a multiple of `align`, which must be a power of two.
+pub(crate) const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+const _: () = {
+    assert!(align_up(0, 4096) == 0);
+    assert!(align_up(1, 4096) == 4096);
+    assert!(align_up(4096, 4096) == 4096);
+    assert!(align_up(13, 1) == 13);
+    assert!(align_up(13, 8) == 16);
+};
+
+/// Returns the first `written` elements of `slice` as initialised.
+///
+/// # Safety
+///
+/// The caller must have initialised the first `written` elements of `slice`,
+/// which is best ensured by counting the elements as they are written.
+///
+/// # Panics
+///
+/// Panics if `written` is greater than the length of `slice`.
+pub(crate) unsafe fn assume_written<T>(slice: &mut [MaybeUninit<T>], written: usize) -> &mut [T] {
+    assert!(
+        written <= slice.len(),
+        "{written} elements were written to a slice of length {}",
+        slice.len()
+    );
+    // SAFETY: Guaranteed by caller.
+    unsafe { MaybeUninit::slice_assume_init_mut(&mut slice[..written]) }
+}
+
+/// Returns `slice` as initialised, after checking that every element was
+/// written.
+///
+/// # Safety
+///
+/// The caller must have initialised the first `written` elements of `slice`.
+///
+/// # Panics
+///
+/// Panics if `written` isn't the length of `slice`.
+pub(crate) unsafe fn assume_all_written<T>(
+    slice: &mut [MaybeUninit<T>],
+    written: usize,
+) -> &mut [T] {
+    assert_eq!(
+        written,
+        slice.len(),
+        "only {written} of {} elements were written",
+        slice.len()
+    );
+    // SAFETY: Guaranteed by caller.
+    unsafe { assume_written(slice, written) }
+}
+
+/// Writes `name` to `out` as UTF-8, returning the number of bytes written.
+///
+/// Surrogate pairs are combined, and unpaired surrogates are replaced with
+/// [`char::REPLACEMENT_CHARACTER`]. If `name` doesn't fit, it is truncated at
+/// a character boundary.
+pub(crate) fn ucs2_to_utf8(name: &CStr16, out: &mut [u8]) -> usize {
+    let mut len = 0;
+    for c in char::decode_utf16(name.to_u16_slice().iter().copied()) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        let Some(rest) = out.get_mut(len..).filter(|rest| rest.len() >= c.len_utf8()) else {
+            break;
+        };
+        len += c.encode_utf8(rest).len();
+    }
+    len
 }